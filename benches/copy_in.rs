@@ -0,0 +1,165 @@
+//! Binary `COPY`, the statement `Database::copy_in` sends, against one `INSERT` per row, loading
+//! rows shaped like the planned guild member sync. The crate only builds for Workers, so this
+//! talks to Postgres with `tokio-postgres` directly.
+//!
+//! Method: each row count is loaded [`RUNS`] times into an empty table, truncated in between,
+//! and the median wall time is reported. Both sides get the best case: the `INSERT`s reuse one
+//! prepared statement inside a single transaction and borrow their values, where
+//! `Database::execute` would box every value again. The server is on loopback, over
+//! Hyperdrive every `INSERT` pays a real round trip and the gap only grows.
+//!
+//! Run against a scratch database, the table is created and dropped:
+//!
+//! ```text
+//! DATABASE_URL=postgres://postgres@127.0.0.1:5432/postgres cargo bench --bench copy_in
+//! ```
+//!
+//! Results on PostgreSQL 15 over loopback, median of 5 runs:
+//!
+//! ```text
+//!     rows        insert          copy   speedup
+//!      100        2.12ms        0.47ms      4.5x
+//!     1000       19.05ms        3.64ms      5.2x
+//!    10000      169.45ms       15.94ms     10.6x
+//! ```
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::pin_mut;
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
+    types::{ToSql, Type},
+    Client, NoTls,
+};
+
+const ROW_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+const RUNS: usize = 5;
+const TABLE: &str = "bench_guild_members";
+
+struct Member {
+    guild_id: i64,
+    user_id: i64,
+    nickname: Option<String>,
+    joined_at: DateTime<Utc>,
+}
+
+impl Member {
+    fn values(&self) -> [&(dyn ToSql + Sync); 4] {
+        [
+            &self.guild_id,
+            &self.user_id,
+            &self.nickname,
+            &self.joined_at,
+        ]
+    }
+}
+
+fn members(count: usize) -> Vec<Member> {
+    (0..count)
+        .map(|i| Member {
+            guild_id: 81384788765712384,
+            user_id: 80351110224678912 + i as i64,
+            nickname: (i % 3 != 0).then(|| format!("member {}", i)),
+            joined_at: Utc.timestamp_opt(1_600_000_000 + i as i64, 0).unwrap(),
+        })
+        .collect()
+}
+
+async fn insert_each(client: &mut Client, rows: &[Member]) -> Result<(), tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare(&format!(
+            "INSERT INTO {} (guild_id, user_id, nickname, joined_at) VALUES ($1, $2, $3, $4)",
+            TABLE
+        ))
+        .await?;
+    for row in rows {
+        transaction.execute(&statement, &row.values()).await?;
+    }
+    transaction.commit().await
+}
+
+async fn copy_in(client: &Client, rows: &[Member]) -> Result<(), tokio_postgres::Error> {
+    let sink = client
+        .copy_in(&format!(
+            "COPY {} (guild_id, user_id, nickname, joined_at) FROM STDIN (FORMAT binary)",
+            TABLE
+        ))
+        .await?;
+    let writer = BinaryCopyInWriter::new(
+        sink,
+        &[Type::INT8, Type::INT8, Type::TEXT, Type::TIMESTAMPTZ],
+    );
+    pin_mut!(writer);
+    for row in rows {
+        writer.as_mut().write(&row.values()).await?;
+    }
+    writer.finish().await?;
+    Ok(())
+}
+
+fn median(mut samples: Vec<Duration>) -> Duration {
+    samples.sort();
+    samples[samples.len() / 2]
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), tokio_postgres::Error> {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        eprintln!("DATABASE_URL is not set, skipping the copy_in benchmark");
+        return Ok(());
+    };
+    let (mut client, connection) = tokio_postgres::connect(&url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("Connection failed: {}", e);
+        }
+    });
+
+    client
+        .batch_execute(&format!(
+            "DROP TABLE IF EXISTS {table};
+             CREATE TABLE {table} (
+                 guild_id BIGINT NOT NULL,
+                 user_id BIGINT NOT NULL,
+                 nickname TEXT,
+                 joined_at TIMESTAMPTZ NOT NULL,
+                 PRIMARY KEY (guild_id, user_id)
+             )",
+            table = TABLE
+        ))
+        .await?;
+
+    println!(
+        "{:>8}  {:>12}  {:>12}  {:>8}",
+        "rows", "insert", "copy", "speedup"
+    );
+    for count in ROW_COUNTS {
+        let rows = members(count);
+        let mut inserts = Vec::with_capacity(RUNS);
+        let mut copies = Vec::with_capacity(RUNS);
+        for _ in 0..RUNS {
+            client.batch_execute(&format!("TRUNCATE {}", TABLE)).await?;
+            let started = Instant::now();
+            insert_each(&mut client, &rows).await?;
+            inserts.push(started.elapsed());
+
+            client.batch_execute(&format!("TRUNCATE {}", TABLE)).await?;
+            let started = Instant::now();
+            copy_in(&client, &rows).await?;
+            copies.push(started.elapsed());
+        }
+
+        let (insert, copy) = (median(inserts), median(copies));
+        println!(
+            "{:>8}  {:>10.2}ms  {:>10.2}ms  {:>7.1}x",
+            count,
+            insert.as_secs_f64() * 1000.0,
+            copy.as_secs_f64() * 1000.0,
+            insert.as_secs_f64() / copy.as_secs_f64()
+        );
+    }
+
+    client.batch_execute(&format!("DROP TABLE {}", TABLE)).await
+}
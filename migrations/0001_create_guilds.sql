@@ -0,0 +1,9 @@
+-- Guilds the bot has joined, read through `services::guild::Guilds`.
+CREATE TABLE IF NOT EXISTS guilds (
+    id           BIGINT      PRIMARY KEY,
+    name         TEXT        NOT NULL,
+    icon         TEXT,
+    owner_id     BIGINT      NOT NULL,
+    joined_at    TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    member_count INTEGER     NOT NULL DEFAULT 0
+);
@@ -0,0 +1,20 @@
+-- Server side login sessions, read through `services::session::Sessions`. The browser only
+-- holds the opaque session id, `session_id` is its SHA-256 and `discord_access_token` is
+-- sealed with COOKIE_SECRET.
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id           TEXT        PRIMARY KEY,
+    user_id              BIGINT      NOT NULL,
+    discord_access_token TEXT        NOT NULL,
+    token_expires_at     TIMESTAMPTZ NOT NULL,
+    created_at           TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at           TIMESTAMPTZ NOT NULL
+);
+
+-- Listing and revoking a user's sessions, and pruning them on login
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
+
+-- Expired rows are never read (every lookup filters on expires_at) but they aren't deleted on
+-- their own either. Logins prune the user's expired sessions, a periodic
+-- `DELETE FROM sessions WHERE expires_at < now()` (pg_cron or similar) catches the rest and
+-- uses this index.
+CREATE INDEX IF NOT EXISTS sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,7 @@
+-- Per guild dashboard settings, a JSON document changed through JSON Merge Patch by
+-- `Database::patch_guild_settings`. `guild_id` is the snowflake as text, the way the patch
+-- route receives it.
+CREATE TABLE IF NOT EXISTS guild_settings (
+    guild_id TEXT  PRIMARY KEY,
+    settings JSONB NOT NULL DEFAULT '{}'::jsonb
+);
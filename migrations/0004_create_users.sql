@@ -0,0 +1,10 @@
+-- Discord users who logged in to the dashboard, upserted on every login by
+-- `Database::upsert_user` and read as `services::user::StoredUser`.
+CREATE TABLE IF NOT EXISTS users (
+    id            BIGINT      PRIMARY KEY,
+    username      TEXT        NOT NULL,
+    global_name   TEXT,
+    avatar        TEXT,
+    email         TEXT,
+    last_login_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
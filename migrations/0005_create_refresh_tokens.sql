@@ -0,0 +1,7 @@
+-- The latest Discord refresh token of each user, written by `Database::store_refresh_token`.
+-- `token` is sealed with COOKIE_SECRET, every session of the user refreshes through it.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    user_id    BIGINT      PRIMARY KEY,
+    token      TEXT        NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,16 @@
+-- Sensitive actions, one row each, written through `services::audit::record` and read as
+-- `services::audit::AuditEntry`. `actor` is `admin` or `user:<id>`, `subject_user_id` the
+-- user the action concerned. Rows outlive the users they mention, erasing a user anonymizes
+-- them instead.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id              BIGSERIAL   PRIMARY KEY,
+    actor           TEXT        NOT NULL,
+    action          TEXT        NOT NULL,
+    subject_user_id BIGINT,
+    details         JSONB       NOT NULL DEFAULT '{}'::jsonb,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- A user's entries, for exports and erasure
+CREATE INDEX IF NOT EXISTS audit_log_subject_user_id_idx ON audit_log (subject_user_id);
+CREATE INDEX IF NOT EXISTS audit_log_actor_idx ON audit_log (actor);
@@ -0,0 +1,3 @@
+-- Sessions an admin opened through `POST /api/admin/impersonate/{user_id}`. They borrow the
+-- user's access token server side, only allow reads and are never refreshed.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS impersonated BOOLEAN NOT NULL DEFAULT false;
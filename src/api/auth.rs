@@ -1,151 +1,591 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
 
 use axum::{
     extract::Query,
     http::StatusCode,
     response::Redirect,
-    routing::{get, post},
+    routing::{delete, get},
     Extension, Json, Router,
 };
-use cookie::{time::Duration, Cookie};
-use tracing::{error, info, warn};
-use worker::{console_error, console_log, Env};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, error, info, warn};
+use worker::Env;
 
 use crate::{
+    api::error::ApiError,
+    durables::gateway_rooms::{self, RoomOwner},
+    middleware::request_id::RequestId,
     services::{
-        auth::{remove_error_cookies, DiscordAPIClient, DiscordOAuth2, DiscordOAuth2Scope},
-        cookie::CookieJar,
-        get_discord_env,
+        audit::{NewAuditEntry, ERASED_ACTOR},
+        auth::{
+            clear_oauth_flow_cookie, oauth_flow_cookie, remove_error_cookies, session_cookie,
+            DiscordAPIClient, DiscordCookie, DiscordOAuth2, TokenTypeHint, REFRESH_TOKEN_PURPOSE,
+        },
+        cookie::{cookie_key, CookieJar, PrivateCookieJar},
+        crypto::{constant_time_eq, seal, unseal},
+        export::UserExport,
+        session::{Session, SESSION_TOKEN_PURPOSE},
+        snowflake::Snowflake,
         user::{DiscordUser, DiscordUserApi},
     },
-    state::{server_info::ServerInfoArc, user::RequestedUser},
-    DASHBOARD_URL,
+    state::{
+        app_state::AppStateArc,
+        server_info::{ServerInfo, ServerInfoArc},
+        user::{AuthenticatedUser, RequestedUser},
+    },
 };
 
 pub fn router() -> Router {
     Router::new()
         .route("/login", get(login))
+        .route("/url", get(auth_url))
         .route("/redirect", get(redirect))
         .route("/status", get(status))
         .route("/logout", get(logout))
+        .route("/export", get(export))
+        .route("/me", delete(delete_me))
 }
 
 async fn login(
-    Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
     Extension(server_info): Extension<ServerInfoArc>,
     Extension(requested_user): Extension<RequestedUser>,
-) -> Result<Redirect, StatusCode> {
-    let Ok((client_id, _)) = get_discord_env(&env) else {
-        error!("Failed to get Discord environment variables");
-        return Ok(Redirect::to(server_info.webpage()));
-    };
-
+    flow: PrivateCookieJar,
+) -> Result<(PrivateCookieJar, Redirect), ApiError> {
     if let RequestedUser::Bot(_) = requested_user {
         warn!("Bots cannot log in through the web interface");
-        return Err(StatusCode::FORBIDDEN);
+        return Err(ApiError::forbidden(
+            "Bots cannot log in through the web interface",
+        ));
     }
 
     if let RequestedUser::UserWithToken(_) = requested_user {
         let dashboard = format!("{}/dashboard", server_info.webpage());
         warn!("User is already logged in, redirecting to dashboard");
-        return Ok(Redirect::to(&dashboard));
+        return Ok((flow, dashboard_redirect(&server_info, &dashboard)));
+    }
+
+    let client_id = app_state.discord.client_id.clone();
+    let Ok(request) = login_oauth(client_id, &server_info).get_auth_url() else {
+        error!("Failed to build Discord OAuth2 URL");
+        return Err(ApiError::internal("Failed to start the login"));
+    };
+    info!("Redirecting to Discord OAuth2 login");
+    Ok((
+        flow.add(oauth_flow_cookie(
+            DiscordCookie::OAuthState,
+            request.state,
+            server_info.cookie_config(),
+        ))
+        .add(oauth_flow_cookie(
+            DiscordCookie::CodeVerifier,
+            request.code_verifier,
+            server_info.cookie_config(),
+        )),
+        Redirect::temporary(request.url.as_ref()),
+    ))
+}
+
+/// Redirects to `location` only when it's on the dashboard's origin, anything else is logged and
+/// replaced by the dashboard itself so a bad value can never become an open redirect.
+fn dashboard_redirect(server_info: &ServerInfo, location: &str) -> Redirect {
+    if server_info.is_dashboard_location(location) {
+        return Redirect::to(location);
     }
+    error!("Refusing to redirect outside the dashboard origin");
+    Redirect::to(server_info.webpage())
+}
 
+fn login_oauth(client_id: String, server_info: &ServerInfoArc) -> DiscordOAuth2 {
     let redirect = format!("{}/api/auth/redirect", server_info.api_host());
-    let discord_oauth = DiscordOAuth2 {
+    DiscordOAuth2 {
         client_id,
         redirect_uri: redirect,
-        scopes: vec![
-            DiscordOAuth2Scope::Identify,
-            DiscordOAuth2Scope::Guilds,
-            DiscordOAuth2Scope::Email,
-        ],
-    };
+        scopes: server_info.login_scopes().to_vec(),
+    }
+}
 
-    let discord_url = discord_oauth.get_auth_url();
-    info!("Redirecting to Discord OAuth2 login");
-    Ok(Redirect::temporary(discord_url.as_ref()))
+#[derive(Serialize)]
+struct AuthUrlResponse {
+    url: String,
+    state: String,
+}
+
+/// Same as `login` but hands the authorize URL back as JSON so the dashboard can drive
+/// navigation itself (e.g. open it in a popup).
+async fn auth_url(
+    Extension(app_state): Extension<AppStateArc>,
+    Extension(server_info): Extension<ServerInfoArc>,
+    flow: PrivateCookieJar,
+) -> Result<(PrivateCookieJar, Json<AuthUrlResponse>), ApiError> {
+    let request = login_oauth(app_state.discord.client_id.clone(), &server_info)
+        .get_auth_url()
+        .map_err(|e| {
+            error!("Failed to build Discord OAuth2 URL: {}", e);
+            ApiError::internal("Failed to start the login")
+        })?;
+
+    Ok((
+        flow.add(oauth_flow_cookie(
+            DiscordCookie::OAuthState,
+            request.state.clone(),
+            server_info.cookie_config(),
+        ))
+        .add(oauth_flow_cookie(
+            DiscordCookie::CodeVerifier,
+            request.code_verifier,
+            server_info.cookie_config(),
+        )),
+        Json(AuthUrlResponse {
+            url: request.url.to_string(),
+            state: request.state,
+        }),
+    ))
 }
 
 #[worker::send]
 async fn redirect(
+    request_id: RequestId,
     Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
     Extension(server_info): Extension<ServerInfoArc>,
     Query(params): Query<HashMap<String, String>>,
     jar: CookieJar,
-) -> Result<(CookieJar, CookieJar, Redirect), Redirect> {
+    flow: PrivateCookieJar,
+) -> Result<(CookieJar, PrivateCookieJar, Redirect), Redirect> {
     let webpage = server_info.webpage();
     let dashboard = format!("{}/dashboard", webpage);
 
-    let Ok((client_id, client_secret)) = get_discord_env(&env) else {
-        error!("Failed to get Discord environment variables");
-        return Err(Redirect::temporary(webpage));
-    };
-
     let redirect_uri = format!("{}/api/auth/redirect", server_info.api_host());
     let code = match params.get("code") {
         Some(code) => code,
         None => {
-            error!("No code provided in redirect");
-            return Err(Redirect::temporary(webpage));
+            error!(request_id = %request_id, "No code provided in redirect");
+            return Err(dashboard_redirect(&server_info, webpage));
         }
     };
 
+    // The state must round trip through Discord unchanged, otherwise this redirect wasn't started
+    // by this browser and exchanging the code would log the victim into someone else's account
+    let expected_state = flow
+        .get(&DiscordCookie::OAuthState.to_string())
+        .map(|c| c.value().to_string());
+    let state_matches = match (params.get("state"), expected_state) {
+        (Some(state), Some(expected)) if !expected.is_empty() => {
+            constant_time_eq(state.as_bytes(), expected.as_bytes())
+        }
+        _ => false,
+    };
+    if !state_matches {
+        warn!(
+            request_id = %request_id,
+            "OAuth2 state missing or mismatched, refusing code exchange"
+        );
+        let error_page = format!("{}?error=invalid_state", webpage);
+        return Err(dashboard_redirect(&server_info, &error_page));
+    }
+
+    let Some(code_verifier) = flow
+        .get(&DiscordCookie::CodeVerifier.to_string())
+        .map(|c| c.value().to_string())
+    else {
+        warn!(
+            request_id = %request_id,
+            "PKCE code verifier cookie missing, refusing code exchange"
+        );
+        let error_page = format!("{}?error=invalid_state", webpage);
+        return Err(dashboard_redirect(&server_info, &error_page));
+    };
+
     let discord_api = DiscordAPIClient::new(
-        client_id.clone(),
-        client_secret.clone(),
+        &env,
+        app_state.discord.client_id.clone(),
+        app_state.discord.client_secret.clone(),
         redirect_uri.clone(),
     );
-    let token = match discord_api.get_access_token(code.clone()).await {
+    let token = match discord_api
+        .get_access_token(code.clone(), code_verifier)
+        .await
+    {
         Ok(token) => token,
         Err(e) => {
-            error!("Failed to get access token: {}", e);
-            return Err(Redirect::to(webpage));
+            error!(request_id = %request_id, "Failed to get access token: {}", e);
+            return Err(dashboard_redirect(&server_info, webpage));
         }
     };
 
-    let cookies = DiscordAPIClient::set_cookies(token);
+    let missing = token.missing_scopes(server_info.login_scopes());
+    if !missing.is_empty() {
+        let missing = missing
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        warn!(
+            request_id = %request_id,
+            denied_scopes = %missing,
+            "User denied required OAuth2 scopes"
+        );
+        let error_page = format!(
+            "{}?error=missing_scopes&scopes={}",
+            webpage,
+            urlencoding::encode(&missing)
+        );
+        return Err(dashboard_redirect(&server_info, &error_page));
+    }
+
+    // The session belongs to a user, without one there's no login
+    let user_api = DiscordUserApi::from_access_token(&env, token.access_token());
+    let user_id = match user_api.get_user().await {
+        Ok(user) => {
+            if let Err(e) = app_state.database.upsert_user(&user).await {
+                error!(request_id = %request_id, user_id = %user.id, "Failed to save user: {}", e);
+            }
+            user.id.parse::<Snowflake>().ok()
+        }
+        Err(e) => {
+            warn!(request_id = %request_id, "Failed to fetch user after login: {}", e);
+            None
+        }
+    };
+    let Some(user_id) = user_id else {
+        return Err(dashboard_redirect(&server_info, webpage));
+    };
+    let key = match cookie_key(&env) {
+        Ok(key) => key,
+        Err(e) => {
+            error!(request_id = %request_id, "Can't seal the session: {}", e);
+            return Err(dashboard_redirect(&server_info, webpage));
+        }
+    };
 
+    // Refreshes go through the stored refresh token, the session only holds the access token
+    let sealed = seal(&key, REFRESH_TOKEN_PURPOSE, token.refresh_token());
+    if let Err(e) = app_state
+        .database
+        .store_refresh_token(user_id, &sealed)
+        .await
+    {
+        error!(request_id = %request_id, "Failed to store refresh token: {}", e);
+    }
+    let sealed = seal(&key, SESSION_TOKEN_PURPOSE, token.access_token());
+    let session_id = match app_state
+        .database
+        .create_session(user_id, &sealed, token.expiry())
+        .await
+    {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            error!(request_id = %request_id, "Failed to create session: {}", e);
+            return Err(dashboard_redirect(&server_info, webpage));
+        }
+    };
+
+    let cookies = server_info.cookie_config();
     Ok((
-        jar.clone().add(cookies[0].clone()),
-        jar.clone().add(cookies[1].clone()),
-        Redirect::to(&dashboard),
+        jar.add(session_cookie(session_id, cookies)),
+        flow.remove(clear_oauth_flow_cookie(DiscordCookie::OAuthState, cookies))
+            .remove(clear_oauth_flow_cookie(
+                DiscordCookie::CodeVerifier,
+                cookies,
+            )),
+        dashboard_redirect(&server_info, &dashboard),
     ))
 }
 
-#[worker::send]
 async fn status(
-    Extension(requested_user): Extension<RequestedUser>,
-    jar: CookieJar,
-) -> Result<Json<DiscordUser>, (Option<(CookieJar, CookieJar)>, StatusCode)> {
-    let user = match requested_user {
-        RequestedUser::UserWithToken(user) => user,
-        _ => {
-            error!("Unauthorized access to status endpoint");
-            return Err((None, StatusCode::UNAUTHORIZED));
-        }
+    request_id: RequestId,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Json<DiscordUser> {
+    debug!(request_id = %request_id, user_id = %user.id, "Resolved session user");
+    Json(user)
+}
+
+/// Everything stored about the logged in user, see [`UserExport`]. Read from the database,
+/// the in-memory caches only hold short-lived copies of what Discord returns.
+#[worker::send]
+async fn export(
+    request_id: RequestId,
+    Extension(app_state): Extension<AppStateArc>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Result<Json<UserExport>, ApiError> {
+    let Ok(user_id) = user.id.parse::<Snowflake>() else {
+        return Err(ApiError::bad_gateway("Discord returned an invalid user id"));
+    };
+    let database = &app_state.database;
+    let failed = |e: worker::Error| {
+        error!(request_id = %request_id, user_id = %user_id, "Failed to export user data: {}", e);
+        ApiError::internal("Failed to export user data")
     };
 
-    let authorization = format!("Bearer {}", user.access_token());
-    let discord_user_api = DiscordUserApi::new(authorization);
-    let user = match discord_user_api.get_user().await {
-        Ok(user) => user,
-        Err(e) => {
-            error!("Failed to fetch user data: {}", e);
-            return Err((Some(remove_error_cookies(&jar)), StatusCode::UNAUTHORIZED));
-        }
+    let profile = database.get_user(user_id).await.map_err(failed)?;
+    let sessions = database.list_sessions(user_id).await.map_err(failed)?;
+    let refresh_token_stored = database
+        .stored_refresh_token(user_id)
+        .await
+        .map_err(failed)?
+        .is_some();
+    let audit_log = database
+        .audit_entries_for_user(user_id)
+        .await
+        .map_err(failed)?;
+
+    info!(request_id = %request_id, user_id = %user_id, "Exported user data");
+    Ok(Json(UserExport::new(
+        Utc::now(),
+        profile,
+        sessions,
+        refresh_token_stored,
+        audit_log,
+    )))
+}
+
+/// Erases the logged in user for a data erasure request, see
+/// [`crate::services::database::Database::erase_user`] for what is deleted and what is kept
+/// anonymized. Their tokens are revoked with Discord afterwards and their gateway connections
+/// closed, neither keeps the data from being gone. An impersonation session can't get here,
+/// `cookie_check` only lets it read.
+#[worker::send]
+async fn delete_me(
+    request_id: RequestId,
+    Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
+    Extension(server_info): Extension<ServerInfoArc>,
+    jar: CookieJar,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Result<((CookieJar, CookieJar), StatusCode), ApiError> {
+    let Ok(user_id) = user.id.parse::<Snowflake>() else {
+        return Err(ApiError::bad_gateway("Discord returned an invalid user id"));
+    };
+    let audit = NewAuditEntry {
+        actor: ERASED_ACTOR.to_string(),
+        action: "erase_user",
+        subject_user_id: None,
+        details: json!({ "request_id": request_id.as_str() }),
     };
+    let erased = app_state
+        .database
+        .erase_user(user_id, audit)
+        .await
+        .map_err(|e| {
+            error!(request_id = %request_id, user_id = %user_id, "Failed to erase user: {}", e);
+            ApiError::internal("Failed to delete user data")
+        })?;
+
+    if let Err(e) = gateway_rooms::revoke(&env, RoomOwner::User(user_id)).await {
+        error!("Failed to close gateway connections on erasure: {}", e);
+    }
+    match cookie_key(&env) {
+        Ok(key) => revoke_tokens(&env, &app_state, &server_info, erased.tokens(&key)).await,
+        Err(e) => error!("Failed to revoke tokens on erasure: {}", e),
+    }
+
+    info!(
+        request_id = %request_id,
+        sessions = erased.sealed_access_tokens.len(),
+        profile_deleted = erased.profile_deleted,
+        audit_entries_anonymized = erased.audit_entries_anonymized,
+        "Erased user data"
+    );
+    Ok((
+        remove_error_cookies(&jar, server_info.cookie_config()),
+        StatusCode::NO_CONTENT,
+    ))
+}
 
-    Ok(Json(user))
+/// Revokes each token with Discord. A failure is logged and skipped, the caller is dropping
+/// the tokens either way.
+async fn revoke_tokens(
+    env: &Env,
+    app_state: &AppStateArc,
+    server_info: &ServerInfo,
+    tokens: impl IntoIterator<Item = (String, TokenTypeHint)>,
+) {
+    let discord_api = DiscordAPIClient::new(
+        env,
+        app_state.discord.client_id.clone(),
+        app_state.discord.client_secret.clone(),
+        format!("{}/api/auth/redirect", server_info.api_host()),
+    );
+    for (token, hint) in tokens {
+        if let Err(e) = discord_api.revoke_token(&token, hint).await {
+            warn!(token_type = ?hint, "Failed to revoke token: {}", e);
+        }
+    }
 }
 
+/// Ends the session and revokes its tokens with Discord, so the grant stops working everywhere,
+/// closes the user's gateway connections with code 4001 and clears the cookies. A failed
+/// revocation is logged but never keeps the user logged in.
+#[worker::send]
 async fn logout(
     Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
+    Extension(server_info): Extension<ServerInfoArc>,
     jar: CookieJar,
 ) -> ((CookieJar, CookieJar), Redirect) {
-    let webpage = env
-        .var("DASHBOARD_URL")
-        .map(|s| s.to_string())
-        .unwrap_or_else(|_| DASHBOARD_URL.into());
-    (remove_error_cookies(&jar), Redirect::to(&webpage))
+    let cookies = server_info.cookie_config();
+    let session_id = jar
+        .get(&cookies.name(DiscordCookie::Session))
+        .map(|c| c.value().to_string())
+        .filter(|id| !id.is_empty());
+    let session = match &session_id {
+        Some(session_id) => app_state
+            .database
+            .get_session(session_id)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to load session on logout: {}", e);
+                None
+            }),
+        None => None,
+    };
+
+    let session = session.filter(revokes_on_logout);
+    if let Some(session) = &session {
+        // Their access is gone, so are the gateway connections it opened
+        if let Err(e) = gateway_rooms::revoke(&env, RoomOwner::User(session.user_id)).await {
+            error!("Failed to close gateway connections on logout: {}", e);
+        }
+    }
+    if let (Some(session), Ok(key)) = (session, cookie_key(&env)) {
+        let refresh_token = match app_state
+            .database
+            .stored_refresh_token(session.user_id)
+            .await
+        {
+            Ok(sealed) => sealed.and_then(|sealed| unseal(&key, REFRESH_TOKEN_PURPOSE, &sealed)),
+            Err(e) => {
+                error!("Failed to load stored refresh token on logout: {}", e);
+                None
+            }
+        };
+        let tokens = [
+            (
+                unseal(&key, SESSION_TOKEN_PURPOSE, &session.discord_access_token),
+                TokenTypeHint::AccessToken,
+            ),
+            (refresh_token, TokenTypeHint::RefreshToken),
+        ];
+        let tokens = tokens
+            .into_iter()
+            .filter_map(|(token, hint)| Some((token.filter(|t| !t.is_empty())?, hint)));
+        revoke_tokens(&env, &app_state, &server_info, tokens).await;
+
+        // The stored copy would otherwise keep refreshing the user's other sessions
+        if let Err(e) = app_state
+            .database
+            .delete_refresh_token(session.user_id)
+            .await
+        {
+            error!("Failed to delete stored refresh token on logout: {}", e);
+        }
+    }
+    if let Some(session_id) = session_id {
+        if let Err(e) = app_state.database.delete_session(&session_id).await {
+            error!("Failed to delete session on logout: {}", e);
+        }
+    }
+
+    (
+        remove_error_cookies(&jar, cookies),
+        dashboard_redirect(&server_info, server_info.webpage()),
+    )
+}
+
+/// Whether logging out of `session` ends the user's grant and gateway connections. An
+/// impersonation borrows the user's grant, ending it must not revoke it.
+fn revokes_on_logout(session: &Session) -> bool {
+    !session.impersonated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::services::auth::code_challenge;
+
+    fn session(impersonated: bool) -> Session {
+        Session {
+            session_id: "hashed".to_string(),
+            user_id: Snowflake::new(80351110224678912),
+            discord_access_token: "sealed".to_string(),
+            token_expires_at: Utc::now(),
+            created_at: Utc::now(),
+            expires_at: Utc::now(),
+            impersonated,
+        }
+    }
+
+    #[test]
+    fn logout_revokes_the_users_own_session() {
+        assert!(revokes_on_logout(&session(false)));
+    }
+
+    #[test]
+    fn ending_an_impersonation_leaves_the_user_connected() {
+        assert!(!revokes_on_logout(&session(true)));
+    }
+
+    #[test]
+    fn auth_url_carries_the_state_and_challenge_it_hands_out() {
+        let server_info = Arc::new(ServerInfo::for_tests("https://dashboard.example.com", &[]));
+        let oauth = login_oauth("1234".to_string(), &server_info);
+        let request = oauth.get_auth_url().unwrap();
+
+        let query: HashMap<String, String> = request.url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], "1234");
+        assert_eq!(
+            query["redirect_uri"],
+            "https://api.example.com/api/auth/redirect"
+        );
+        assert_eq!(query["state"], request.state);
+        assert_eq!(
+            query["code_challenge"],
+            code_challenge(&request.code_verifier)
+        );
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        // Every call starts a flow of its own
+        let again = oauth.get_auth_url().unwrap();
+        assert_ne!(again.state, request.state);
+        assert_ne!(again.code_verifier, request.code_verifier);
+    }
+
+    fn location(redirect: Redirect) -> String {
+        axum::response::IntoResponse::into_response(redirect)
+            .headers()
+            .get(axum::http::header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn redirects_stay_on_the_dashboard_origin() {
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[]);
+        let error_page = "https://fanclub.example.com?error=invalid_state";
+        assert_eq!(
+            location(dashboard_redirect(&server_info, error_page)),
+            error_page
+        );
+
+        for outside in [
+            "https://evil.example.com/dashboard",
+            "https://fanclub.example.com.evil.example.com/",
+            "http://fanclub.example.com/dashboard",
+            "//evil.example.com",
+        ] {
+            assert_eq!(
+                location(dashboard_redirect(&server_info, outside)),
+                "https://fanclub.example.com",
+                "{}",
+                outside
+            );
+        }
+    }
 }
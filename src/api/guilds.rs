@@ -1,27 +1,181 @@
-use axum::{debug_handler, response::Redirect, routing::get, Extension, Json, Router};
+use std::collections::{BTreeMap, HashSet};
+
+use axum::{
+    debug_handler,
+    extract::Query,
+    http::header::RETRY_AFTER,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
 use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, warn};
 use worker::Env;
 
 use crate::{
+    api::error::ApiError,
     services::{
         auth::{DiscordOAuth2, DiscordOAuth2Scope},
+        guild_count,
         guilds::{DiscordGuildHTTP, PartialDiscordGuild},
+        snowflake::Snowflake,
+        user::{DiscordUserApi, GuildsPage, PartialGuild, UserApiError},
     },
-    state::{server_info::ServerInfoArc, user::RequestedUser},
+    state::{app_state::AppStateArc, server_info::ServerInfoArc, user::RequestedUser},
 };
 
-const DISCORD_ADD_BOT : &str = "https://discord.com/oauth2/authorize?client_id=1340907937471660142&permissions=8&integration_type=0&scope=bot+applications.commands";
+/// Matches the most guilds a (Nitro) user can be in.
+const MAX_MEMBERSHIP_IDS: usize = 200;
 
 pub fn router() -> Router {
     Router::new()
         .route("/", get(get_guilds))
+        .route("/count", get(get_guild_count))
         .route("/mutual", get(get_mutual_guilds))
+        .route("/membership", post(get_membership))
         .route("/add", get(add_guild))
 }
 
-async fn get_guilds() -> &'static str {
-    "List of guilds"
+#[worker::send]
+async fn get_guilds(
+    Extension(env): Extension<Env>,
+    Extension(requested_user): Extension<RequestedUser>,
+    Query(page): Query<GuildsPage>,
+) -> Result<Json<Vec<PartialGuild>>, Response> {
+    let RequestedUser::UserWithToken(user) = &requested_user else {
+        return Err(ApiError::unauthorized("Must be authenticated to list guilds").into_response());
+    };
+    let user_api = DiscordUserApi::from_access_token(&env, user.access_token());
+
+    match user_api.get_user_guilds(&page).await {
+        Ok(guilds) => {
+            if is_complete_list(&page, guilds.len()) {
+                guild_count::remember(user.access_token(), guilds.len());
+            }
+            Ok(Json(guilds))
+        }
+        Err(UserApiError::Unauthorized) => {
+            Err(ApiError::unauthorized("Discord rejected the access token").into_response())
+        }
+        Err(UserApiError::RateLimited { retry_after, .. }) => {
+            warn!("Rate limited listing guilds, retry after {}s", retry_after);
+            let retry_after = (retry_after.ceil() as u64).max(1).to_string();
+            Err((
+                [(RETRY_AFTER, retry_after)],
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    "Rate limited by Discord",
+                ),
+            )
+                .into_response())
+        }
+        Err(e) => {
+            error!("Failed to fetch guilds: {}", e);
+            Err(ApiError::bad_gateway("Failed to fetch guilds").into_response())
+        }
+    }
+}
+
+/// Whether a page of `len` guilds fetched with `page` is the user's whole guild list.
+fn is_complete_list(page: &GuildsPage, len: usize) -> bool {
+    let limit = page
+        .limit
+        .unwrap_or(GuildsPage::MAX_LIMIT)
+        .clamp(1, GuildsPage::MAX_LIMIT);
+    page.before.is_none() && page.after.is_none() && len < usize::from(limit)
+}
+
+#[derive(Serialize)]
+struct GuildCount {
+    count: usize,
+}
+
+/// Reuses the count of a recent full `/api/guilds` listing before asking Discord again.
+#[worker::send]
+async fn get_guild_count(
+    Extension(env): Extension<Env>,
+    Extension(requested_user): Extension<RequestedUser>,
+) -> Result<Json<GuildCount>, ApiError> {
+    let RequestedUser::UserWithToken(user) = requested_user else {
+        return Err(ApiError::unauthorized(
+            "Must be authenticated to count guilds",
+        ));
+    };
+    if let Some(count) = guild_count::cached(user.access_token()) {
+        return Ok(Json(GuildCount { count }));
+    }
+
+    let user_api = DiscordUserApi::from_access_token(&env, user.access_token());
+    match user_api.get_user_guilds_all().await {
+        Ok(guilds) => {
+            guild_count::remember(user.access_token(), guilds.len());
+            Ok(Json(GuildCount {
+                count: guilds.len(),
+            }))
+        }
+        Err(UserApiError::Unauthorized) => {
+            Err(ApiError::unauthorized("Discord rejected the access token"))
+        }
+        Err(e) => {
+            error!("Failed to fetch guilds: {}", e);
+            Err(ApiError::bad_gateway("Failed to fetch guilds"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MembershipRequest {
+    guild_ids: Vec<Snowflake>,
+}
+
+/// Answers membership for many guilds from a single fetch of the user's guild list.
+#[worker::send]
+async fn get_membership(
+    Extension(env): Extension<Env>,
+    Extension(requested_user): Extension<RequestedUser>,
+    Json(body): Json<MembershipRequest>,
+) -> Result<Json<BTreeMap<String, bool>>, ApiError> {
+    let RequestedUser::UserWithToken(user) = requested_user else {
+        return Err(ApiError::unauthorized(
+            "Must be authenticated to check guild membership",
+        ));
+    };
+
+    if body.guild_ids.len() > MAX_MEMBERSHIP_IDS {
+        return Err(ApiError::bad_request(format!(
+            "At most {} guild ids may be checked at once",
+            MAX_MEMBERSHIP_IDS
+        )));
+    }
+
+    let user_api = DiscordUserApi::from_access_token(&env, user.access_token());
+    let guilds = match user_api.get_user_guilds_all().await {
+        Ok(guilds) => guilds,
+        Err(UserApiError::Unauthorized) => {
+            return Err(ApiError::unauthorized("Discord rejected the access token"));
+        }
+        Err(e) => {
+            error!("Failed to fetch guilds: {}", e);
+            return Err(ApiError::bad_gateway("Failed to fetch guilds"));
+        }
+    };
+
+    Ok(Json(membership(&guilds, &body.guild_ids)))
+}
+
+/// Whether each of `guild_ids` is among `guilds`, keyed by the id as a string.
+fn membership(guilds: &[PartialGuild], guild_ids: &[Snowflake]) -> BTreeMap<String, bool> {
+    let joined: HashSet<&str> = guilds.iter().map(|g| g.id.as_str()).collect();
+    guild_ids
+        .iter()
+        .map(|id| {
+            let id = id.to_string();
+            let member = joined.contains(id.as_str());
+            (id, member)
+        })
+        .collect()
 }
 
 #[debug_handler]
@@ -29,33 +183,26 @@ async fn get_guilds() -> &'static str {
 async fn get_mutual_guilds(
     Extension(env): Extension<Env>,
     Extension(requested_user): Extension<RequestedUser>,
-) -> Result<Json<Vec<PartialDiscordGuild>>, (StatusCode, String)> {
+) -> Result<Json<Vec<PartialDiscordGuild>>, ApiError> {
     let Ok(bot_token) = env.secret("DISCORD_BOT_TOKEN").map(|s| s.to_string()) else {
         error!("Failed to get bot token from environment");
-        return Err((StatusCode::INTERNAL_SERVER_ERROR, "".into()));
+        return Err(ApiError::internal("Failed to fetch mutual guilds"));
     };
 
     let RequestedUser::UserWithToken(user) = requested_user else {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "Must be authenticated to access mutual guilds".into(),
+        return Err(ApiError::unauthorized(
+            "Must be authenticated to access mutual guilds",
         ));
     };
 
-    let bot_auth = format!("Bot {}", bot_token);
-    let user_auth = format!("Bearer {}", user.access_token());
-
-    let bot_client = DiscordGuildHTTP::new(bot_auth);
-    let user_client = DiscordGuildHTTP::new(user_auth);
+    let bot_client = DiscordGuildHTTP::new(&env, format!("Bot {}", bot_token));
+    let user_client = DiscordGuildHTTP::from_access_token(&env, user.access_token());
 
     let mutual_guilds = match bot_client.get_mutual_guilds(user_client).await {
         Ok(guilds) => guilds,
         Err(e) => {
             error!("Failed to fetch mutual guilds: {}", e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to fetch mutual guilds".into(),
-            ));
+            return Err(ApiError::bad_gateway("Failed to fetch mutual guilds"));
         }
     };
 
@@ -63,14 +210,11 @@ async fn get_mutual_guilds(
 }
 
 async fn add_guild(
-    Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
     Extension(server_info): Extension<ServerInfoArc>,
     Extension(requested_user): Extension<RequestedUser>,
-) -> Result<Redirect, StatusCode> {
-    let Ok(client_id) = env.var("DISCORD_CLIENT_ID").map(|s| s.to_string()) else {
-        error!("Failed to get client ID from environment");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    };
+) -> Result<Redirect, ApiError> {
+    let client_id = app_state.discord.client_id.clone();
     let dashboard = format!("{}/dashboard", server_info.webpage());
     let oauth = DiscordOAuth2 {
         client_id,
@@ -82,8 +226,65 @@ async fn add_guild(
     };
     if let RequestedUser::Bot(_) = requested_user {
         error!("Unauthorized access to add guild endpoint");
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(ApiError::unauthorized("Bots can't add the bot to a guild"));
     }
     info!("Redirecting to Discord OAuth2 add bot URL");
     Ok(Redirect::to(oauth.get_add_bot_url().as_str()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::user::collect_guild_pages;
+
+    fn guilds(ids: std::ops::Range<u64>) -> Vec<PartialGuild> {
+        ids.map(|id| PartialGuild {
+            id: id.to_string(),
+            name: format!("Guild {}", id),
+            icon: None,
+            owner: false,
+            permissions: "0".to_string(),
+        })
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn membership_covers_guilds_on_later_pages() {
+        let full = u64::from(GuildsPage::MAX_LIMIT);
+        let all = collect_guild_pages(|after| async move {
+            Ok(match after {
+                None => guilds(1..full + 1),
+                Some(_) => guilds(full + 1..full + 3),
+            })
+        })
+        .await
+        .unwrap();
+
+        let first_page = Snowflake::new(1);
+        let second_page = Snowflake::new(full + 2);
+        let elsewhere = Snowflake::new(full + 50);
+        let membership = membership(&all, &[first_page, second_page, elsewhere]);
+        assert_eq!(membership.get(&first_page.to_string()), Some(&true));
+        assert_eq!(membership.get(&second_page.to_string()), Some(&true));
+        assert_eq!(membership.get(&elsewhere.to_string()), Some(&false));
+    }
+
+    #[test]
+    fn only_an_unpaged_short_list_is_complete() {
+        assert!(is_complete_list(&GuildsPage::default(), 3));
+        assert!(!is_complete_list(&GuildsPage::default(), 200));
+
+        let paged = GuildsPage {
+            after: Some(Snowflake::new(1)),
+            ..Default::default()
+        };
+        assert!(!is_complete_list(&paged, 3));
+
+        let limited = GuildsPage {
+            limit: Some(10),
+            ..Default::default()
+        };
+        assert!(!is_complete_list(&limited, 10));
+        assert!(is_complete_list(&limited, 9));
+    }
+}
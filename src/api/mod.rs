@@ -1,6 +1,9 @@
 mod auth;
+pub mod error;
 mod guilds;
 mod protected;
+mod telemetry;
+mod webhook;
 
 use crate::middleware;
 use axum::Router;
@@ -10,7 +13,20 @@ pub fn router() -> Router {
         .merge(protected::router())
         .nest("/guilds", guilds::router())
         .nest("/auth", auth::router())
+        .nest("/telemetry", telemetry::router())
+        .nest("/webhook", webhook::router())
+        // Innermost so it only measures the handler itself
+        .layer(axum::middleware::from_fn(
+            middleware::response_time::middleware,
+        ))
+        // Inside `cookie_check`, keys are scoped to the session it resolves
+        .layer(axum::middleware::from_fn(
+            middleware::idempotency::middleware,
+        ))
         .layer(axum::middleware::from_fn(
             middleware::cookie_check::middleware,
         ))
+        .layer(axum::middleware::from_fn(
+            middleware::https_only::middleware,
+        ))
 }
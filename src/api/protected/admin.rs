@@ -0,0 +1,192 @@
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info, warn};
+use worker::Env;
+
+use crate::{
+    api::error::ApiError,
+    durables::gateway_rooms::{self, RoomOwner},
+    middleware::{api_protect, request_id::RequestId},
+    services::{
+        audit::{NewAuditEntry, ADMIN_ACTOR},
+        bot_presence,
+        session::SessionSummary,
+        snowflake::Snowflake,
+    },
+    state::{app_state::AppStateArc, server_info::ServerInfoArc, user::Caller},
+};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{id}", delete(delete_session))
+        .route("/impersonate/{user_id}", post(impersonate))
+        .route("/guilds/{id}", delete(delete_guild))
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionsQuery {
+    user_id: Snowflake,
+}
+
+/// `api_protect` lets callers without credentials through, every admin handler checks.
+fn require_admin(caller: Option<Extension<Caller>>) -> Result<(), ApiError> {
+    match caller {
+        Some(Extension(Caller::Admin)) => Ok(()),
+        _ => Err(ApiError::forbidden("Admin access required")),
+    }
+}
+
+/// The active sessions of `?user_id=`, newest first, for debugging logins.
+#[worker::send]
+async fn list_sessions(
+    caller: Option<Extension<Caller>>,
+    Extension(app_state): Extension<AppStateArc>,
+    Query(query): Query<SessionsQuery>,
+) -> Result<Json<Vec<SessionSummary>>, ApiError> {
+    require_admin(caller)?;
+    match app_state.database.list_sessions(query.user_id).await {
+        Ok(sessions) => Ok(Json(sessions.into_iter().map(Into::into).collect())),
+        Err(e) => {
+            error!(user_id = %query.user_id, "Failed to list sessions: {}", e);
+            Err(ApiError::internal("Failed to list sessions"))
+        }
+    }
+}
+
+/// Force expires one session by the id [`list_sessions`] shows. The user's Discord grant is
+/// left alone, their other sessions keep working.
+#[worker::send]
+async fn delete_session(
+    caller: Option<Extension<Caller>>,
+    Extension(app_state): Extension<AppStateArc>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    require_admin(caller)?;
+    match app_state.database.delete_session_by_id(&id).await {
+        Ok(true) => {
+            info!(session_id = %id, "Session revoked by an admin");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(ApiError::not_found("Session not found")),
+        Err(e) => {
+            error!(session_id = %id, "Failed to delete session: {}", e);
+            Err(ApiError::internal("Failed to delete session"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImpersonationResponse {
+    user_id: Snowflake,
+    /// Value for the session cookie, it opens the impersonation and nothing else.
+    session: String,
+    expires_at: DateTime<Utc>,
+    read_only: bool,
+}
+
+/// Opens a short, read-only session as `user_id` so support can see the dashboard the way the
+/// user does. The session borrows the user's access token on the server, the response only
+/// carries the new session's id. Every use lands in the audit log.
+#[worker::send]
+async fn impersonate(
+    request_id: RequestId,
+    caller: Option<Extension<Caller>>,
+    Extension(app_state): Extension<AppStateArc>,
+    Path(user_id): Path<Snowflake>,
+) -> Result<Json<ImpersonationResponse>, ApiError> {
+    if let Err(e) = require_admin(caller) {
+        warn!(request_id = %request_id, user_id = %user_id, "Refused impersonation by a non-admin");
+        return Err(e);
+    }
+    let audit = NewAuditEntry {
+        actor: ADMIN_ACTOR.to_string(),
+        action: "impersonate",
+        subject_user_id: Some(user_id),
+        details: json!({ "request_id": request_id.as_str() }),
+    };
+    match app_state
+        .database
+        .create_impersonation_session(user_id, audit)
+        .await
+    {
+        Ok(Some((session, expires_at))) => Ok(Json(ImpersonationResponse {
+            user_id,
+            session,
+            expires_at,
+            read_only: true,
+        })),
+        Ok(None) => Err(ApiError::not_found(
+            "User has no active session to impersonate",
+        )),
+        Err(e) => {
+            error!(request_id = %request_id, user_id = %user_id, "Failed to impersonate: {}", e);
+            Err(ApiError::internal("Failed to start the impersonation"))
+        }
+    }
+}
+
+/// Tears a guild down, the counterpart of registering it: the guild row and its settings go in
+/// one transaction, then this isolate stops accepting the guild, its cached bot presence is
+/// dropped and its gateway connections are closed. Other isolates refuse it on their next
+/// database check.
+#[worker::send]
+async fn delete_guild(
+    request_id: RequestId,
+    caller: Option<Extension<Caller>>,
+    Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
+    Extension(server_info): Extension<ServerInfoArc>,
+    Path(guild_id): Path<Snowflake>,
+) -> Result<StatusCode, ApiError> {
+    require_admin(caller)?;
+    let audit = NewAuditEntry {
+        actor: ADMIN_ACTOR.to_string(),
+        action: "delete_guild",
+        subject_user_id: None,
+        details: json!({ "request_id": request_id.as_str(), "guild_id": guild_id }),
+    };
+    match app_state.database.delete_guild(guild_id, audit).await {
+        Ok(true) => {}
+        Ok(false) => return Err(ApiError::not_found("Guild not found")),
+        Err(e) => {
+            error!(request_id = %request_id, guild_id = %guild_id, "Failed to delete guild: {}", e);
+            return Err(ApiError::internal("Failed to delete guild"));
+        }
+    }
+
+    api_protect::remember_unknown(guild_id);
+    bot_presence::forget(server_info.api_host(), guild_id).await;
+    if let Err(e) = gateway_rooms::revoke(&env, RoomOwner::Guild(guild_id)).await {
+        // The row is gone, a connection left open can't act on the guild anymore
+        error!(guild_id = %guild_id, "Failed to close gateway connections: {}", e);
+    }
+    info!(request_id = %request_id, guild_id = %guild_id, "Guild removed by an admin");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_is_allowed() {
+        assert!(require_admin(Some(Extension(Caller::Admin))).is_ok());
+    }
+
+    #[test]
+    fn everyone_else_is_denied() {
+        let guild = Caller::Guild(Snowflake::new(81384788765712384));
+        for caller in [None, Some(Extension(Caller::Bot)), Some(Extension(guild))] {
+            let err = require_admin(caller).unwrap_err();
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        }
+    }
+}
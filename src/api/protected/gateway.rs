@@ -1,51 +1,411 @@
 use axum::{
     body::Body,
     extract::{Path, Request},
-    http::Response,
+    http::{
+        header::{CONNECTION, ORIGIN, UPGRADE},
+        HeaderMap, Response, StatusCode,
+    },
+    response::IntoResponse,
     Extension,
 };
-use worker::Env;
+use tracing::{error, warn};
+use worker::{Env, WebSocketPair};
+
+use crate::{
+    api::error::ApiError,
+    durables::{
+        bot_room::SHUTDOWN_PATH,
+        gateway_rooms::{self, RoomOwner},
+        protocol::{CloseCode, GatewayClient, GATEWAY_CLIENT_HEADER},
+    },
+    state::{
+        server_info::{ServerInfo, ServerInfoArc},
+        user::Caller,
+    },
+};
 
 #[worker::send]
 pub async fn handle_websocket(
     Path(id): Path<String>,
     Extension(env): Extension<Env>,
+    Extension(server_info): Extension<ServerInfoArc>,
+    caller: Option<Extension<Caller>>,
     req: Request,
 ) -> Response<Body> {
+    if !is_websocket_upgrade(req.headers()) {
+        return ApiError::bad_request("Expected a WebSocket upgrade").into_response();
+    }
+
+    if !is_allowed_origin(req.headers(), &server_info) {
+        warn!(
+            "Rejecting gateway connection from origin {:?}",
+            req.headers().get(ORIGIN)
+        );
+        return reject_websocket(CloseCode::OriginNotAllowed);
+    }
+
+    // The room only trusts what the gateway tells it about the client
+    let Some(client) = caller
+        .as_ref()
+        .and_then(|Extension(caller)| GatewayClient::from_caller(caller))
+    else {
+        return ApiError::forbidden("Not allowed on the gateway").into_response();
+    };
+
     let object = match env.durable_object("BOTROOM") {
         Ok(obj) => obj,
-        Err(_) => return Response::new(Body::from("Error accessing durable object")),
+        Err(e) => {
+            error!("Failed to get the BOTROOM durable object: {}", e);
+            return ApiError::internal("Error accessing durable object").into_response();
+        }
     };
 
     let Ok(object_id) = object.id_from_name(&id) else {
-        return Response::new(Body::from("Invalid object ID"));
+        return ApiError::bad_request("Invalid object ID").into_response();
     };
 
     let Ok(stub) = object_id.get_stub() else {
-        return Response::new(Body::from("Error getting durable object stub"));
+        return ApiError::internal("Error getting durable object stub").into_response();
     };
 
     let url = req.uri().clone();
 
     let Ok(mut new_req) = worker::Request::new(&url.to_string(), worker::Method::Get) else {
-        return Response::new(Body::from("Error creating request"));
+        return ApiError::internal("Error creating request").into_response();
     };
 
-    for (key, value) in req.headers().iter() {
-        if new_req
-            .headers_mut()
-            .expect("Failed to get headers")
-            .append(key.as_str(), value.to_str().expect("Invalid header value"))
-            .is_err()
-        {
-            return Response::new(Body::from("Error setting headers"));
-        }
+    let Ok(headers) = new_req.headers_mut() else {
+        return ApiError::internal("Error setting headers").into_response();
+    };
+    if let Err(name) = forward_headers(req.headers(), headers) {
+        error!("Failed to forward critical gateway header {}", name);
+        return ApiError::bad_request(format!("Invalid {} header", name)).into_response();
+    }
+    if headers
+        .set(GATEWAY_CLIENT_HEADER, &client.to_header())
+        .is_err()
+    {
+        return ApiError::internal("Error setting headers").into_response();
     }
 
     let res = match stub.fetch_with_request(new_req).await {
         Ok(response) => response,
-        Err(_) => return Response::new(Body::from("Error fetching durable object")),
+        Err(e) => {
+            error!("Failed to fetch from the BOTROOM durable object: {}", e);
+            return ApiError::bad_gateway("Error fetching durable object").into_response();
+        }
     };
 
+    if res.status_code() == 101 {
+        // A missing entry only costs closing this connection on logout or guild removal
+        let owner = match client {
+            GatewayClient::Bot => None,
+            GatewayClient::Guild(guild_id) => Some(RoomOwner::Guild(guild_id)),
+            GatewayClient::User(user_id) => Some(RoomOwner::User(user_id)),
+        };
+        if let Some(owner) = owner {
+            if let Err(e) = gateway_rooms::join_room(&env, owner, &id).await {
+                error!(owner = ?owner, "Failed to index gateway room: {}", e);
+            }
+        }
+    }
     res.into()
 }
+
+/// Asks the room to close every connection with [`CloseCode::ServiceRestart`] and drop their
+/// subscriptions, e.g. before the bot redeploys. Only the bot may do this.
+#[worker::send]
+pub async fn shutdown(
+    Path(id): Path<String>,
+    Extension(env): Extension<Env>,
+    caller: Option<Extension<Caller>>,
+) -> Result<StatusCode, ApiError> {
+    match caller {
+        Some(Extension(Caller::Bot)) => {}
+        Some(_) => {
+            return Err(ApiError::forbidden(
+                "Only the bot can shut the gateway down",
+            ))
+        }
+        None => return Err(ApiError::unauthorized("Not authenticated")),
+    }
+
+    let object = env.durable_object("BOTROOM").map_err(|e| {
+        error!("Failed to get the BOTROOM durable object: {}", e);
+        ApiError::internal("Error accessing durable object")
+    })?;
+    let stub = object
+        .id_from_name(&id)
+        .and_then(|object_id| object_id.get_stub())
+        .map_err(|e| {
+            error!("Failed to get the BOTROOM durable object stub: {}", e);
+            ApiError::internal("Error getting durable object stub")
+        })?;
+
+    // Durable objects only see the path, the host is never resolved
+    let url = format!("https://botroom{}", SHUTDOWN_PATH);
+    let request = worker::Request::new(&url, worker::Method::Post).map_err(|e| {
+        error!("Failed to build the shutdown request: {}", e);
+        ApiError::internal("Error creating request")
+    })?;
+    match stub.fetch_with_request(request).await {
+        Ok(response) if (200..300).contains(&response.status_code()) => Ok(StatusCode::NO_CONTENT),
+        Ok(response) => {
+            error!(
+                status = response.status_code(),
+                "BOTROOM refused to shut down"
+            );
+            Err(ApiError::bad_gateway("Durable object refused to shut down"))
+        }
+        Err(e) => {
+            error!("Failed to shut down the BOTROOM durable object: {}", e);
+            Err(ApiError::bad_gateway("Error fetching durable object"))
+        }
+    }
+}
+
+/// CORS doesn't apply to WebSocket upgrades, so without this any site could open a socket
+/// carrying the user's cookies. Non-browser clients (the bot) don't send an Origin and pass.
+fn is_allowed_origin(headers: &HeaderMap, server_info: &ServerInfo) -> bool {
+    match headers.get(ORIGIN) {
+        Some(origin) => origin
+            .to_str()
+            .is_ok_and(|origin| server_info.is_allowed_origin(origin)),
+        None => true,
+    }
+}
+
+/// `Upgrade: websocket` plus an `upgrade` token in `Connection`, both case-insensitive.
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let upgrade = headers
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("websocket"));
+    let connection = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    upgrade && connection
+}
+
+/// Handshake headers the WebSocket upgrade can't work without. Anything else is best effort.
+const CRITICAL_HEADERS: [&str; 6] = [
+    "upgrade",
+    "connection",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
+
+/// Hop-by-hop headers (RFC 9110, 7.6.1) only describe the client's connection to us. `Upgrade`
+/// and `Connection` are the exception, the durable object needs them to accept the WebSocket.
+const HOP_BY_HOP_HEADERS: [&str; 6] = [
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+/// Copies `src` into `dst`, skipping hop-by-hop headers and headers that can't be forwarded
+/// (non-ASCII values or names the runtime rejects, usually injected by proxies or extensions).
+///
+/// Returns the name of the first critical header that couldn't be forwarded.
+fn forward_headers(src: &HeaderMap, dst: &mut worker::Headers) -> Result<(), String> {
+    forward_headers_with(src, |name, value| dst.append(name, value).is_ok())
+}
+
+/// [`forward_headers`] with `append` in place of the runtime's headers, returning whether the
+/// header was taken.
+fn forward_headers_with(
+    src: &HeaderMap,
+    mut append: impl FnMut(&str, &str) -> bool,
+) -> Result<(), String> {
+    // Headers listed in `Connection` are hop-by-hop as well, unless the handshake needs them
+    let connection_tokens: Vec<String> = src
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !CRITICAL_HEADERS.contains(&token.as_str()))
+        .collect();
+
+    for (key, value) in src.iter() {
+        // The room's caller header is only ever set by us
+        if HOP_BY_HOP_HEADERS.contains(&key.as_str())
+            || key.as_str() == GATEWAY_CLIENT_HEADER
+            || connection_tokens.iter().any(|token| token == key.as_str())
+        {
+            continue;
+        }
+        let forwarded = value
+            .to_str()
+            .ok()
+            .is_some_and(|value| append(key.as_str(), value));
+        if forwarded {
+            continue;
+        }
+        if CRITICAL_HEADERS.contains(&key.as_str()) {
+            return Err(key.to_string());
+        }
+        warn!(
+            "Skipping gateway header {} that could not be forwarded",
+            key
+        );
+    }
+    Ok(())
+}
+
+/// Accepts the upgrade only to close it straight away, so the client sees a proper close code.
+fn reject_websocket(code: CloseCode) -> Response<Body> {
+    let Ok(pair) = WebSocketPair::new() else {
+        return ApiError::internal("Error creating WebSocket").into_response();
+    };
+    if pair.server.accept().is_err()
+        || pair
+            .server
+            .close(Some(code.code()), Some(code.reason()))
+            .is_err()
+    {
+        return ApiError::internal("Error closing WebSocket").into_response();
+    }
+    match worker::Response::from_websocket(pair.client) {
+        Ok(res) => res.into(),
+        Err(_) => ApiError::internal("Error creating WebSocket response").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    const BROKEN: &str = "x-broken";
+
+    fn handshake(extra: &[(&'static str, HeaderValue)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade, x-hop"));
+        headers.insert(
+            "sec-websocket-key",
+            HeaderValue::from_static("dGhlIHNhbXBsZQ=="),
+        );
+        headers.insert("sec-websocket-version", HeaderValue::from_static("13"));
+        for (name, value) in extra {
+            headers.append(HeaderName::from_static(name), value.clone());
+        }
+        headers
+    }
+
+    /// Forwards like the runtime would, except that it refuses `refused`.
+    fn forward(src: &HeaderMap, refused: &str) -> (Result<(), String>, Vec<String>) {
+        let mut forwarded = Vec::new();
+        let result = forward_headers_with(src, |name, _| {
+            if name == refused {
+                return false;
+            }
+            forwarded.push(name.to_string());
+            true
+        });
+        (result, forwarded)
+    }
+
+    #[test]
+    fn one_bad_header_is_skipped() {
+        let headers = handshake(&[
+            (BROKEN, HeaderValue::from_static("1")),
+            ("x-agent", HeaderValue::from_bytes(b"caf\xe9").unwrap()),
+            ("x-request-id", HeaderValue::from_static("abc")),
+        ]);
+        let (result, forwarded) = forward(&headers, BROKEN);
+        assert_eq!(result, Ok(()));
+        assert!(forwarded.contains(&"x-request-id".to_string()));
+        assert!(!forwarded.contains(&BROKEN.to_string()));
+        assert!(!forwarded.contains(&"x-agent".to_string()));
+        for critical in [
+            "upgrade",
+            "connection",
+            "sec-websocket-key",
+            "sec-websocket-version",
+        ] {
+            assert!(forwarded.contains(&critical.to_string()), "{}", critical);
+        }
+    }
+
+    #[test]
+    fn hop_by_hop_headers_stay_behind() {
+        let headers = handshake(&[
+            ("keep-alive", HeaderValue::from_static("timeout=5")),
+            ("x-hop", HeaderValue::from_static("1")),
+        ]);
+        let (result, forwarded) = forward(&headers, BROKEN);
+        assert_eq!(result, Ok(()));
+        assert!(!forwarded.contains(&"keep-alive".to_string()));
+        assert!(!forwarded.contains(&"x-hop".to_string()));
+        // Listed in `Connection` too, but the handshake needs it
+        assert!(forwarded.contains(&"upgrade".to_string()));
+    }
+
+    #[test]
+    fn client_supplied_caller_header_is_dropped() {
+        let headers = handshake(&[(GATEWAY_CLIENT_HEADER, HeaderValue::from_static("bot"))]);
+        let (result, forwarded) = forward(&headers, BROKEN);
+        assert_eq!(result, Ok(()));
+        assert!(!forwarded.contains(&GATEWAY_CLIENT_HEADER.to_string()));
+    }
+
+    fn with_origin(origin: &'static str) -> HeaderMap {
+        handshake(&[("origin", HeaderValue::from_static(origin))])
+    }
+
+    #[test]
+    fn dashboard_and_listed_origins_are_allowed() {
+        let server_info = ServerInfo::for_tests(
+            "https://dashboard.example.com",
+            &["https://preview.example.com"],
+        );
+        assert!(is_allowed_origin(
+            &with_origin("https://dashboard.example.com"),
+            &server_info
+        ));
+        assert!(is_allowed_origin(
+            &with_origin("https://preview.example.com"),
+            &server_info
+        ));
+    }
+
+    #[test]
+    fn other_origins_are_rejected() {
+        let server_info = ServerInfo::for_tests("https://dashboard.example.com", &[]);
+        for origin in [
+            "https://evil.example.com",
+            "http://dashboard.example.com",
+            "https://dashboard.example.com.evil.com",
+            "null",
+        ] {
+            assert!(
+                !is_allowed_origin(&with_origin(origin), &server_info),
+                "{}",
+                origin
+            );
+        }
+    }
+
+    #[test]
+    fn clients_without_an_origin_pass() {
+        let server_info = ServerInfo::for_tests("https://dashboard.example.com", &[]);
+        assert!(is_allowed_origin(&handshake(&[]), &server_info));
+    }
+
+    #[test]
+    fn a_critical_header_that_fails_aborts() {
+        let (result, _) = forward(&handshake(&[]), "sec-websocket-key");
+        assert_eq!(result, Err("sec-websocket-key".to_string()));
+    }
+}
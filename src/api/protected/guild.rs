@@ -1,5 +1,219 @@
-use axum::{routing::get, Router};
+use axum::{
+    extract::{Path, Query},
+    http::header::CACHE_CONTROL,
+    response::{IntoResponse, Response},
+    routing::{get, patch},
+    Extension, Json, Router,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::error;
+use worker::Env;
+
+use crate::{
+    api::error::ApiError,
+    services::{
+        bot_presence::{self, BOT_PRESENCE_TTL_SECS},
+        guild::{Guild, GuildListQuery},
+        guilds::DiscordGuildHTTP,
+        settings::validate_settings_patch,
+        snowflake::Snowflake,
+    },
+    state::{app_state::AppStateArc, server_info::ServerInfoArc, user::Caller},
+};
 
 pub fn router() -> Router {
     Router::new()
+        .route("/", get(list_guilds))
+        .route("/{id}/settings", patch(patch_settings))
+        .route("/{id}/bot-status", get(bot_status))
+}
+
+/// The guilds the bot has stored, `?limit=` (at most 100) and `?offset=` page through them.
+/// Only the bot and admins may list them.
+#[worker::send]
+async fn list_guilds(
+    caller: Option<Extension<Caller>>,
+    Extension(app_state): Extension<AppStateArc>,
+    Query(query): Query<GuildListQuery>,
+) -> Result<Json<Vec<Guild>>, ApiError> {
+    authorize_listing(caller.as_ref().map(|Extension(c)| c))?;
+    match app_state
+        .database
+        .list_guilds(query.limit(), query.offset())
+        .await
+    {
+        Ok(guilds) => Ok(Json(guilds)),
+        Err(e) => {
+            error!("Failed to list guilds: {}", e);
+            Err(ApiError::internal("Failed to list guilds"))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BotStatus {
+    bot_present: bool,
+}
+
+/// Whether the bot is in the guild, served from a short lived server side cache so the
+/// dashboard polling it doesn't turn into Discord requests.
+#[worker::send]
+async fn bot_status(
+    Path(id): Path<String>,
+    caller: Option<Extension<Caller>>,
+    Extension(env): Extension<Env>,
+    Extension(server_info): Extension<ServerInfoArc>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Ok(guild_id) = id.parse::<Snowflake>() else {
+        return Err(ApiError::bad_request("Invalid guild id"));
+    };
+    authorize_bot_status(caller.as_ref().map(|Extension(c)| c), guild_id)?;
+    let cache_control = format!("private, max-age={}", BOT_PRESENCE_TTL_SECS);
+
+    if let Some(bot_present) = bot_presence::cached(server_info.api_host(), guild_id).await {
+        return Ok((
+            [(CACHE_CONTROL, cache_control)],
+            Json(BotStatus { bot_present }),
+        ));
+    }
+
+    let Ok(bot_token) = env.secret("DISCORD_BOT_TOKEN").map(|s| s.to_string()) else {
+        error!("Failed to get bot token from environment");
+        return Err(ApiError::internal("Failed to check bot presence"));
+    };
+    let bot_client = DiscordGuildHTTP::new(&env, format!("Bot {}", bot_token));
+    match bot_client.has_guild(&guild_id.to_string()).await {
+        Ok(bot_present) => {
+            bot_presence::remember(server_info.api_host(), guild_id, bot_present).await;
+            Ok((
+                [(CACHE_CONTROL, cache_control)],
+                Json(BotStatus { bot_present }),
+            ))
+        }
+        Err(e) => {
+            error!("Failed to check bot presence: {}", e);
+            Err(ApiError::bad_gateway("Failed to check bot presence"))
+        }
+    }
+}
+
+/// Whether `caller` may act on `guild_id`: the bot and admins for any guild, a guild scoped
+/// request only for its own guild.
+fn authorize_guild(caller: Option<&Caller>, guild_id: Snowflake) -> Result<(), ApiError> {
+    match caller {
+        Some(Caller::Bot | Caller::Admin) => Ok(()),
+        Some(Caller::Guild(id)) if *id == guild_id => Ok(()),
+        Some(Caller::Guild(_)) => Err(ApiError::forbidden("Not allowed to act on another guild")),
+        Some(Caller::User(_)) => Err(ApiError::forbidden("Not allowed to act on guilds")),
+        None => Err(ApiError::unauthorized("Not authenticated")),
+    }
+}
+
+/// Every stored guild is more than any single guild or user should see.
+fn authorize_listing(caller: Option<&Caller>) -> Result<(), ApiError> {
+    match caller {
+        Some(Caller::Bot | Caller::Admin) => Ok(()),
+        Some(_) => Err(ApiError::forbidden("Not allowed to list guilds")),
+        None => Err(ApiError::unauthorized("Not authenticated")),
+    }
+}
+
+/// Bot presence is public knowledge, dashboard users may ask about any guild to decide whether
+/// to offer the invite.
+fn authorize_bot_status(caller: Option<&Caller>, guild_id: Snowflake) -> Result<(), ApiError> {
+    match caller {
+        Some(Caller::User(_)) => Ok(()),
+        caller => authorize_guild(caller, guild_id),
+    }
+}
+
+#[worker::send]
+async fn patch_settings(
+    Path(id): Path<String>,
+    caller: Option<Extension<Caller>>,
+    Extension(app_state): Extension<AppStateArc>,
+    Json(patch): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let Ok(guild_id) = id.parse::<Snowflake>() else {
+        return Err(ApiError::bad_request("Invalid guild id").into_response());
+    };
+    authorize_guild(caller.as_ref().map(|Extension(c)| c), guild_id)
+        .map_err(IntoResponse::into_response)?;
+    validate_settings_patch(&patch).map_err(|e| e.into_response())?;
+
+    match app_state
+        .database
+        .patch_guild_settings(&guild_id.to_string(), &patch)
+        .await
+    {
+        Ok(settings) => Ok(Json(settings)),
+        Err(e) => {
+            error!("Failed to patch settings for guild {}: {}", guild_id, e);
+            Err(ApiError::internal("Failed to update settings").into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+
+    const GUILD_ID: u64 = 81384788765712384;
+
+    #[test]
+    fn bot_and_admin_may_act_on_any_guild() {
+        let guild = Snowflake::new(GUILD_ID);
+        assert!(authorize_guild(Some(&Caller::Bot), guild).is_ok());
+        assert!(authorize_guild(Some(&Caller::Admin), guild).is_ok());
+    }
+
+    #[test]
+    fn guild_caller_may_only_act_on_its_own_guild() {
+        let guild = Snowflake::new(GUILD_ID);
+        assert!(authorize_guild(Some(&Caller::Guild(guild)), guild).is_ok());
+
+        let other = Snowflake::new(GUILD_ID + 1);
+        let err = authorize_guild(Some(&Caller::Guild(other)), guild).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn dashboard_users_may_only_read_bot_status() {
+        let guild = Snowflake::new(GUILD_ID);
+        let user = Caller::User(Snowflake::new(GUILD_ID + 1));
+        let err = authorize_guild(Some(&user), guild).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        assert!(authorize_bot_status(Some(&user), guild).is_ok());
+
+        let other = Caller::Guild(Snowflake::new(GUILD_ID + 1));
+        let err = authorize_bot_status(Some(&other), guild).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        let err = authorize_bot_status(None, guild).unwrap_err();
+        assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn only_bot_and_admin_list_guilds() {
+        assert!(authorize_listing(Some(&Caller::Bot)).is_ok());
+        assert!(authorize_listing(Some(&Caller::Admin)).is_ok());
+
+        let guild = Caller::Guild(Snowflake::new(GUILD_ID));
+        let user = Caller::User(Snowflake::new(GUILD_ID));
+        for caller in [guild, user] {
+            let err = authorize_listing(Some(&caller)).unwrap_err();
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        }
+        let err = authorize_listing(None).unwrap_err();
+        assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn missing_caller_is_unauthorized() {
+        let guild = Snowflake::new(GUILD_ID);
+        let err = authorize_guild(None, guild).unwrap_err();
+        assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+    }
 }
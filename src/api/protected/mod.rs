@@ -1,14 +1,24 @@
+mod admin;
 mod gateway;
 mod guild;
 
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 
 use crate::middleware;
 
 pub fn router() -> Router {
     Router::new()
+        .nest("/admin", admin::router())
         .nest("/guild", guild::router())
         .route("/gateway/{id}", get(gateway::handle_websocket))
+        .route("/gateway/{id}/shutdown", post(gateway::shutdown))
+        // Runs after `api_protect`, which identifies the caller it limits
+        .layer(axum::middleware::from_fn(
+            middleware::rate_limit::middleware,
+        ))
         .layer(axum::middleware::from_fn(
             middleware::api_protect::middleware,
         ))
@@ -0,0 +1,144 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use worker::Env;
+
+use crate::{
+    services::rate_limit::{check_rate_limit, RateLimit, RateLimitOutcome},
+    state::user::RequestedUser,
+};
+
+const MAX_BODY_BYTES: usize = 16 * 1024;
+const MAX_FIELD_CHARS: usize = 4 * 1024;
+const REPORT_RATE_LIMIT: RateLimit = RateLimit {
+    limit: 10,
+    window_secs: 60,
+};
+/// Runs of token-ish characters at least this long are assumed to be secrets.
+const MIN_SECRET_LENGTH: usize = 32;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/client-error", post(client_error))
+        .layer(DefaultBodyLimit::max(MAX_BODY_BYTES))
+}
+
+#[derive(Deserialize)]
+struct ClientErrorReport {
+    message: String,
+    stack: Option<String>,
+    url: Option<String>,
+    user_agent: Option<String>,
+}
+
+#[worker::send]
+async fn client_error(
+    Extension(env): Extension<Env>,
+    Extension(requested_user): Extension<RequestedUser>,
+    headers: HeaderMap,
+    Json(report): Json<ClientErrorReport>,
+) -> StatusCode {
+    // Never log the token itself, a fingerprint is enough to correlate reports
+    let user = match &requested_user {
+        RequestedUser::UserWithToken(user) => Some(format!(
+            "{:x}",
+            Sha256::digest(user.access_token().as_bytes())
+        )),
+        _ => None,
+    };
+    let client = user.clone().unwrap_or_else(|| {
+        headers
+            .get("cf-connecting-ip")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string()
+    });
+
+    match check_rate_limit(&env, &format!("telemetry:{}", client), REPORT_RATE_LIMIT).await {
+        Ok(RateLimitOutcome::Allowed) => {}
+        Ok(RateLimitOutcome::Limited { .. }) => return StatusCode::TOO_MANY_REQUESTS,
+        Err(e) => warn!("Failed to check telemetry rate limit: {}", e),
+    }
+
+    error!(
+        user = %user.as_deref().map(|u| &u[..16]).unwrap_or("anonymous"),
+        message = %clean(&report.message),
+        stack = %report.stack.as_deref().map(clean).unwrap_or_default(),
+        url = %report.url.as_deref().map(clean).unwrap_or_default(),
+        user_agent = %report.user_agent.as_deref().map(clean).unwrap_or_default(),
+        "Client error reported"
+    );
+    StatusCode::NO_CONTENT
+}
+
+fn clean(value: &str) -> String {
+    let truncated: String = value.chars().take(MAX_FIELD_CHARS).collect();
+    scrub_tokens(&truncated)
+}
+
+/// Replaces anything that looks like a token (long runs of base64/hex-ish characters) with
+/// `[redacted]`.
+fn scrub_tokens(value: &str) -> String {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    let mut scrubbed = String::with_capacity(value.len());
+    let mut run = String::new();
+
+    let flush = |run: &mut String, scrubbed: &mut String| {
+        if run.len() >= MIN_SECRET_LENGTH
+            && run.chars().any(|c| c.is_ascii_digit())
+            && run.chars().any(|c| c.is_ascii_alphabetic())
+        {
+            scrubbed.push_str("[redacted]");
+        } else {
+            scrubbed.push_str(run);
+        }
+        run.clear();
+    };
+
+    for c in value.chars() {
+        if is_token_char(c) {
+            run.push(c);
+        } else {
+            flush(&mut run, &mut scrubbed);
+            scrubbed.push(c);
+        }
+    }
+    flush(&mut run, &mut scrubbed);
+    scrubbed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_redacted() {
+        let token = "MTIzNDU2Nzg5MDEyMzQ1Njc4.GaBcDe.abcdefghijklmnopqrstuvwxyz0123";
+        assert_eq!(
+            scrub_tokens(&format!("Authorization: Bearer {}", token)),
+            "Authorization: Bearer [redacted]"
+        );
+    }
+
+    #[test]
+    fn ordinary_text_is_kept() {
+        let stack = "TypeError: cannot read properties of undefined (reading 'guilds')\n    at \
+                     https://fanclub.example.com/assets/index-4f2a.js:12:345";
+        assert_eq!(scrub_tokens(stack), stack);
+        // Long but letters only, e.g. a CSS class or a word
+        let word = "a".repeat(MIN_SECRET_LENGTH * 2);
+        assert_eq!(scrub_tokens(&word), word);
+    }
+
+    #[test]
+    fn fields_are_truncated() {
+        let long = "x ".repeat(MAX_FIELD_CHARS);
+        assert_eq!(clean(&long).chars().count(), MAX_FIELD_CHARS);
+    }
+}
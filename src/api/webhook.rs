@@ -0,0 +1,188 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, error, info, warn};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use worker::{
+    js_sys::{self, Array, Function, Object, Promise, Reflect, Uint8Array},
+    Date, Env,
+};
+
+use crate::api::error::ApiError;
+
+const SIGNATURE_HEADER: &str = "x-signature-ed25519";
+const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// Older timestamps are treated as replays even when the signature checks out.
+const MAX_TIMESTAMP_SKEW_SECS: u64 = 300;
+
+/// Interaction type of Discord's endpoint check, answered with a PONG of the same type.
+const INTERACTION_PING: u8 = 1;
+/// Webhook event payload types.
+const EVENT_PING: u8 = 0;
+const EVENT: u8 = 1;
+
+pub fn router() -> Router {
+    Router::new().route("/discord", post(discord_webhook))
+}
+
+/// Both payloads Discord can post here: interactions (`type` 1 is a PING) and webhook events,
+/// which carry an `event` object (`type` 0 is their PING, 1 an event).
+#[derive(Debug, Deserialize)]
+struct WebhookPayload {
+    #[serde(rename = "type")]
+    kind: u8,
+    event: Option<WebhookEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Value,
+}
+
+/// Receives Discord's interactions and webhook events. Every request must be signed with the
+/// application's key, `X-Signature-Ed25519` over `X-Signature-Timestamp` followed by the raw
+/// body, checked against `DISCORD_PUBLIC_KEY`. Anything unsigned or badly signed is a 401,
+/// Discord relies on that to confirm the endpoint verifies signatures at all.
+#[worker::send]
+async fn discord_webhook(
+    Extension(env): Extension<Env>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let Some(public_key) = env
+        .var("DISCORD_PUBLIC_KEY")
+        .ok()
+        .and_then(|key| decode_hex(key.to_string().trim()))
+        .filter(|key| key.len() == PUBLIC_KEY_LEN)
+    else {
+        error!("DISCORD_PUBLIC_KEY is missing or not hex");
+        return Err(ApiError::internal("Webhook is not configured"));
+    };
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(signature), Some(timestamp)) = (
+        header(SIGNATURE_HEADER)
+            .and_then(decode_hex)
+            .filter(|signature| signature.len() == SIGNATURE_LEN),
+        header(TIMESTAMP_HEADER),
+    ) else {
+        warn!("Rejecting webhook without a valid signature header");
+        return Err(ApiError::unauthorized("Missing request signature"));
+    };
+    let fresh = timestamp.parse::<u64>().is_ok_and(|ts| {
+        let now = Date::now().as_millis() / 1000;
+        now.abs_diff(ts) <= MAX_TIMESTAMP_SKEW_SECS
+    });
+    if !fresh {
+        warn!(timestamp, "Rejecting webhook with a stale timestamp");
+        return Err(ApiError::unauthorized("Invalid request signature"));
+    }
+
+    let mut message = timestamp.as_bytes().to_vec();
+    message.extend_from_slice(&body);
+    match verify_ed25519(&public_key, &signature, &message).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Rejecting webhook with a bad signature");
+            return Err(ApiError::unauthorized("Invalid request signature"));
+        }
+        Err(e) => {
+            error!("Failed to verify webhook signature: {}", e);
+            return Err(ApiError::internal("Failed to verify request signature"));
+        }
+    }
+
+    let payload: WebhookPayload = serde_json::from_slice(&body).map_err(|e| {
+        warn!("Signed webhook with an unexpected body: {}", e);
+        ApiError::bad_request("Malformed payload")
+    })?;
+    match (payload.kind, payload.event) {
+        (EVENT, Some(event)) => {
+            info!(event = %event.kind, "Received Discord webhook event");
+            debug!(event = %event.kind, data = %event.data, "Discord webhook event data");
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        (EVENT_PING, None) => Ok(StatusCode::NO_CONTENT.into_response()),
+        (INTERACTION_PING, None) => {
+            Ok(Json(serde_json::json!({ "type": INTERACTION_PING })).into_response())
+        }
+        (kind, _) => {
+            warn!(kind, "Unhandled Discord webhook payload");
+            Err(ApiError::bad_request("Unsupported payload type"))
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Checks the signature with the runtime's WebCrypto Ed25519, no crypto crate has to be
+/// compiled into the worker for it.
+async fn verify_ed25519(
+    public_key: &[u8],
+    signature: &[u8],
+    message: &[u8],
+) -> Result<bool, String> {
+    let js_err = |e: JsValue| format!("{:?}", e);
+    let crypto = Reflect::get(&js_sys::global(), &"crypto".into()).map_err(js_err)?;
+    let subtle = Reflect::get(&crypto, &"subtle".into()).map_err(js_err)?;
+    let method = |name: &str| -> Result<Function, String> {
+        Reflect::get(&subtle, &name.into())
+            .map_err(js_err)?
+            .dyn_into::<Function>()
+            .map_err(js_err)
+    };
+    let algorithm = Object::new();
+    Reflect::set(&algorithm, &"name".into(), &"Ed25519".into()).map_err(js_err)?;
+
+    let import_key = Reflect::apply(
+        &method("importKey")?,
+        &subtle,
+        &Array::of5(
+            &"raw".into(),
+            &Uint8Array::from(public_key).into(),
+            &algorithm,
+            &JsValue::FALSE,
+            &Array::of1(&"verify".into()),
+        ),
+    )
+    .map_err(js_err)?;
+    let key = JsFuture::from(Promise::from(import_key))
+        .await
+        .map_err(js_err)?;
+
+    let verify = Reflect::apply(
+        &method("verify")?,
+        &subtle,
+        &Array::of4(
+            &algorithm,
+            &key,
+            &Uint8Array::from(signature).into(),
+            &Uint8Array::from(message).into(),
+        ),
+    )
+    .map_err(js_err)?;
+    let verified = JsFuture::from(Promise::from(verify))
+        .await
+        .map_err(js_err)?;
+    Ok(verified.as_bool().unwrap_or(false))
+}
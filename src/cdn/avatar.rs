@@ -0,0 +1,280 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, USER_AGENT},
+        HeaderMap, Response, StatusCode,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use worker::{Cache, Headers};
+
+use crate::{
+    services::{snowflake::Snowflake, upstream::timed},
+    state::server_info::ServerInfoArc,
+    DISCORD_CDN_BASE_URL,
+};
+
+/// The fallback stands in for an avatar that may show up later, keep it short.
+const FALLBACK_CACHE_CONTROL: &str = "public, max-age=300";
+
+const DEFAULT_SIZE: u16 = 128;
+
+#[derive(Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u16>,
+    /// Cache buster, only used as part of the cache key.
+    v: Option<String>,
+}
+
+/// `a_` marks animated avatars, the rest is a 128 bit hex digest. Anything else is rejected
+/// before it can be put into a Discord URL.
+fn is_valid_hash(hash: &str) -> bool {
+    let digest = hash.strip_prefix("a_").unwrap_or(hash);
+    digest.len() == 32
+        && digest
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// An image on its way to the client, with the validator it's served under.
+struct Image {
+    bytes: Vec<u8>,
+    content_type: String,
+    etag: String,
+}
+
+/// Discord's own ETag when it's a strong one, otherwise a SHA-256 of the bytes. Either way the
+/// same image always gets the same tag.
+fn strong_etag(upstream: Option<&str>, bytes: &[u8]) -> String {
+    match upstream {
+        Some(etag) if etag.starts_with('"') && etag.ends_with('"') && etag.len() > 2 => {
+            etag.to_string()
+        }
+        _ => format!("\"{:x}\"", Sha256::digest(bytes)),
+    }
+}
+
+/// Whether `If-None-Match` lists `etag` (or is `*`). The comparison is weak as RFC 9110
+/// requires for this header, a `W/` prefix on either side is ignored.
+fn matches_if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Proxies `avatars/{user_id}/{hash}` from Discord's CDN through the edge cache. Unknown
+/// avatars get the user's default avatar instead of Discord's 404. Every response carries an
+/// ETag, a matching `If-None-Match` gets a bodiless 304.
+#[worker::send]
+pub async fn get_avatar(
+    Path((user_id, hash)): Path<(String, String)>,
+    Query(query): Query<AvatarQuery>,
+    Extension(server_info): Extension<ServerInfoArc>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let Ok(user_id) = user_id.parse::<Snowflake>() else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if !is_valid_hash(&hash) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let size = query
+        .size
+        .unwrap_or(DEFAULT_SIZE)
+        .clamp(16, 4096)
+        .next_power_of_two();
+
+    let cache_key = cache_key(
+        server_info.api_host(),
+        user_id,
+        &hash,
+        size,
+        query.v.as_deref(),
+    );
+
+    // Avatar hashes never point at different images, so a hit can be kept for a while
+    let avatar_cache_control = format!("public, max-age={}, immutable", server_info.cdn_max_age());
+
+    let cache = Cache::default();
+    match cache.get(cache_key.as_str(), false).await {
+        Ok(Some(mut cached)) => {
+            let content_type = cached.headers().get("content-type").ok().flatten();
+            let cache_control = cached.headers().get("cache-control").ok().flatten();
+            let etag = cached.headers().get("etag").ok().flatten();
+            if let Ok(bytes) = cached.bytes().await {
+                // Entries cached before ETags were stored get theirs from the bytes
+                let etag = strong_etag(etag.as_deref(), &bytes);
+                let image = Image {
+                    bytes,
+                    content_type: content_type.unwrap_or_else(|| "image/png".into()),
+                    etag,
+                };
+                let cache_control = cache_control.unwrap_or(avatar_cache_control);
+                return Ok(image_response(&headers, image, &cache_control));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Avatar cache lookup failed: {}", e),
+    }
+
+    let url = upstream_url(user_id, &hash, size);
+    let (image, cache_control) = match fetch_image(&url).await? {
+        Some(image) => (image, avatar_cache_control.as_str()),
+        None => {
+            let index = (user_id.get() >> 22) % 6;
+            let url = format!("{}/embed/avatars/{}.png", DISCORD_CDN_BASE_URL, index);
+            let Some(image) = fetch_image(&url).await? else {
+                error!("Default avatar {} is missing on Discord's CDN", index);
+                return Err(StatusCode::BAD_GATEWAY);
+            };
+            (image, FALLBACK_CACHE_CONTROL)
+        }
+    };
+
+    if let Err(e) = store(&cache, &cache_key, &image, cache_control).await {
+        warn!("Failed to cache avatar: {}", e);
+    }
+
+    Ok(image_response(&headers, image, cache_control))
+}
+
+/// Built from the validated parts only, so junk query params can't fill the cache. `v` is the
+/// dashboard's cache buster and only ever ends up here, never in the upstream URL.
+fn cache_key(api_host: &str, user_id: Snowflake, hash: &str, size: u16, v: Option<&str>) -> String {
+    let mut key = format!("{}/cdn/avatar/{}/{}?size={}", api_host, user_id, hash, size);
+    if let Some(v) = v.filter(|v| !v.is_empty()) {
+        key.push_str("&v=");
+        key.push_str(&urlencoding::encode(v));
+    }
+    key
+}
+
+fn upstream_url(user_id: Snowflake, hash: &str, size: u16) -> String {
+    let ext = if hash.starts_with("a_") { "gif" } else { "png" };
+    format!(
+        "{}/avatars/{}/{}.{}?size={}",
+        DISCORD_CDN_BASE_URL, user_id, hash, ext, size
+    )
+}
+
+/// The image, or `None` when Discord doesn't have it.
+async fn fetch_image(url: &str) -> Result<Option<Image>, StatusCode> {
+    let request = reqwest::Client::new()
+        .get(url)
+        .header(USER_AGENT, crate::USER_AGENT)
+        .send();
+    let response = timed("GET cdn avatar", request).await.map_err(|e| {
+        error!("Failed to fetch avatar: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    match response.status() {
+        StatusCode::NOT_FOUND => return Ok(None),
+        status if !status.is_success() => {
+            error!(status = status.as_u16(), "Discord CDN returned an error");
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        _ => {}
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    // Never relay anything but an image under our origin
+    if !content_type.starts_with("image/") {
+        error!(content_type, "Discord CDN returned a non-image avatar");
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+
+    let bytes = response.bytes().await.map_err(|e| {
+        error!("Failed to read avatar: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    Ok(Some(Image {
+        etag: strong_etag(etag.as_deref(), &bytes),
+        bytes: bytes.to_vec(),
+        content_type,
+    }))
+}
+
+async fn store(cache: &Cache, key: &str, image: &Image, cache_control: &str) -> worker::Result<()> {
+    let headers = Headers::new();
+    headers.set("content-type", &image.content_type)?;
+    headers.set("cache-control", cache_control)?;
+    headers.set("etag", &image.etag)?;
+    let response = worker::Response::from_bytes(image.bytes.clone())?.with_headers(headers);
+    cache.put(key, response).await
+}
+
+/// The image, or a 304 with the same validators when the client already has it.
+fn image_response(request: &HeaderMap, image: Image, cache_control: &str) -> Response<Body> {
+    let headers = [
+        (ETAG, image.etag.clone()),
+        (CACHE_CONTROL, cache_control.to_string()),
+    ];
+    if matches_if_none_match(request, &image.etag) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    (headers, [(CONTENT_TYPE, image.content_type)], image.bytes).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const API_HOST: &str = "https://api.example.com";
+    const HASH: &str = "a_0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn cache_busters_are_cached_independently() {
+        let user = Snowflake::new(80351110224678912);
+        let old = cache_key(API_HOST, user, HASH, 128, Some("old"));
+        let new = cache_key(API_HOST, user, HASH, 128, Some("new"));
+        assert_ne!(old, new);
+        assert_eq!(old, cache_key(API_HOST, user, HASH, 128, Some("old")));
+
+        // No buster and an empty one share the plain entry
+        assert_eq!(
+            cache_key(API_HOST, user, HASH, 128, None),
+            cache_key(API_HOST, user, HASH, 128, Some(""))
+        );
+    }
+
+    #[test]
+    fn cache_buster_is_encoded_into_the_key() {
+        let user = Snowflake::new(80351110224678912);
+        let key = cache_key(API_HOST, user, HASH, 128, Some("a&size=16"));
+        assert!(key.ends_with("?size=128&v=a%26size%3D16"), "{}", key);
+    }
+
+    #[test]
+    fn upstream_url_ignores_the_cache_buster() {
+        let user = Snowflake::new(80351110224678912);
+        assert_eq!(
+            upstream_url(user, HASH, 64),
+            format!(
+                "{}/avatars/80351110224678912/{}.gif?size=64",
+                DISCORD_CDN_BASE_URL, HASH
+            )
+        );
+        assert!(upstream_url(user, "0123456789abcdef0123456789abcdef", 64).contains(".png?"));
+    }
+}
@@ -1,5 +1,7 @@
+mod avatar;
+
 use axum::{routing::get, Router};
 
 pub fn router() -> Router {
-    Router::new()
+    Router::new().route("/avatar/{user_id}/{hash}", get(avatar::get_avatar))
 }
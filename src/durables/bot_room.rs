@@ -1,18 +1,126 @@
-use reqwest::header::USER_AGENT;
-use worker::{console_log, durable_object, Env, Request, Response, Result, State, WebSocketPair};
+use std::{collections::BTreeSet, time::Duration};
 
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_log, durable_object, Date, Env, ListOptions, Method, Request, Response, Result, State,
+    Url, WebSocket, WebSocketPair,
+};
+
+use crate::{
+    durables::{
+        protocol::{
+            user_tag, CloseCode, GatewayClient, GatewayMessage, InboundFrame, OutboundFrame,
+            GATEWAY_CLIENT_HEADER,
+        },
+        stored,
+    },
+    services::snowflake::Snowflake,
+};
+
+/// Query parameter a client reconnects with to get its unacknowledged messages replayed, the
+/// value is the `session_id` of the [`OutboundFrame::Ready`] it was sent. Browsers can't set
+/// headers on a WebSocket, so it can't be a header.
+const SESSION_PARAM: &str = "session";
+const SESSION_TAG_PREFIX: &str = "session:";
+/// Storage key prefix of each session's [`SessionState`].
+const SESSION_KEY_PREFIX: &str = "session:";
+/// How long a session without connections can still be resumed before its state is deleted.
+const RESUME_WINDOW_MS: u64 = 5 * 60 * 1000;
+/// Unacknowledged messages kept per session, older ones are dropped first.
+const MAX_PENDING: usize = 256;
+/// Path the gateway posts to when every connection should be drained.
+pub const SHUTDOWN_PATH: &str = "/shutdown";
+/// Path posted to with `?user=<id>` when a user logs out, or `?guild=<id>` when a guild is
+/// removed, to close their connections.
+pub const REVOKE_PATH: &str = "/revoke";
+
+/// The gateway room. Everything that has to outlive a single event lives in storage or in the
+/// sockets' attachments, the object hibernates between messages and loses its memory.
 #[durable_object]
 pub struct BotRoom {
     state: State,
-    env: Env,
+}
+
+/// What a session needs to resume, stored under [`SESSION_KEY_PREFIX`] and its id.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SessionState {
+    next_seq: u64,
+    /// Sent with `ack: true` and not acknowledged yet, oldest first.
+    pending: Vec<PendingMessage>,
+    /// When (ms since epoch) the session's last connection closed, `None` while connected.
+    disconnected_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingMessage {
+    seq: u64,
+    message: String,
+}
+
+/// Attached to each socket, so it survives hibernation along with the socket.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SocketState {
+    /// Guilds the socket sent [`InboundFrame::Subscribe`] for.
+    subscriptions: BTreeSet<Snowflake>,
+}
+
+/// What a [`REVOKE_PATH`] request closes.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Revocation {
+    /// The sockets to close, see [`GatewayClient::tags`].
+    pub(crate) tag: String,
+    pub(crate) code: CloseCode,
+    /// A removed guild is dropped from every subscription, the bot's included.
+    pub(crate) unsubscribe: Option<Snowflake>,
+}
+
+impl Revocation {
+    /// Reads `?user=<id>` or `?guild=<id>`, exactly one of them.
+    pub(crate) fn from_url(url: &Url) -> Option<Self> {
+        let id = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .and_then(|(_, value)| value.parse::<Snowflake>().ok())
+        };
+        match (id("user"), id("guild")) {
+            (Some(user_id), None) => Some(Revocation {
+                tag: user_tag(user_id),
+                code: CloseCode::Unauthorized,
+                unsubscribe: None,
+            }),
+            (None, Some(guild_id)) => Some(Revocation {
+                tag: guild_id.to_string(),
+                code: CloseCode::GuildRemoved,
+                unsubscribe: Some(guild_id),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl DurableObject for BotRoom {
-    fn new(state: State, env: Env) -> Self {
-        BotRoom { state, env }
+    fn new(state: State, _env: Env) -> Self {
+        BotRoom { state }
     }
 
     async fn fetch(&self, req: Request) -> Result<Response> {
+        if req.method() == Method::Post && req.path() == SHUTDOWN_PATH {
+            let closed = self.drain(CloseCode::ServiceRestart).await;
+            console_log!("Drained {} gateway connections", closed);
+            return Ok(Response::empty()?.with_status(204));
+        }
+        if req.method() == Method::Post && req.path() == REVOKE_PATH {
+            let Some(revocation) = Revocation::from_url(&req.url()?) else {
+                return Response::error("Expected a user or guild query parameter", 400);
+            };
+            if let Some(guild_id) = revocation.unsubscribe {
+                self.unsubscribe_all(guild_id)?;
+            }
+            let closed = self.revoke(&revocation.tag, revocation.code).await?;
+            console_log!("Revoked {} gateway connections", closed);
+            return Ok(Response::empty()?.with_status(204));
+        }
+
         match req.headers().get("Upgrade") {
             Ok(Some(value)) => {
                 if value != "websocket" {
@@ -22,30 +130,61 @@ impl DurableObject for BotRoom {
             _ => return Response::error("Expected WebSocket upgrade", 400),
         };
 
-        let Some(user_agent) = req.headers().get(USER_AGENT.as_str())? else {
-            return Response::error("Missing User-Agent header", 400);
+        let Some(gateway_client) = req
+            .headers()
+            .get(GATEWAY_CLIENT_HEADER)?
+            .and_then(|value| GatewayClient::from_header(&value))
+        else {
+            return Response::error("Unknown gateway client", 400);
         };
 
         let ws = WebSocketPair::new()?;
         let client = ws.client;
         let server = ws.server;
 
-        if user_agent == "DiscordBot" {
-            // This is a bot
-            console_log!("New bot connected");
-            self.state.accept_websocket_with_tags(&server, &["bot"]);
-        } else if user_agent.starts_with("DiscordGuild") {
-            // This is a dashboard connection fr
-            console_log!("New guild client connected");
-            let split_user_agent: Vec<&str> = user_agent.split('/').collect();
-            let guild_id_str = split_user_agent.get(1).unwrap_or(&"unknown");
-            console_log!("Guild ID: {}", guild_id_str);
-            self.state
-                .accept_websocket_with_tags(&server, &["guild", guild_id_str]);
-
-            if let Err(e) = self.send_to_bot(&format!("Guild {} connected to bot", guild_id_str)) {
-                console_log!("Failed to send message to bot: {}", e);
+        let requested_session = req
+            .url()?
+            .query_pairs()
+            .find(|(name, _)| name == SESSION_PARAM)
+            .map(|(_, value)| value.into_owned())
+            .filter(|s| s.len() == 32 && s.bytes().all(|b| b.is_ascii_hexdigit()));
+        // Only sessions that still have state resume, anything else starts over
+        let resumed_session = match requested_session {
+            Some(session) => self
+                .load_session(&session)
+                .await?
+                .map(|state| (session, state)),
+            None => None,
+        };
+        let session = match &resumed_session {
+            Some((session, _)) => session.clone(),
+            None => new_session_id()?,
+        };
+        let session_tag = format!("{}{}", SESSION_TAG_PREFIX, session);
+
+        let mut tags = gateway_client.tags();
+        tags.push(session_tag);
+        let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+        self.state.accept_websocket_with_tags(&server, &tags);
+        match gateway_client {
+            GatewayClient::Bot => console_log!("New bot connected"),
+            GatewayClient::Guild(guild_id) => {
+                console_log!("Guild {} connected", guild_id);
+                if let Err(e) = self.send_to_bot(&format!("Guild {} connected to bot", guild_id)) {
+                    console_log!("Failed to send message to bot: {}", e);
+                }
             }
+            GatewayClient::User(user_id) => console_log!("User {} connected", user_id),
+        }
+
+        send_frame(
+            &server,
+            &OutboundFrame::Ready {
+                session_id: session.clone(),
+            },
+        )?;
+        if let Some((session, state)) = resumed_session {
+            self.resume(&server, &session, state).await?;
         }
 
         Response::from_websocket(client)
@@ -57,12 +196,39 @@ impl DurableObject for BotRoom {
     ) -> Result<()> {
         match message {
             worker::WebSocketIncomingMessage::String(text) => {
-                console_log!("Received text message: {}", text);
-                // Handle text message
+                let Ok(message) = serde_json::from_str::<GatewayMessage>(&text) else {
+                    return send_frame(&ws, &OutboundFrame::error("Malformed message"));
+                };
+                let frame = match message.frame::<InboundFrame>() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        console_log!("Rejecting gateway frame {:?}: {}", message.op, e);
+                        return send_frame(
+                            &ws,
+                            &OutboundFrame::error(format!("Invalid frame: {}", e)),
+                        );
+                    }
+                };
+
+                if let InboundFrame::Ack(ack) = &frame {
+                    if let Some(session) = self.session_of(&ws) {
+                        self.clear_pending(&session, ack.seq).await?;
+                    }
+                    return Ok(());
+                }
+
+                if let (Some(seq), true) = (message.seq, message.ack) {
+                    let ack = serde_json::to_string(&GatewayMessage::ack(seq))?;
+                    ws.send_with_str(&ack)?;
+                }
+                self.handle_frame(&ws, frame).await?;
             }
             worker::WebSocketIncomingMessage::Binary(bits) => {
                 console_log!("Received binary message of length: {}", bits.len());
-                // Handle binary message
+                send_frame(
+                    &ws,
+                    &OutboundFrame::error("Binary frames are not supported"),
+                )?;
             }
         }
         Ok(())
@@ -81,46 +247,292 @@ impl DurableObject for BotRoom {
             reason,
             was_clean
         );
+        self.end_session(&ws).await;
 
-        // Handle WebSocket close event
+        // Complete the closing handshake. 1005 and 1006 only describe how the peer went away
+        // and must never be sent, a plain normal closure answers those.
+        let code = match u16::try_from(code) {
+            Ok(code @ (1000..=1003 | 1007..=1014 | 3000..=4999)) => code,
+            _ => CloseCode::Normal.code(),
+        };
+        if let Err(e) = ws.close(Some(code), Some(CloseCode::Normal.reason())) {
+            // Already closed on the runtime's side, nothing left to answer
+            console_log!("Failed to complete WebSocket close: {}", e);
+        }
         Ok(())
     }
 
     async fn websocket_error(&self, ws: worker::WebSocket, error: worker::Error) -> Result<()> {
         console_log!("WebSocket error: {}", error);
-        // Handle WebSocket error
+        self.end_session(&ws).await;
+        if let Err(e) = ws.close(
+            Some(CloseCode::InternalError.code()),
+            Some(CloseCode::InternalError.reason()),
+        ) {
+            console_log!("Failed to close errored WebSocket: {}", e);
+        }
         Ok(())
     }
+
+    /// Deletes the state of sessions nobody resumed within [`RESUME_WINDOW_MS`] and schedules
+    /// itself again for the ones still inside it.
+    async fn alarm(&self) -> Result<Response> {
+        let storage = self.state.storage();
+        let now = Date::now().as_millis();
+        let mut next_expiry: Option<u64> = None;
+
+        let sessions = storage
+            .list_with_options(ListOptions::new().prefix(SESSION_KEY_PREFIX))
+            .await?;
+        for key in sessions.keys() {
+            let Some(key) = key?.as_string() else {
+                continue;
+            };
+            let Some(session) = key.strip_prefix(SESSION_KEY_PREFIX) else {
+                continue;
+            };
+            let Some(mut state) = stored::<SessionState>(&storage, &key).await? else {
+                continue;
+            };
+            let Some(disconnected_at) = state.disconnected_at else {
+                continue;
+            };
+            if !self.session_sockets(session).is_empty() {
+                // Resumed while the close was still being handled
+                state.disconnected_at = None;
+                storage.put(&key, &state).await?;
+                continue;
+            }
+
+            let expires_at = disconnected_at + RESUME_WINDOW_MS;
+            if expires_at <= now {
+                storage.delete(&key).await?;
+            } else {
+                next_expiry = Some(next_expiry.map_or(expires_at, |next| next.min(expires_at)));
+            }
+        }
+
+        if let Some(expires_at) = next_expiry {
+            storage
+                .set_alarm(Duration::from_millis(expires_at.saturating_sub(now)))
+                .await?;
+        }
+        Response::empty()
+    }
 }
 
 impl BotRoom {
-    fn send_to_bot(&self, message: &str) -> Result<()> {
-        let connections = self.state.get_websockets_with_tag("bot");
-        for ws in connections.iter() {
-            if let Err(e) = ws.send_with_str(message) {
-                console_log!("Failed to send message to bot: {}", e);
+    /// Starts the resume window once the session's last socket is gone, its subscriptions go
+    /// with the socket. Unacknowledged messages stay until the window is over.
+    async fn end_session(&self, ws: &WebSocket) {
+        let Some(session) = self.session_of(ws) else {
+            return;
+        };
+        if let Err(e) = self.mark_disconnected(&session).await {
+            console_log!("Failed to start the resume window of {}: {}", session, e);
+        }
+    }
+
+    async fn mark_disconnected(&self, session: &str) -> Result<()> {
+        let Some(mut state) = self.load_session(session).await? else {
+            return Ok(());
+        };
+        state.disconnected_at = Some(Date::now().as_millis());
+        self.save_session(session, &state).await?;
+        // The alarm checks whether the session came back before deleting anything
+        self.state
+            .storage()
+            .set_alarm(Duration::from_millis(RESUME_WINDOW_MS))
+            .await
+    }
+
+    /// Closes every connection with `code`, returns how many there were. Their sessions stay
+    /// resumable for [`RESUME_WINDOW_MS`].
+    async fn drain(&self, code: CloseCode) -> usize {
+        let sockets = self.state.get_websockets();
+        for ws in &sockets {
+            if let Err(e) = ws.close(Some(code.code()), Some(code.reason())) {
+                console_log!("Failed to close WebSocket while draining: {}", e);
+            }
+            self.end_session(ws).await;
+        }
+        sockets.len()
+    }
+
+    /// Closes the connections tagged `tag` with `code` and forgets their sessions right away,
+    /// a logged out user or removed guild has nothing to resume. Returns how many there were.
+    async fn revoke(&self, tag: &str, code: CloseCode) -> Result<usize> {
+        let sockets = self.state.get_websockets_with_tag(tag);
+        for ws in &sockets {
+            if let Err(e) = ws.close(Some(code.code()), Some(code.reason())) {
+                console_log!("Failed to close revoked WebSocket: {}", e);
+            }
+            if let Some(session) = self.session_of(ws) {
+                let key = format!("{}{}", SESSION_KEY_PREFIX, session);
+                self.state.storage().delete(&key).await?;
+            }
+        }
+        Ok(sockets.len())
+    }
+
+    /// Drops `guild_id` from every socket's subscriptions, the bot's included.
+    fn unsubscribe_all(&self, guild_id: Snowflake) -> Result<()> {
+        for ws in self.state.get_websockets() {
+            let mut socket = socket_state(&ws);
+            if socket.subscriptions.remove(&guild_id) {
+                ws.serialize_attachment(&socket)?;
             }
         }
         Ok(())
     }
 
-    fn send_to_dashboard(&self, guild_id: &str, message: &str) -> Result<()> {
-        let connections = self.state.get_websockets_with_tag(guild_id);
-        for ws in connections.iter() {
-            if let Err(e) = ws.send_with_str(message) {
-                console_log!("Failed to send message to guild {}: {}", guild_id, e);
+    async fn handle_frame(&self, ws: &WebSocket, frame: InboundFrame) -> Result<()> {
+        let client = GatewayClient::from_tags(&self.state.get_tags(ws));
+        match frame {
+            InboundFrame::Heartbeat => send_frame(ws, &OutboundFrame::HeartbeatAck),
+            InboundFrame::Subscribe { guild_id } => {
+                if !client.is_some_and(|client| client.may_subscribe(guild_id)) {
+                    return send_frame(ws, &OutboundFrame::error("Not allowed for this guild"));
+                }
+                let mut socket = socket_state(ws);
+                socket.subscriptions.insert(guild_id);
+                ws.serialize_attachment(&socket)?;
+                send_frame(ws, &OutboundFrame::Subscribed { guild_id })
+            }
+            InboundFrame::Presence { guild_id, status } => {
+                if client != Some(GatewayClient::Bot) {
+                    return send_frame(ws, &OutboundFrame::error("Only the bot can send presence"));
+                }
+                self.send_to_subscribers(guild_id, &OutboundFrame::Presence { guild_id, status })
+                    .await;
+                Ok(())
+            }
+            // Handled before anything else in `websocket_message`
+            InboundFrame::Ack(_) => Ok(()),
+        }
+    }
+
+    /// Relays `frame` to every session subscribed to `guild_id`, buffered until acknowledged.
+    async fn send_to_subscribers(&self, guild_id: Snowflake, frame: &OutboundFrame) {
+        let sessions: BTreeSet<String> = self
+            .state
+            .get_websockets()
+            .iter()
+            .filter(|ws| socket_state(ws).subscriptions.contains(&guild_id))
+            .filter_map(|ws| self.session_of(ws))
+            .collect();
+        for session in sessions {
+            if let Err(e) = self.send_with_ack(&session, frame).await {
+                console_log!("Failed to send message to session {}: {}", session, e);
             }
         }
+    }
+
+    fn session_of(&self, ws: &WebSocket) -> Option<String> {
+        self.state
+            .get_tags(ws)
+            .into_iter()
+            .find_map(|tag| tag.strip_prefix(SESSION_TAG_PREFIX).map(|s| s.to_string()))
+    }
+
+    fn session_sockets(&self, session: &str) -> Vec<WebSocket> {
+        self.state
+            .get_websockets_with_tag(&format!("{}{}", SESSION_TAG_PREFIX, session))
+    }
+
+    async fn load_session(&self, session: &str) -> Result<Option<SessionState>> {
+        let key = format!("{}{}", SESSION_KEY_PREFIX, session);
+        stored(&self.state.storage(), &key).await
+    }
+
+    async fn save_session(&self, session: &str, state: &SessionState) -> Result<()> {
+        let key = format!("{}{}", SESSION_KEY_PREFIX, session);
+        self.state.storage().put(&key, state).await
+    }
+
+    /// Sends `frame` to the session's sockets and keeps it buffered until the session
+    /// acknowledges it, a resumed session gets it replayed.
+    async fn send_with_ack(&self, session: &str, frame: &OutboundFrame) -> Result<()> {
+        let mut state = self.load_session(session).await?.unwrap_or_default();
+        state.next_seq += 1;
+        let seq = state.next_seq;
+        let message = serde_json::to_string(&GatewayMessage::acked(frame, seq)?)?;
+
+        state.pending.push(PendingMessage {
+            seq,
+            message: message.clone(),
+        });
+        if state.pending.len() > MAX_PENDING {
+            let excess = state.pending.len() - MAX_PENDING;
+            state.pending.drain(..excess);
+        }
+        self.save_session(session, &state).await?;
+
+        for ws in self.session_sockets(session) {
+            ws.send_with_str(&message)?;
+        }
+        Ok(())
+    }
+
+    async fn clear_pending(&self, session: &str, seq: u64) -> Result<()> {
+        let Some(mut state) = self.load_session(session).await? else {
+            return Ok(());
+        };
+        let before = state.pending.len();
+        state.pending.retain(|pending| pending.seq != seq);
+        if state.pending.len() != before {
+            self.save_session(session, &state).await?;
+        }
         Ok(())
     }
 
-    fn send_to_all_dashboards(&self, message: &str) -> Result<()> {
-        let connections = self.state.get_websockets_with_tag("guild");
+    /// Replays what `session` hasn't acknowledged to its new socket and ends its resume window.
+    async fn resume(&self, ws: &WebSocket, session: &str, mut state: SessionState) -> Result<()> {
+        console_log!(
+            "Replaying {} messages for session {}",
+            state.pending.len(),
+            session
+        );
+        for pending in &state.pending {
+            if let Err(e) = ws.send_with_str(&pending.message) {
+                console_log!("Failed to replay message to session {}: {}", session, e);
+            }
+        }
+        if state.disconnected_at.take().is_some() {
+            self.save_session(session, &state).await?;
+        }
+        Ok(())
+    }
+
+    fn send_to_bot(&self, message: &str) -> Result<()> {
+        let connections = self.state.get_websockets_with_tag("bot");
         for ws in connections.iter() {
             if let Err(e) = ws.send_with_str(message) {
-                console_log!("Failed to send message to guild: {}", e);
+                console_log!("Failed to send message to bot: {}", e);
             }
         }
         Ok(())
     }
 }
+
+fn send_frame(ws: &WebSocket, frame: &OutboundFrame) -> Result<()> {
+    ws.send_with_str(&frame.to_json()?)
+}
+
+/// The socket's attachment, empty for a socket that never stored one.
+fn socket_state(ws: &WebSocket) -> SocketState {
+    match ws.deserialize_attachment::<SocketState>() {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            console_log!("Failed to read WebSocket attachment: {}", e);
+            SocketState::default()
+        }
+    }
+}
+
+fn new_session_id() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| worker::Error::RustError(format!("Failed to generate session id: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
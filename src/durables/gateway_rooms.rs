@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use worker::{durable_object, Date, Env, Method, Request, Response, Result, State};
+
+use crate::{
+    durables::{bot_room::REVOKE_PATH, stored},
+    services::snowflake::Snowflake,
+};
+
+const ROOMS_KEY: &str = "rooms";
+/// Rooms the owner hasn't joined for this long are dropped from the index. Leaving isn't
+/// reported, an entry for a room the owner already left only costs a no-op on revoke.
+const ROOM_TTL_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+/// Bounds the index, the oldest joins go first.
+const MAX_ROOMS: usize = 64;
+
+/// The gateway rooms a user or guild connected to, one object per [`RoomOwner`]. Logout and
+/// guild removal read it to find the connections they have to close.
+#[durable_object]
+pub struct GatewayRooms {
+    state: State,
+}
+
+/// Room name to when (ms since epoch) the owner last connected to it.
+type Rooms = BTreeMap<String, u64>;
+
+#[derive(Serialize, Deserialize)]
+struct TakeResponse {
+    rooms: Vec<String>,
+}
+
+impl DurableObject for GatewayRooms {
+    fn new(state: State, _env: Env) -> Self {
+        GatewayRooms { state }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let storage = self.state.storage();
+        let mut rooms = stored::<Rooms>(&storage, ROOMS_KEY)
+            .await?
+            .unwrap_or_default();
+
+        let is_post = req.method() == Method::Post;
+        match apply(
+            url.path(),
+            is_post,
+            &query,
+            &mut rooms,
+            Date::now().as_millis(),
+        ) {
+            Outcome::Joined => {
+                storage.put(ROOMS_KEY, &rooms).await?;
+                Response::empty()
+            }
+            Outcome::Taken(taken) => {
+                storage.delete_all().await?;
+                Response::from_json(&TakeResponse { rooms: taken })
+            }
+            Outcome::BadRequest(message) => Response::error(message, 400),
+            Outcome::NotFound => Response::error("Not Found", 404),
+        }
+    }
+}
+
+/// What a request did to the index, for `fetch` to persist.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Joined,
+    /// The index was emptied, these rooms have to be told.
+    Taken(Vec<String>),
+    BadRequest(&'static str),
+    NotFound,
+}
+
+fn apply(
+    path: &str,
+    is_post: bool,
+    query: &HashMap<String, String>,
+    rooms: &mut Rooms,
+    now: u64,
+) -> Outcome {
+    match path {
+        "/join" => {
+            let Some(room) = query.get("room") else {
+                return Outcome::BadRequest("Expected room query parameter");
+            };
+            join(rooms, room, now);
+            Outcome::Joined
+        }
+        "/take" if is_post => Outcome::Taken(std::mem::take(rooms).into_keys().collect()),
+        _ => Outcome::NotFound,
+    }
+}
+
+/// Records a connection to `room` at `now`, dropping stale entries and the oldest ones past
+/// [`MAX_ROOMS`].
+fn join(rooms: &mut Rooms, room: &str, now: u64) {
+    rooms.retain(|_, joined_at| now.saturating_sub(*joined_at) < ROOM_TTL_MS);
+    rooms.insert(room.to_string(), now);
+    while rooms.len() > MAX_ROOMS {
+        let Some(oldest) = rooms
+            .iter()
+            .min_by_key(|(_, joined_at)| **joined_at)
+            .map(|(room, _)| room.clone())
+        else {
+            break;
+        };
+        rooms.remove(&oldest);
+    }
+}
+
+/// Whose connections an index entry tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomOwner {
+    /// A logged in dashboard user, their connections close when they log out.
+    User(Snowflake),
+    /// A guild's dashboard connections, they close when the guild is removed.
+    Guild(Snowflake),
+}
+
+impl RoomOwner {
+    /// Name of the owner's index object, and the query [`REVOKE_PATH`] takes for it.
+    fn query(self) -> String {
+        match self {
+            RoomOwner::User(id) => format!("user={}", id),
+            RoomOwner::Guild(id) => format!("guild={}", id),
+        }
+    }
+
+    /// What each of the owner's rooms is posted, read back by
+    /// [`Revocation::from_url`](crate::durables::bot_room::Revocation::from_url).
+    fn revoke_url(self) -> String {
+        format!("https://botroom{}?{}", REVOKE_PATH, self.query())
+    }
+
+    fn stub(self, env: &Env) -> Result<worker::Stub> {
+        env.durable_object("GATEWAYROOMS")?
+            .id_from_name(&self.query())?
+            .get_stub()
+    }
+}
+
+/// Notes that `owner` connected to the gateway room `room`.
+pub async fn join_room(env: &Env, owner: RoomOwner, room: &str) -> Result<()> {
+    let url = format!(
+        "https://gateway-rooms/join?room={}",
+        urlencoding::encode(room)
+    );
+    owner.stub(env)?.fetch_with_str(&url).await?;
+    Ok(())
+}
+
+/// Closes every gateway connection of `owner` and empties their index. Returns how many rooms
+/// were told, a room that fails is logged and skipped.
+pub async fn revoke(env: &Env, owner: RoomOwner) -> Result<usize> {
+    let request = Request::new("https://gateway-rooms/take", Method::Post)?;
+    let mut response = owner.stub(env)?.fetch_with_request(request).await?;
+    let rooms = response.json::<TakeResponse>().await?.rooms;
+
+    let namespace = env.durable_object("BOTROOM")?;
+    let url = owner.revoke_url();
+    let mut revoked = 0;
+    for room in rooms {
+        let result = async {
+            let request = Request::new(&url, Method::Post)?;
+            namespace
+                .id_from_name(&room)?
+                .get_stub()?
+                .fetch_with_request(request)
+                .await
+        }
+        .await;
+        match result {
+            Ok(_) => revoked += 1,
+            Err(e) => error!(room = %room, "Failed to close gateway connections: {}", e),
+        }
+    }
+    Ok(revoked)
+}
+
+#[cfg(test)]
+mod tests {
+    use worker::Url;
+
+    use super::*;
+    use crate::durables::{
+        bot_room::Revocation,
+        protocol::{CloseCode, GatewayClient},
+    };
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+    const GUILD_ID: u64 = 81384788765712384;
+
+    fn join_query(room: &str) -> HashMap<String, String> {
+        [("room".to_string(), room.to_string())]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn guild_removal_closes_every_indexed_room() {
+        let guild = Snowflake::new(GUILD_ID);
+        let mut rooms = Rooms::new();
+        for (now, room) in ["lobby", "events"].into_iter().enumerate() {
+            let outcome = apply("/join", false, &join_query(room), &mut rooms, now as u64);
+            assert_eq!(outcome, Outcome::Joined);
+        }
+
+        let empty = HashMap::new();
+        let Outcome::Taken(taken) = apply("/take", true, &empty, &mut rooms, 2) else {
+            panic!("take should hand the rooms over");
+        };
+        assert_eq!(taken, vec!["events".to_string(), "lobby".to_string()]);
+        // Consumed, a second removal has nothing left to close
+        assert!(rooms.is_empty());
+        assert_eq!(
+            apply("/take", true, &empty, &mut rooms, 3),
+            Outcome::Taken(Vec::new())
+        );
+
+        // Each room closes the guild's sockets with 4004 and drops the guild's subscriptions
+        let url = Url::parse(&RoomOwner::Guild(guild).revoke_url()).unwrap();
+        let revocation = Revocation::from_url(&url).unwrap();
+        assert_eq!(revocation.code, CloseCode::GuildRemoved);
+        assert_eq!(revocation.unsubscribe, Some(guild));
+        assert!(GatewayClient::Guild(guild).tags().contains(&revocation.tag));
+        assert!(!GatewayClient::Bot.tags().contains(&revocation.tag));
+    }
+
+    #[test]
+    fn logout_consumes_the_users_index() {
+        let user = Snowflake::new(GUILD_ID);
+        let mut rooms = Rooms::new();
+        apply("/join", false, &join_query("lobby"), &mut rooms, 0);
+
+        let taken = apply("/take", true, &HashMap::new(), &mut rooms, 1);
+        assert_eq!(taken, Outcome::Taken(vec!["lobby".to_string()]));
+        assert!(rooms.is_empty());
+
+        let url = Url::parse(&RoomOwner::User(user).revoke_url()).unwrap();
+        let revocation = Revocation::from_url(&url).unwrap();
+        assert_eq!(revocation.code, CloseCode::Unauthorized);
+        assert_eq!(revocation.unsubscribe, None);
+        assert!(GatewayClient::User(user).tags().contains(&revocation.tag));
+        // The user's id as a guild tag must not match, the two share id spaces
+        assert!(!GatewayClient::Guild(user).tags().contains(&revocation.tag));
+    }
+
+    #[test]
+    fn take_needs_a_post() {
+        let mut rooms = Rooms::new();
+        join(&mut rooms, "lobby", 0);
+        assert_eq!(
+            apply("/take", false, &HashMap::new(), &mut rooms, 1),
+            Outcome::NotFound
+        );
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(
+            apply("/join", false, &HashMap::new(), &mut rooms, 1),
+            Outcome::BadRequest("Expected room query parameter")
+        );
+    }
+
+    #[test]
+    fn join_records_the_latest_connection() {
+        let mut rooms = Rooms::new();
+        join(&mut rooms, "lobby", 1);
+        join(&mut rooms, "lobby", 2);
+        assert_eq!(rooms.get("lobby"), Some(&2));
+        assert_eq!(rooms.len(), 1);
+    }
+
+    #[test]
+    fn stale_rooms_are_dropped() {
+        let mut rooms = Rooms::new();
+        join(&mut rooms, "old", 0);
+        join(&mut rooms, "new", ROOM_TTL_MS - DAY_MS);
+        join(&mut rooms, "newer", ROOM_TTL_MS);
+        assert!(!rooms.contains_key("old"));
+        assert!(rooms.contains_key("new"));
+        assert!(rooms.contains_key("newer"));
+    }
+
+    #[test]
+    fn oldest_rooms_go_past_the_limit() {
+        let mut rooms = Rooms::new();
+        for i in 0..=MAX_ROOMS as u64 {
+            join(&mut rooms, &format!("room-{}", i), i);
+        }
+        assert_eq!(rooms.len(), MAX_ROOMS);
+        assert!(!rooms.contains_key("room-0"));
+        assert!(rooms.contains_key(&format!("room-{}", MAX_ROOMS)));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use worker::{durable_object, Date, Env, Method, Request, Response, Result, State};
+
+use super::stored;
+
+const RECORD_KEY: &str = "record";
+/// How long a key stays claimed by a request that never reports back, e.g. a crashed isolate.
+const IN_FLIGHT_SECS: u64 = 60;
+
+/// Remembers one `Idempotency-Key`, one instance per caller and key. The record is kept in
+/// storage rather than memory so a replay still works after the object was evicted, an alarm
+/// deletes it once its TTL is up.
+#[durable_object]
+pub struct IdempotencyStore {
+    state: State,
+}
+
+/// A finished response, as much of it as is needed to send it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Standard base64, bodies aren't necessarily UTF-8.
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// Hash of the method, path and body the key was first used with.
+    fingerprint: String,
+    expires_at: u64,
+    /// `None` while the first request is still running.
+    response: Option<StoredResponse>,
+}
+
+/// What `/begin` found for the key.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BeginResponse {
+    /// The key was free and is now claimed by the caller, who must `/complete` or `/abort`.
+    Started,
+    /// Another request with this key hasn't finished yet.
+    InFlight,
+    /// The key was used for a different request.
+    Mismatch,
+    Completed {
+        response: StoredResponse,
+    },
+}
+
+impl DurableObject for IdempotencyStore {
+    fn new(state: State, _env: Env) -> Self {
+        IdempotencyStore { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let Some(fingerprint) = query.get("fingerprint") else {
+            return Response::error("Expected fingerprint query parameter", 400);
+        };
+        let now = Date::now().as_millis();
+        let record = stored::<Record>(&self.state.storage(), RECORD_KEY)
+            .await?
+            .filter(|record| record.expires_at > now);
+
+        match url.path() {
+            "/begin" => {
+                let begin = match record {
+                    Some(record) if record.fingerprint != *fingerprint => BeginResponse::Mismatch,
+                    Some(Record {
+                        response: Some(response),
+                        ..
+                    }) => BeginResponse::Completed { response },
+                    Some(_) => BeginResponse::InFlight,
+                    None => {
+                        self.save(&Record {
+                            fingerprint: fingerprint.clone(),
+                            expires_at: now + IN_FLIGHT_SECS * 1000,
+                            response: None,
+                        })
+                        .await?;
+                        BeginResponse::Started
+                    }
+                };
+                Response::from_json(&begin)
+            }
+            "/complete" if req.method() == Method::Post => {
+                let Some(ttl_secs) = query.get("ttl").and_then(|v| v.parse::<u64>().ok()) else {
+                    return Response::error("Expected ttl query parameter", 400);
+                };
+                // Only the request that claimed the key may fill it in. A claim that timed out
+                // in the meantime is filled in as well, nobody else holds the key.
+                let claimed = match &record {
+                    Some(r) => r.fingerprint == *fingerprint && r.response.is_none(),
+                    None => true,
+                };
+                if claimed {
+                    let response = req.json::<StoredResponse>().await?;
+                    self.save(&Record {
+                        fingerprint: fingerprint.clone(),
+                        expires_at: now + ttl_secs * 1000,
+                        response: Some(response),
+                    })
+                    .await?;
+                }
+                Response::empty()
+            }
+            "/abort" => {
+                // Frees the key for a retry, a finished response is never thrown away
+                if record.is_some_and(|r| r.fingerprint == *fingerprint && r.response.is_none()) {
+                    self.state.storage().delete_all().await?;
+                }
+                Response::empty()
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let now = Date::now().as_millis();
+        let storage = self.state.storage();
+        match stored::<Record>(&storage, RECORD_KEY).await? {
+            // Completing a key pushes its expiry out after the alarm was set
+            Some(record) if record.expires_at > now => {
+                storage
+                    .set_alarm(Duration::from_millis(record.expires_at - now))
+                    .await?;
+            }
+            _ => storage.delete_all().await?,
+        }
+        Response::empty()
+    }
+}
+
+impl IdempotencyStore {
+    async fn save(&self, record: &Record) -> Result<()> {
+        let storage = self.state.storage();
+        storage.put(RECORD_KEY, record).await?;
+        let ttl = record.expires_at.saturating_sub(Date::now().as_millis());
+        storage.set_alarm(Duration::from_millis(ttl)).await
+    }
+}
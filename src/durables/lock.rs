@@ -0,0 +1,326 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use worker::{durable_object, Date, Env, Request, Response, Result, State};
+
+use super::stored;
+
+const HOLDER_KEY: &str = "holder";
+
+/// A mutex shared by every isolate, one object per lock key.
+///
+/// Locks carry a TTL so a holder that dies without releasing can't wedge the key forever. The
+/// holder is kept in storage so an evicted object doesn't forget the lock is taken, an alarm
+/// deletes it once the TTL is up.
+#[durable_object]
+pub struct DurableLock {
+    state: State,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Holder {
+    token: String,
+    expires_at: u64,
+}
+
+impl Holder {
+    /// The holder after `token` asks for the lock at `now`, or `None` while `current` still
+    /// holds it.
+    fn claim(current: Option<&Holder>, token: String, ttl_ms: u64, now: u64) -> Option<Holder> {
+        if current.is_some_and(|h| h.expires_at > now) {
+            return None;
+        }
+        Some(Holder {
+            token,
+            expires_at: now + ttl_ms,
+        })
+    }
+
+    /// Only the current holder may release, a stale guard must not free someone else's lock.
+    fn released_by(&self, token: &str) -> bool {
+        self.token == token
+    }
+}
+
+/// What a request does to the lock, decided before anything is written.
+#[derive(Debug, PartialEq, Eq)]
+enum Transition {
+    /// Store the new holder and hand its token back.
+    Acquired {
+        holder: Holder,
+        ttl_ms: u64,
+    },
+    /// Somebody else holds the lock.
+    Busy,
+    /// The holder let go, clear storage.
+    Released,
+    /// A release by a token that doesn't hold the lock.
+    Ignored,
+    BadRequest(&'static str),
+    NotFound,
+}
+
+impl Transition {
+    /// `new_token` is only called for an acquire that can succeed.
+    fn decide(
+        path: &str,
+        query: &HashMap<String, String>,
+        holder: Option<&Holder>,
+        now: u64,
+        new_token: impl FnOnce() -> Result<String>,
+    ) -> Result<Transition> {
+        match path {
+            "/acquire" => {
+                let Some(ttl_secs) = query.get("ttl").and_then(|v| v.parse::<u64>().ok()) else {
+                    return Ok(Transition::BadRequest("Expected ttl query parameter"));
+                };
+                let ttl_ms = ttl_secs * 1000;
+                if holder.is_some_and(|h| h.expires_at > now) {
+                    return Ok(Transition::Busy);
+                }
+                Ok(Holder::claim(holder, new_token()?, ttl_ms, now).map_or(
+                    Transition::Busy,
+                    |holder| Transition::Acquired { holder, ttl_ms },
+                ))
+            }
+            "/release" => {
+                let token = query.get("token").map(String::as_str).unwrap_or_default();
+                if holder.is_some_and(|h| h.released_by(token)) {
+                    Ok(Transition::Released)
+                } else {
+                    Ok(Transition::Ignored)
+                }
+            }
+            _ => Ok(Transition::NotFound),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AcquireResponse {
+    token: Option<String>,
+}
+
+impl DurableObject for DurableLock {
+    fn new(state: State, _env: Env) -> Self {
+        DurableLock { state }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let now = Date::now().as_millis();
+        // The input gate holds other requests back while this one reads and writes storage, so
+        // two acquires can't both see the lock free
+        let storage = self.state.storage();
+        let holder = stored::<Holder>(&storage, HOLDER_KEY).await?;
+
+        match Transition::decide(url.path(), &query, holder.as_ref(), now, random_token)? {
+            Transition::Acquired { holder, ttl_ms } => {
+                storage.put(HOLDER_KEY, &holder).await?;
+                storage.set_alarm(Duration::from_millis(ttl_ms)).await?;
+                Response::from_json(&AcquireResponse {
+                    token: Some(holder.token),
+                })
+            }
+            Transition::Busy => Response::from_json(&AcquireResponse { token: None }),
+            Transition::Released => {
+                storage.delete_all().await?;
+                Response::empty()
+            }
+            Transition::Ignored => Response::empty(),
+            Transition::BadRequest(message) => Response::error(message, 400),
+            Transition::NotFound => Response::error("Not Found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let now = Date::now().as_millis();
+        let storage = self.state.storage();
+        match stored::<Holder>(&storage, HOLDER_KEY).await? {
+            // Released and taken again since the alarm was set
+            Some(holder) if holder.expires_at > now => {
+                storage
+                    .set_alarm(Duration::from_millis(holder.expires_at - now))
+                    .await?;
+            }
+            _ => storage.delete_all().await?,
+        }
+        Response::empty()
+    }
+}
+
+fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| worker::Error::RustError(format!("Failed to generate lock token: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Proof of holding the lock for `key`, pass it back to [`LockGuard::release`].
+#[derive(Debug)]
+pub struct LockGuard {
+    key: String,
+    token: String,
+}
+
+impl LockGuard {
+    pub async fn release(self, env: &Env) -> Result<()> {
+        let namespace = env.durable_object("LOCKS")?;
+        let stub = namespace.id_from_name(&self.key)?.get_stub()?;
+        let url = format!(
+            "https://lock/release?token={}",
+            urlencoding::encode(&self.token)
+        );
+        stub.fetch_with_str(&url).await?;
+        Ok(())
+    }
+}
+
+/// Tries once to take the lock for `key`, returns `None` if somebody else holds it.
+pub async fn acquire(env: &Env, key: &str, ttl_secs: u64) -> Result<Option<LockGuard>> {
+    let namespace = env.durable_object("LOCKS")?;
+    let stub = namespace.id_from_name(key)?.get_stub()?;
+    let url = format!("https://lock/acquire?ttl={}", ttl_secs);
+
+    let mut response = stub.fetch_with_str(&url).await?;
+    Ok(response
+        .json::<AcquireResponse>()
+        .await?
+        .token
+        .map(|token| LockGuard {
+            key: key.to_string(),
+            token,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL_MS: u64 = 30_000;
+
+    #[test]
+    fn concurrent_acquires_serialize() {
+        let first = Holder::claim(None, "a".to_string(), TTL_MS, 0).unwrap();
+        assert_eq!(
+            Holder::claim(Some(&first), "b".to_string(), TTL_MS, 1),
+            None
+        );
+
+        // Once released the next acquire goes through
+        assert!(first.released_by("a"));
+        let second = Holder::claim(None, "b".to_string(), TTL_MS, 2).unwrap();
+        assert_eq!(second.token, "b");
+    }
+
+    #[test]
+    fn ttl_expiry_frees_a_stuck_lock() {
+        let stuck = Holder::claim(None, "a".to_string(), TTL_MS, 0).unwrap();
+        assert_eq!(
+            Holder::claim(Some(&stuck), "b".to_string(), TTL_MS, TTL_MS - 1),
+            None
+        );
+
+        let next = Holder::claim(Some(&stuck), "b".to_string(), TTL_MS, TTL_MS).unwrap();
+        assert_eq!(next.token, "b");
+        assert_eq!(next.expires_at, TTL_MS * 2);
+    }
+
+    /// Runs a request against an in-memory holder the way `fetch` runs it against storage.
+    fn request(
+        holder: &mut Option<Holder>,
+        path: &str,
+        query: &[(&str, &str)],
+        now: u64,
+    ) -> Transition {
+        let query = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let token = format!("token-{}", now);
+        let transition =
+            Transition::decide(path, &query, holder.as_ref(), now, || Ok(token)).unwrap();
+        match &transition {
+            Transition::Acquired {
+                holder: claimed, ..
+            } => *holder = Some(claimed.clone()),
+            Transition::Released => *holder = None,
+            _ => {}
+        }
+        transition
+    }
+
+    #[test]
+    fn acquire_and_release_round_trip() {
+        let mut holder = None;
+
+        let Transition::Acquired {
+            holder: first,
+            ttl_ms,
+        } = request(&mut holder, "/acquire", &[("ttl", "30")], 0)
+        else {
+            panic!("a free lock should be acquired");
+        };
+        assert_eq!(ttl_ms, TTL_MS);
+        assert_eq!(first.expires_at, TTL_MS);
+
+        assert_eq!(
+            request(&mut holder, "/acquire", &[("ttl", "30")], 1),
+            Transition::Busy
+        );
+        assert_eq!(
+            request(&mut holder, "/release", &[("token", "someone-else")], 2),
+            Transition::Ignored
+        );
+        assert_eq!(holder.as_ref(), Some(&first));
+
+        assert_eq!(
+            request(&mut holder, "/release", &[("token", &first.token)], 3),
+            Transition::Released
+        );
+        assert_eq!(holder, None);
+
+        let Transition::Acquired { holder: second, .. } =
+            request(&mut holder, "/acquire", &[("ttl", "30")], 4)
+        else {
+            panic!("a released lock should be acquired again");
+        };
+        assert_ne!(second.token, first.token);
+    }
+
+    #[test]
+    fn busy_acquire_does_not_mint_a_token() {
+        let holder = Holder::claim(None, "a".to_string(), TTL_MS, 0).unwrap();
+        let query = [("ttl".to_string(), "30".to_string())]
+            .into_iter()
+            .collect();
+        let transition = Transition::decide("/acquire", &query, Some(&holder), 1, || {
+            panic!("token generated for a held lock")
+        });
+        assert_eq!(transition.unwrap(), Transition::Busy);
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let mut holder = None;
+        assert_eq!(
+            request(&mut holder, "/acquire", &[], 0),
+            Transition::BadRequest("Expected ttl query parameter")
+        );
+        assert_eq!(
+            request(&mut holder, "/acquire", &[("ttl", "soon")], 0),
+            Transition::BadRequest("Expected ttl query parameter")
+        );
+        assert_eq!(request(&mut holder, "/steal", &[], 0), Transition::NotFound);
+        assert_eq!(holder, None);
+    }
+
+    #[test]
+    fn only_the_holder_releases() {
+        let holder = Holder::claim(None, "a".to_string(), TTL_MS, 0).unwrap();
+        assert!(!holder.released_by("b"));
+        assert!(!holder.released_by(""));
+        assert!(holder.released_by("a"));
+    }
+}
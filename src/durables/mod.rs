@@ -1 +1,21 @@
 pub mod bot_room;
+pub mod gateway_rooms;
+pub mod idempotency;
+pub mod lock;
+pub mod protocol;
+pub mod rate_limiter;
+
+use serde::de::DeserializeOwned;
+use worker::{wasm_bindgen::JsValue, Result, Storage};
+
+/// Reads `key`, `None` when nothing is stored under it.
+///
+/// `Storage::get` fails on a missing key the same way it fails on a broken read, so objects that
+/// treat "never written" as a normal state look the key up first.
+pub(crate) async fn stored<T: DeserializeOwned>(storage: &Storage, key: &str) -> Result<Option<T>> {
+    let found = storage.get_multiple(vec![key]).await?;
+    if !found.has(&JsValue::from_str(key)) {
+        return Ok(None);
+    }
+    storage.get(key).await.map(Some)
+}
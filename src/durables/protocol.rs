@@ -0,0 +1,298 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{services::snowflake::Snowflake, state::user::Caller};
+
+/// Header the gateway route passes the verified caller to the room in, see
+/// [`GatewayClient::to_header`]. The route drops whatever the client sent under this name.
+pub const GATEWAY_CLIENT_HEADER: &str = "x-gateway-client";
+const USER_TAG_PREFIX: &str = "user:";
+
+/// Envelope for every message going over the gateway.
+///
+/// Messages carrying a `seq` with `ack: true` must be acknowledged by the receiver with an
+/// [`GatewayMessage::ack`] message, until then the sender keeps them buffered and replays them
+/// when the session resumes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayMessage {
+    pub op: String,
+    #[serde(default)]
+    pub d: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ack: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AckData {
+    pub seq: u64,
+}
+
+pub const OP_ACK: &str = "ack";
+
+impl GatewayMessage {
+    pub fn ack(seq: u64) -> Self {
+        Self {
+            op: OP_ACK.into(),
+            d: serde_json::json!({ "seq": seq }),
+            seq: None,
+            ack: false,
+        }
+    }
+
+    /// `frame` (e.g. an [`OutboundFrame`]) as a message the receiver must acknowledge under
+    /// `seq`.
+    pub fn acked<T: Serialize>(frame: &T, seq: u64) -> serde_json::Result<Self> {
+        let mut message: Self = serde_json::from_value(serde_json::to_value(frame)?)?;
+        message.seq = Some(seq);
+        message.ack = true;
+        Ok(message)
+    }
+
+    /// Decodes `op` and `d` into a typed frame, e.g. [`InboundFrame`].
+    pub fn frame<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_value(serde_json::json!({ "op": self.op, "d": self.d }))
+    }
+
+    /// The sequence number this message acknowledges, if it's an ack.
+    pub fn acked_seq(&self) -> Option<u64> {
+        if self.op != OP_ACK {
+            return None;
+        }
+        serde_json::from_value::<AckData>(self.d.clone())
+            .ok()
+            .map(|d| d.seq)
+    }
+}
+
+/// What clients may send over the gateway, as the `op`/`d` of a [`GatewayMessage`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "d", rename_all = "snake_case")]
+pub enum InboundFrame {
+    /// Keeps the connection alive, answered with [`OutboundFrame::HeartbeatAck`].
+    Heartbeat,
+    /// Asks for the events of a guild on this session.
+    Subscribe {
+        guild_id: Snowflake,
+    },
+    /// The bot's status in a guild, relayed to the dashboards watching it.
+    Presence {
+        guild_id: Snowflake,
+        status: String,
+    },
+    Ack(AckData),
+}
+
+/// What the gateway sends back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "d", rename_all = "snake_case")]
+pub enum OutboundFrame {
+    /// First frame on every connection. Reconnecting with `?session=<session_id>` resumes the
+    /// session and replays what it hasn't acknowledged.
+    Ready {
+        session_id: String,
+    },
+    HeartbeatAck,
+    Subscribed {
+        guild_id: Snowflake,
+    },
+    Presence {
+        guild_id: Snowflake,
+        status: String,
+    },
+    /// The client sent something the gateway doesn't understand or allow.
+    Error {
+        message: String,
+    },
+}
+
+impl OutboundFrame {
+    pub fn error(message: impl Into<String>) -> Self {
+        OutboundFrame::Error {
+            message: message.into(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Who opened a gateway connection, as `api_protect` verified it. The room tags each socket
+/// with it, so revocations and subscriptions never rest on anything the client claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayClient {
+    Bot,
+    Guild(Snowflake),
+    User(Snowflake),
+}
+
+impl GatewayClient {
+    /// Admins have nothing to do on the gateway.
+    pub fn from_caller(caller: &Caller) -> Option<Self> {
+        match caller {
+            Caller::Bot => Some(GatewayClient::Bot),
+            Caller::Guild(id) => Some(GatewayClient::Guild(*id)),
+            Caller::User(id) => Some(GatewayClient::User(*id)),
+            Caller::Admin => None,
+        }
+    }
+
+    pub fn to_header(self) -> String {
+        match self {
+            GatewayClient::Bot => "bot".to_string(),
+            GatewayClient::Guild(id) => format!("guild:{}", id),
+            GatewayClient::User(id) => format!("user:{}", id),
+        }
+    }
+
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value.split_once(':') {
+            None if value == "bot" => Some(GatewayClient::Bot),
+            Some(("guild", id)) => id.parse().ok().map(GatewayClient::Guild),
+            Some(("user", id)) => id.parse().ok().map(GatewayClient::User),
+            _ => None,
+        }
+    }
+
+    /// What the room accepts the socket with. Guild sockets carry the bare guild id so guild
+    /// removal can close them by it.
+    pub fn tags(self) -> Vec<String> {
+        match self {
+            GatewayClient::Bot => vec!["bot".to_string()],
+            GatewayClient::Guild(id) => vec!["guild".to_string(), id.to_string()],
+            GatewayClient::User(id) => vec![user_tag(id)],
+        }
+    }
+
+    /// Reverses [`GatewayClient::tags`], ignoring the socket's other tags.
+    pub fn from_tags(tags: &[String]) -> Option<Self> {
+        if tags.iter().any(|tag| tag == "bot") {
+            return Some(GatewayClient::Bot);
+        }
+        if tags.iter().any(|tag| tag == "guild") {
+            return tags
+                .iter()
+                .find_map(|tag| tag.parse().ok())
+                .map(GatewayClient::Guild);
+        }
+        tags.iter()
+            .find_map(|tag| tag.strip_prefix(USER_TAG_PREFIX)?.parse().ok())
+            .map(GatewayClient::User)
+    }
+
+    /// The bot may follow any guild and a guild client only its own. Dashboard users aren't
+    /// scoped to a guild, so there is nothing they may subscribe to.
+    pub fn may_subscribe(self, guild_id: Snowflake) -> bool {
+        match self {
+            GatewayClient::Bot => true,
+            GatewayClient::Guild(id) => id == guild_id,
+            GatewayClient::User(_) => false,
+        }
+    }
+}
+
+/// The tag of every socket `user_id` opened, what logout closes them by.
+pub fn user_tag(user_id: Snowflake) -> String {
+    format!("{}{}", USER_TAG_PREFIX, user_id)
+}
+
+/// Close codes the gateway ends connections with. The 4xxx range is ours, RFC 6455 leaves it to
+/// applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// The session is over and everything it held was released.
+    Normal,
+    /// The peer went away without saying why, e.g. a tab closed mid-connection.
+    GoingAway,
+    /// Something on our side broke while handling the connection.
+    InternalError,
+    /// The room is shutting down, clients should reconnect (and resume) after a moment.
+    ServiceRestart,
+    /// The user logged out, their access is gone. Don't reconnect with the same session.
+    Unauthorized,
+    /// The browser's `Origin` isn't one of the allowed origins.
+    OriginNotAllowed,
+    /// The guild was removed, there is nothing left to show for it.
+    GuildRemoved,
+}
+
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::Unauthorized => 4001,
+            CloseCode::OriginNotAllowed => 4003,
+            CloseCode::GuildRemoved => 4004,
+        }
+    }
+
+    /// Sent along with the code, kept well under the 123 bytes a close frame allows.
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseCode::Normal => "Session closed",
+            CloseCode::GoingAway => "Going away",
+            CloseCode::InternalError => "Internal error",
+            CloseCode::ServiceRestart => "Gateway restarting, reconnect shortly",
+            CloseCode::Unauthorized => "Logged out",
+            CloseCode::OriginNotAllowed => "Origin not allowed",
+            CloseCode::GuildRemoved => "Guild removed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD_ID: u64 = 81384788765712384;
+
+    #[test]
+    fn clients_come_from_the_verified_caller_only() {
+        let guild = Snowflake::new(GUILD_ID);
+        assert_eq!(
+            GatewayClient::from_caller(&Caller::Guild(guild)),
+            Some(GatewayClient::Guild(guild))
+        );
+        assert_eq!(GatewayClient::from_caller(&Caller::Admin), None);
+
+        for client in [
+            GatewayClient::Bot,
+            GatewayClient::Guild(guild),
+            GatewayClient::User(guild),
+        ] {
+            assert_eq!(
+                GatewayClient::from_header(&client.to_header()),
+                Some(client)
+            );
+            let mut tags = client.tags();
+            tags.push("session:00ff".to_string());
+            assert_eq!(GatewayClient::from_tags(&tags), Some(client));
+        }
+        // What older clients put in their User-Agent means nothing any more
+        assert_eq!(GatewayClient::from_header("DiscordBot"), None);
+        assert_eq!(
+            GatewayClient::from_header(&format!("DiscordGuild/{}", GUILD_ID)),
+            None
+        );
+        assert_eq!(GatewayClient::from_header("guild:not-a-snowflake"), None);
+    }
+
+    #[test]
+    fn subscribe_is_refused_outside_the_clients_scope() {
+        let guild = Snowflake::new(GUILD_ID);
+        let other = Snowflake::new(GUILD_ID + 1);
+        assert!(GatewayClient::Bot.may_subscribe(guild));
+        assert!(GatewayClient::Guild(guild).may_subscribe(guild));
+
+        assert!(!GatewayClient::Guild(other).may_subscribe(guild));
+        assert!(!GatewayClient::User(guild).may_subscribe(guild));
+        // A socket without a client tag, e.g. accepted before tags were verified, gets nothing
+        let untagged = GatewayClient::from_tags(&["session:00ff".to_string()]);
+        assert!(!untagged.is_some_and(|client| client.may_subscribe(guild)));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use serde::Serialize;
+use worker::{durable_object, Date, Env, Request, Response, Result, State};
+
+/// Token bucket, one instance per rate limit key. It can also hold a cooldown deadline for
+/// limits imposed on us from outside (e.g. Discord's global rate limit) and hand out leased
+/// permits to act as a semaphore across isolates.
+///
+/// State only lives in memory, if the object gets evicted the bucket simply starts full again
+/// which errs on the side of letting requests through.
+#[durable_object]
+pub struct RateLimiter {
+    bucket: RefCell<Option<Bucket>>,
+    cooldown_until: RefCell<u64>,
+    /// Lease id to expiry, leases expire so a crashed isolate can't hold a permit forever.
+    leases: RefCell<HashMap<String, u64>>,
+}
+
+/// `limit` tokens at most, refilled continuously so an empty bucket is full again after one
+/// window. Bursts up to the limit pass, sustained traffic is held to `limit` per window without
+/// the double burst a fixed window allows around its edges.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: u64,
+}
+
+#[derive(Serialize)]
+struct AcquireResponse {
+    lease: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CooldownResponse {
+    remaining: u64,
+}
+
+#[derive(Serialize)]
+struct HitResponse {
+    allowed: bool,
+    retry_after: u64,
+}
+
+impl DurableObject for RateLimiter {
+    fn new(_state: State, _env: Env) -> Self {
+        RateLimiter {
+            bucket: RefCell::new(None),
+            cooldown_until: RefCell::new(0),
+            leases: RefCell::new(HashMap::new()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        match url.path() {
+            "/cooldown" => {
+                let set = query.get("set").and_then(|v| v.parse::<u64>().ok());
+                return Response::from_json(&self.cooldown(set));
+            }
+            "/acquire" => {
+                let (Some(max), Some(lease_secs)) = (
+                    query.get("max").and_then(|v| v.parse::<usize>().ok()),
+                    query.get("lease").and_then(|v| v.parse::<u64>().ok()),
+                ) else {
+                    return Response::error("Expected max and lease query parameters", 400);
+                };
+                return Response::from_json(&self.acquire(max, lease_secs * 1000)?);
+            }
+            "/release" => {
+                if let Some(lease) = query.get("lease") {
+                    self.leases.borrow_mut().remove(lease);
+                }
+                return Response::empty();
+            }
+            _ => {}
+        }
+
+        let (Some(limit), Some(window_secs)) = (
+            query.get("limit").and_then(|v| v.parse::<u32>().ok()),
+            query.get("window").and_then(|v| v.parse::<u64>().ok()),
+        ) else {
+            return Response::error("Expected limit and window query parameters", 400);
+        };
+
+        Response::from_json(&self.hit(limit, window_secs * 1000))
+    }
+}
+
+impl RateLimiter {
+    fn acquire(&self, max: usize, lease_ms: u64) -> Result<AcquireResponse> {
+        let now = Date::now().as_millis();
+        let mut leases = self.leases.borrow_mut();
+        leases.retain(|_, expires_at| *expires_at > now);
+        if leases.len() >= max {
+            return Ok(AcquireResponse { lease: None });
+        }
+
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|e| worker::Error::RustError(format!("Failed to generate lease: {}", e)))?;
+        let lease: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        leases.insert(lease.clone(), now + lease_ms);
+        Ok(AcquireResponse { lease: Some(lease) })
+    }
+
+    fn cooldown(&self, set: Option<u64>) -> CooldownResponse {
+        let now = Date::now().as_millis();
+        let mut cooldown_until = self.cooldown_until.borrow_mut();
+        if let Some(secs) = set {
+            *cooldown_until = (*cooldown_until).max(now + secs * 1000);
+        }
+        CooldownResponse {
+            remaining: cooldown_until.saturating_sub(now).div_ceil(1000),
+        }
+    }
+
+    fn hit(&self, limit: u32, window_ms: u64) -> HitResponse {
+        let now = Date::now().as_millis();
+        let mut bucket = self.bucket.borrow_mut();
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: limit as f64,
+            refilled_at: now,
+        });
+        bucket.take(limit, window_ms, now)
+    }
+}
+
+impl Bucket {
+    /// Refills for the time since the last call, then takes a token if there is one.
+    fn take(&mut self, limit: u32, window_ms: u64, now: u64) -> HitResponse {
+        let limit = limit as f64;
+        let window_ms = window_ms.max(1) as f64;
+        let elapsed = now.saturating_sub(self.refilled_at) as f64;
+        self.tokens = (self.tokens + elapsed * limit / window_ms).min(limit);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            let wait_ms = ((1.0 - self.tokens) * window_ms / limit).ceil() as u64;
+            return HitResponse {
+                allowed: false,
+                retry_after: wait_ms.div_ceil(1000).max(1),
+            };
+        }
+
+        self.tokens -= 1.0;
+        HitResponse {
+            allowed: true,
+            retry_after: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u32 = 3;
+    const WINDOW_MS: u64 = 60_000;
+
+    fn full(now: u64) -> Bucket {
+        Bucket {
+            tokens: LIMIT as f64,
+            refilled_at: now,
+        }
+    }
+
+    #[test]
+    fn allows_a_burst_up_to_the_limit() {
+        let mut bucket = full(0);
+        for _ in 0..LIMIT {
+            assert!(bucket.take(LIMIT, WINDOW_MS, 0).allowed);
+        }
+        let limited = bucket.take(LIMIT, WINDOW_MS, 0);
+        assert!(!limited.allowed);
+        // One token comes back every 20s
+        assert_eq!(limited.retry_after, 20);
+    }
+
+    #[test]
+    fn refills_one_token_at_a_time() {
+        let mut bucket = full(0);
+        for _ in 0..LIMIT {
+            bucket.take(LIMIT, WINDOW_MS, 0);
+        }
+        // Half a token after 10s, the other half by 20s
+        assert!(!bucket.take(LIMIT, WINDOW_MS, 10_000).allowed);
+        assert!(bucket.take(LIMIT, WINDOW_MS, 20_000).allowed);
+        assert!(!bucket.take(LIMIT, WINDOW_MS, 20_000).allowed);
+    }
+
+    #[test]
+    fn never_holds_more_than_the_limit() {
+        let mut bucket = full(0);
+        // Idle for far longer than a window, still only a burst of `LIMIT`
+        let later = WINDOW_MS * 10;
+        for _ in 0..LIMIT {
+            assert!(bucket.take(LIMIT, WINDOW_MS, later).allowed);
+        }
+        assert!(!bucket.take(LIMIT, WINDOW_MS, later).allowed);
+    }
+
+    #[test]
+    fn no_double_burst_across_a_window_edge() {
+        let mut bucket = full(0);
+        for _ in 0..LIMIT {
+            assert!(bucket.take(LIMIT, WINDOW_MS, WINDOW_MS - 1).allowed);
+        }
+        // A fixed window would reset here and allow another full burst
+        assert!(!bucket.take(LIMIT, WINDOW_MS, WINDOW_MS + 1).allowed);
+    }
+}
@@ -1,25 +1,42 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use axum::{
     body::Body,
-    http::{HeaderValue, Response},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+        HeaderName, HeaderValue, Response,
+    },
+    response::IntoResponse,
     routing::get,
-    Extension, Router,
+    Extension, Json, Router,
 };
+use futures::future::{select, Either};
 use reqwest::{Method, StatusCode};
-use tower_http::cors::{AllowCredentials, Any, CorsLayer};
+use tower_http::cors::{AllowCredentials, AllowOrigin, CorsLayer};
 use tower_service::Service;
+use tracing::{error, warn};
 use tracing_subscriber::{
+    filter::LevelFilter,
     fmt::{format::Pretty, time::UtcTime},
     layer::SubscriberExt,
+    reload,
     util::SubscriberInitExt,
+    Registry,
 };
 use tracing_web::{performance_layer, MakeConsoleWriter};
-use worker::{console_error, event, Context, Env, Error, HttpRequest, Result};
+use worker::{console_error, event, Context, Delay, Env, Error, HttpRequest, Result};
 
 use crate::{
+    api::error::ApiError,
     services::database::Database,
-    state::{app_state::AppState, server_info::ServerInfo},
+    state::{
+        app_state::{AppState, AppStateArc},
+        server_config::ServerConfig,
+        server_info::{ServerInfo, ServerInfoArc},
+    },
 };
 pub mod durables;
 pub mod middleware;
@@ -30,7 +47,14 @@ mod api;
 mod cdn;
 
 pub const DISCORD_API_BASE_URL: &str = "https://discord.com/api/v10";
+pub const DISCORD_CDN_BASE_URL: &str = "https://cdn.discordapp.com";
 pub const DASHBOARD_URL: &str = "http://localhost:5173";
+/// Sent with every request to Discord, which asks API clients to identify themselves.
+pub const USER_AGENT: &str = concat!(
+    "BeabadoobeeFanclub (https://github.com/Beabadoobee-Fanclub/backend-api, v",
+    env!("CARGO_PKG_VERSION"),
+    ")"
+);
 
 #[event(start)]
 fn start() {
@@ -41,59 +65,322 @@ fn start() {
         .with_writer(MakeConsoleWriter);
 
     let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
+
+    // `start` has no env, the level from `LOG_LEVEL` is applied on the first request
+    let (level_layer, level_handle) = reload::Layer::new(DEFAULT_LOG_LEVEL);
+    let _ = LOG_LEVEL.set(level_handle);
+
     tracing_subscriber::registry()
+        .with(level_layer)
         .with(fmt_layer)
         .with(perf_layer)
         .init();
 }
 
-fn cors_layer(webpage: &str) -> CorsLayer {
-    let webpage_header = HeaderValue::from_str(webpage).expect("Invalid URL for CORS");
-    CorsLayer::new()
-        .allow_origin(webpage_header)
-        .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_credentials(AllowCredentials::yes())
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::INFO;
+
+static LOG_LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Applies `LOG_LEVEL` (`error`, `warn`, `info`, `debug`, `trace` or `off`) to the subscriber
+/// set up in `start`. Unknown values fall back to `info`.
+fn apply_log_level(env: &Env) {
+    let Some(handle) = LOG_LEVEL.get() else {
+        return;
+    };
+    let level = env
+        .var("LOG_LEVEL")
+        .ok()
+        .and_then(|v| v.to_string().parse::<LevelFilter>().ok())
+        .unwrap_or(DEFAULT_LOG_LEVEL);
+    if handle.clone_current() != Some(level) {
+        let _ = handle.reload(level);
+    }
+}
+
+/// Reflects the request's `Origin` only when it's one of the allowed origins. Entries that
+/// aren't valid header values are skipped rather than taking the worker down.
+///
+/// The auth cookies need credentialed requests, and browsers drop the response of those when
+/// it says `Access-Control-Allow-Origin: *` (tower-http panics on it first). A wildcard is
+/// refused here so the deploy fails loudly instead of cross-origin logins breaking silently.
+fn cors_layer(server_info: &ServerInfo) -> Result<CorsLayer> {
+    if server_info
+        .allowed_origins()
+        .iter()
+        .any(|o| o.trim() == "*")
+    {
+        return Err(Error::RustError(
+            "A wildcard CORS origin can't be combined with credentials".into(),
+        ));
+    }
+    let origins = server_info
+        .allowed_origins()
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("Skipping invalid CORS origin {:?}", origin);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        // Anything beyond the CORS safelisted headers has to be listed, JSON bodies and bearer
+        // clients included
+        .allow_headers([
+            CONTENT_TYPE,
+            AUTHORIZATION,
+            HeaderName::from_static(middleware::idempotency::IDEMPOTENCY_KEY_HEADER),
+        ])
+        .expose_headers([HeaderName::from_static(
+            middleware::idempotency::IDEMPOTENT_REPLAYED_HEADER,
+        )])
+        .allow_credentials(AllowCredentials::yes()))
 }
 
 #[event(fetch)]
-async fn fetch(req: HttpRequest, env: Env, ctx: Context) -> Result<Response<Body>> {
+async fn fetch(req: HttpRequest, env: Env, _ctx: Context) -> Result<Response<Body>> {
     console_error_panic_hook::set_once();
+    apply_log_level(&env);
 
-    let Ok(hyperdrive) = env.hyperdrive("DATABASE") else {
-        console_error!("Failed to get Hyperdrive instance");
-        return Ok(Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("Internal Server Error"))
-            .unwrap());
+    let config = match ServerConfig::from_env(&env) {
+        Ok(config) => config,
+        Err(e) => {
+            // Only names, never values, so it's safe to hand back to whoever is deploying
+            console_error!("{}", e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap());
+        }
     };
 
-    let server_info = ServerInfo::new(&env)?;
+    let server_info = ServerInfo::new(&env, &config)?;
+    if let Some(threshold) = env
+        .var("SLOW_UPSTREAM_MS")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+    {
+        services::upstream::set_slow_threshold(threshold);
+    }
 
     let app_state = Arc::new(AppState {
         // Initialize your application state here
-        database: Database::new(hyperdrive),
+        database: Database::new(config.hyperdrive),
+        discord: config.discord,
     });
 
     let mut app = Router::new()
         .nest("/api", api::router())
         .nest("/cdn", cdn::router())
         .route("/", get(root))
+        // Outside `/api` so uptime checks need neither credentials nor HTTPS
+        .route("/health", get(health))
         .fallback(fallback)
+        // After every route, it only reaches the method routers registered before it
+        .method_not_allowed_fallback(method_not_allowed)
         .layer(axum::middleware::from_fn(
             middleware::requested_user::middleware,
         ))
+        .layer(axum::middleware::from_fn(
+            middleware::user_cache::middleware,
+        ))
+        .layer(axum::middleware::from_fn(
+            middleware::request_id::middleware,
+        ))
         .layer(Extension(app_state))
         .layer(Extension(env))
         .layer(Extension(server_info.clone()))
-        .layer(cors_layer(&server_info.webpage()));
+        .layer(cors_layer(&server_info)?);
 
     Ok(app.call(req).await?)
 }
 
-async fn fallback() -> Response<Body> {
-    Response::new(Body::from("Not Found"))
+async fn fallback() -> ApiError {
+    ApiError::not_found("No route matches this path")
+}
+
+/// The path exists but not for this method. Routing adds the `Allow` header listing the
+/// methods it does take.
+async fn method_not_allowed() -> ApiError {
+    ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "This path doesn't support the request method",
+    )
+}
+
+/// How long `/health` waits on the database before calling it down.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Uptime probe, `200 {"db":"ok","db_latency_ms":..}` when a `SELECT 1` goes through and
+/// `503 {"db":"error"}` when it fails or doesn't answer within [`HEALTH_TIMEOUT`].
+#[worker::send]
+pub async fn health(Extension(app_state): Extension<AppStateArc>) -> Response<Body> {
+    let check = app_state.database.healthcheck();
+
+    let latency = match select(Box::pin(check), Delay::from(HEALTH_TIMEOUT)).await {
+        Either::Left((Ok(latency), _)) => Some(latency),
+        Either::Left((Err(e), _)) => {
+            error!("Health check failed: {}", e);
+            None
+        }
+        Either::Right(_) => {
+            error!(
+                timeout_ms = HEALTH_TIMEOUT.as_millis() as u64,
+                "Health check timed out"
+            );
+            None
+        }
+    };
+
+    match latency {
+        Some(latency) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "db": "ok",
+                "db_latency_ms": latency.as_millis() as u64,
+            })),
+        )
+            .into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "db": "error" })),
+        )
+            .into_response(),
+    }
 }
 
-pub async fn root() -> &'static str {
-    "Hello Axum!"
+pub async fn root(Extension(server_info): Extension<ServerInfoArc>) -> Response<Body> {
+    if server_info.is_development() {
+        return Json(serde_json::json!({
+            "status": "ok",
+            "environment": "development",
+        }))
+        .into_response();
+    }
+
+    // Outside of development there is nothing to see here, send people to the dashboard
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        [(LOCATION, server_info.webpage().to_string())],
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+    use crate::state::server_info::Environment;
+
+    /// The fallbacks as `fetch` wires them, around a route to miss.
+    fn router() -> Router {
+        Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .fallback(fallback)
+            .method_not_allowed_fallback(method_not_allowed)
+    }
+
+    async fn send(method: Method, path: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = router().call(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_a_json_404() {
+        let (status, body) = send(Method::GET, "/does-not-exist").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn wrong_method_is_a_json_405() {
+        let (status, body) = send(Method::DELETE, "/health").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn root_sends_visitors_to_the_dashboard() {
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[]);
+        let response = root(Extension(Arc::new(server_info))).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap(),
+            "https://fanclub.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn root_reports_status_in_development() {
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[])
+            .with_environment(Environment::Development);
+        let response = root(Extension(Arc::new(server_info))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["environment"], "development");
+    }
+
+    #[test]
+    fn cors_refuses_a_wildcard_origin() {
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &["*"]);
+        assert!(cors_layer(&server_info).is_err());
+    }
+
+    #[tokio::test]
+    async fn cors_allows_credentials_for_listed_origins_only() {
+        let server_info = ServerInfo::for_tests(
+            "https://fanclub.example.com",
+            &["https://preview.example.com"],
+        );
+        let mut router = Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .layer(cors_layer(&server_info).unwrap());
+
+        for (origin, allowed) in [
+            ("https://preview.example.com", true),
+            ("https://evil.example.com", false),
+        ] {
+            let request = Request::builder()
+                .uri("/health")
+                .header("origin", origin)
+                .body(Body::empty())
+                .unwrap();
+            let response = router.call(request).await.unwrap();
+            let headers = response.headers();
+            assert_eq!(
+                headers.get("access-control-allow-origin").is_some(),
+                allowed,
+                "{}",
+                origin
+            );
+            if allowed {
+                assert_eq!(headers["access-control-allow-origin"], origin);
+                assert_eq!(headers["access-control-allow-credentials"], "true");
+            }
+        }
+    }
 }
@@ -1,7 +1,273 @@
-use axum::{extract::Request, middleware::Next, response::Response};
+use std::{cell::RefCell, collections::HashMap};
 
-pub async fn middleware(request: Request, next: Next) -> Response {
-    let response = next.run(request).await;
+use axum::{
+    extract::Request,
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use tracing::{error, warn};
+use worker::{Date, Env};
 
-    response
+use crate::{
+    middleware::requested_user::get_client,
+    services::{
+        crypto::{constant_time_eq, guild_credential},
+        snowflake::Snowflake,
+    },
+    state::{
+        app_state::AppStateArc,
+        user::{Caller, RequestedUser},
+    },
+};
+
+/// How long a guild that isn't in the database is refused without asking it again.
+const UNKNOWN_GUILD_TTL_MS: u64 = 60_000;
+/// Bounds the cache, spraying random guild ids must not grow it without limit.
+const UNKNOWN_GUILD_CACHE_SIZE: usize = 1024;
+
+thread_local! {
+    /// Guilds recently found missing, with when (ms since epoch) that stops being trusted.
+    static UNKNOWN_GUILDS: RefCell<HashMap<Snowflake, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Guards the protected routes, anything without a verified caller is a 401. Dashboard users
+/// pass with their login session (already checked by `cookie_check`), bot callers must
+/// present the `BOT_API_TOKEN` secret (or `ADMIN_API_TOKEN` to act as an admin) and
+/// `client: DiscordGuild <id> <credential>` callers must name a guild we know and sign for it
+/// with `GUILD_API_SECRET` (see [`guild_credential`]), once checked the caller is recorded as a
+/// [`Caller`] extension for the handlers.
+#[worker::send]
+pub async fn middleware(
+    Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
+    Extension(requested_user): Extension<RequestedUser>,
+    headers: HeaderMap,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if let Some(claim) = claimed_guild(&headers)? {
+        let Ok(secret) = env.secret("GUILD_API_SECRET").map(|s| s.to_string()) else {
+            // Fail closed, without the secret no claim can be checked
+            error!("GUILD_API_SECRET is not set, refusing guild requests");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        if !claim.is_signed_with(secret.as_bytes()) {
+            warn!("Rejecting guild request with an invalid credential");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let guild_id = claim.guild_id;
+        if is_known_unknown(guild_id) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        match app_state.database.guild_exists(guild_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Rejecting request for unknown guild {}", guild_id);
+                remember_unknown(guild_id);
+                return Err(StatusCode::FORBIDDEN);
+            }
+            Err(e) => {
+                error!("Failed to look up guild {}: {}", guild_id, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        req.extensions_mut().insert(Caller::Guild(guild_id));
+        return Ok(next.run(req).await);
+    }
+
+    if let Some(caller) = session_caller(&requested_user) {
+        req.extensions_mut().insert(caller);
+        return Ok(next.run(req).await);
+    }
+
+    let RequestedUser::Bot(bot) = &requested_user else {
+        // Anonymous, or a bearer token that was never resolved to a user
+        warn!("Rejecting protected request without a verified caller");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    // Optional, without it there's simply no admin
+    if let Ok(admin) = env.secret("ADMIN_API_TOKEN").map(|s| s.to_string()) {
+        if !admin.is_empty() && constant_time_eq(bot.token().as_bytes(), admin.as_bytes()) {
+            req.extensions_mut().insert(Caller::Admin);
+            return Ok(next.run(req).await);
+        }
+    }
+    let Ok(expected) = env.secret("BOT_API_TOKEN").map(|s| s.to_string()) else {
+        // Fail closed, an unset secret must not let every self-proclaimed bot through
+        error!("BOT_API_TOKEN is not set, refusing bot requests");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    if !constant_time_eq(bot.token().as_bytes(), expected.as_bytes()) {
+        warn!("Rejecting bot request with an invalid token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    req.extensions_mut().insert(Caller::Bot);
+
+    Ok(next.run(req).await)
+}
+
+/// The dashboard user behind a session login. Bearer tokens aren't resolved to a user, they
+/// don't get a caller.
+fn session_caller(requested_user: &RequestedUser) -> Option<Caller> {
+    match requested_user {
+        RequestedUser::UserWithToken(user) => user.user_id().map(Caller::User),
+        _ => None,
+    }
+}
+
+/// A guild a request claims to act for, not trusted until [`GuildClaim::is_signed_with`].
+#[derive(Debug, PartialEq, Eq)]
+struct GuildClaim {
+    guild_id: Snowflake,
+    credential: String,
+}
+
+impl GuildClaim {
+    fn is_signed_with(&self, secret: &[u8]) -> bool {
+        let expected = guild_credential(secret, self.guild_id);
+        constant_time_eq(self.credential.as_bytes(), expected.as_bytes())
+    }
+}
+
+/// The guild named by a `client: DiscordGuild <id> <credential>` header, if that's what was
+/// sent. A claim without a credential proves nothing and is a 401.
+fn claimed_guild(headers: &HeaderMap) -> Result<Option<GuildClaim>, StatusCode> {
+    let Some(client) = get_client(headers) else {
+        return Ok(None);
+    };
+    match client.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["DiscordGuild", guild_id, credential] => {
+            let guild_id = guild_id
+                .parse::<Snowflake>()
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            Ok(Some(GuildClaim {
+                guild_id,
+                credential: credential.to_string(),
+            }))
+        }
+        ["DiscordGuild", ..] => Err(StatusCode::UNAUTHORIZED),
+        _ => Ok(None),
+    }
+}
+
+fn is_known_unknown(guild_id: Snowflake) -> bool {
+    is_known_unknown_at(guild_id, Date::now().as_millis())
+}
+
+/// Refuses `guild_id` without asking the database for a while, e.g. right after it was
+/// removed.
+pub fn remember_unknown(guild_id: Snowflake) {
+    remember_unknown_at(guild_id, Date::now().as_millis());
+}
+
+fn is_known_unknown_at(guild_id: Snowflake, now: u64) -> bool {
+    UNKNOWN_GUILDS.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        match cache.get(&guild_id) {
+            Some(&expires) if expires > now => true,
+            Some(_) => {
+                cache.remove(&guild_id);
+                false
+            }
+            None => false,
+        }
+    })
+}
+
+fn remember_unknown_at(guild_id: Snowflake, now: u64) {
+    UNKNOWN_GUILDS.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.len() >= UNKNOWN_GUILD_CACHE_SIZE {
+            cache.retain(|_, &mut expires| expires > now);
+            if cache.len() >= UNKNOWN_GUILD_CACHE_SIZE {
+                cache.clear();
+            }
+        }
+        cache.insert(guild_id, now + UNKNOWN_GUILD_TTL_MS);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::user::{AuthMethod, Bot, User};
+
+    const GUILD_ID: u64 = 81384788765712384;
+    const SECRET: &[u8] = b"guild-api-secret";
+
+    fn claim(client: &str) -> Result<Option<GuildClaim>, StatusCode> {
+        let mut headers = HeaderMap::new();
+        headers.insert("client", client.parse().unwrap());
+        claimed_guild(&headers)
+    }
+
+    #[test]
+    fn guild_claims_need_a_matching_credential() {
+        let guild = Snowflake::new(GUILD_ID);
+        let credential = guild_credential(SECRET, guild);
+        let claimed = claim(&format!("DiscordGuild {} {}", GUILD_ID, credential))
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.guild_id, guild);
+        assert!(claimed.is_signed_with(SECRET));
+        assert!(!claimed.is_signed_with(b"another-secret"));
+
+        // A credential is only good for the guild it was issued for
+        let other = claim(&format!("DiscordGuild {} {}", GUILD_ID + 1, credential))
+            .unwrap()
+            .unwrap();
+        assert!(!other.is_signed_with(SECRET));
+    }
+
+    #[test]
+    fn unsigned_guild_claims_are_refused() {
+        assert_eq!(
+            claim(&format!("DiscordGuild {}", GUILD_ID)),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            claim("DiscordGuild not-a-snowflake credential"),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(claim("DiscordBot token"), Ok(None));
+    }
+
+    #[test]
+    fn session_users_are_callers_of_their_own() {
+        let user_id = Snowflake::new(GUILD_ID);
+        let session = User::new("token".to_string()).with_user_id(user_id);
+        assert_eq!(
+            session_caller(&RequestedUser::UserWithToken(session)),
+            Some(Caller::User(user_id))
+        );
+
+        let bearer = User::with_method("token".to_string(), AuthMethod::Bearer);
+        assert_eq!(session_caller(&RequestedUser::UserWithToken(bearer)), None);
+        assert_eq!(session_caller(&RequestedUser::User), None);
+        assert_eq!(
+            session_caller(&RequestedUser::Bot(Bot::new("token".to_string()))),
+            None
+        );
+    }
+
+    #[test]
+    fn removed_guild_is_refused_until_the_ttl() {
+        let guild = Snowflake::new(GUILD_ID);
+        assert!(!is_known_unknown_at(guild, 0));
+
+        remember_unknown_at(guild, 0);
+        assert!(is_known_unknown_at(guild, UNKNOWN_GUILD_TTL_MS - 1));
+        // Past the TTL the database is asked again
+        assert!(!is_known_unknown_at(guild, UNKNOWN_GUILD_TTL_MS));
+    }
+
+    #[test]
+    fn cache_stays_bounded() {
+        for id in 0..=UNKNOWN_GUILD_CACHE_SIZE as u64 {
+            remember_unknown_at(Snowflake::new(GUILD_ID + 1 + id), 0);
+        }
+        UNKNOWN_GUILDS.with(|cache| assert!(cache.borrow().len() <= UNKNOWN_GUILD_CACHE_SIZE));
+    }
 }
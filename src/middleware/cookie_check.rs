@@ -1,24 +1,57 @@
-use axum::{extract::Request, middleware::Next, response::Response, Extension};
-use cookie::Cookie;
+use std::time::Duration;
+
+use axum::{
+    extract::Request,
+    http::{header::AUTHORIZATION, HeaderMap, Method},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use cookie::Key;
 use reqwest::StatusCode;
-use tracing::error;
-use worker::Env;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use worker::{Date, Delay, Env};
 
 use crate::{
+    durables::lock::{self, LockGuard},
     services::{
-        auth::{add_success_cookies, DiscordAPIClient, DiscordCookie},
-        cookie::CookieJar,
-        get_discord_env,
+        auth::{
+            remove_error_cookies, DiscordAPIClient, DiscordCookie, TokenError,
+            REFRESH_TOKEN_PURPOSE,
+        },
+        cookie::{cookie_key, CookieJar},
+        crypto::{seal, unseal},
+        rate_limit::{check_rate_limit, RateLimit, RateLimitOutcome},
+        session::{Session, SESSION_TOKEN_PURPOSE},
+        snowflake::Snowflake,
     },
     state::{
+        app_state::AppStateArc,
         server_info::ServerInfoArc,
-        user::{RequestedUser, User},
+        user::{AuthMethod, RequestedUser, User},
     },
 };
 
+/// Access tokens this close to expiry are refreshed up front rather than sent to Discord.
+const REFRESH_LEEWAY_MS: u64 = 60_000;
+
+/// At most one token refresh per refresh token every ten seconds.
+const REFRESH_RATE_LIMIT: RateLimit = RateLimit {
+    limit: 1,
+    window_secs: 10,
+};
+/// How often a throttled request looks for the token the concurrent refresh stores, and how
+/// long it waits in between.
+const REFRESHED_TOKEN_CHECKS: u32 = 3;
+const REFRESHED_TOKEN_POLL: Duration = Duration::from_millis(500);
+/// Upper bound on a refresh holding the lock, in case its isolate dies before releasing it.
+const REFRESH_LOCK_TTL_SECS: u64 = 30;
+
 #[worker::send]
 pub async fn middleware(
     Extension(env): Extension<Env>,
+    Extension(app_state): Extension<AppStateArc>,
     Extension(server_info): Extension<ServerInfoArc>,
     Extension(requested_user): Extension<RequestedUser>,
     jar: CookieJar,
@@ -30,49 +63,333 @@ pub async fn middleware(
         return Ok((None, next.run(req).await));
     }
 
-    match jar
-        .get(&DiscordCookie::AccessToken.to_string())
+    // Non-browser clients may send the Discord access token directly
+    if let Some(token) = bearer_token(req.headers()) {
+        if !server_info.allows_auth_method(AuthMethod::Bearer) {
+            warn!("Bearer authentication attempted but it is disabled");
+            return Err((None, StatusCode::UNAUTHORIZED));
+        }
+        let user = User::with_method(token, AuthMethod::Bearer);
+        req.extensions_mut()
+            .insert(RequestedUser::UserWithToken(user));
+        return Ok((None, next.run(req).await));
+    }
+
+    let cookies = server_info.cookie_config();
+    let Some(session_id) = jar
+        .get(&cookies.name(DiscordCookie::Session))
         .map(|c| c.value().to_string())
-    {
-        Some(token) => {
-            let user = User::new(token);
-            req.extensions_mut()
-                .insert(RequestedUser::UserWithToken(user));
-            Ok((None, next.run(req).await))
-        }
-        None => {
-            let Some(refresh_token) = jar
-                .get(&DiscordCookie::RefreshToken.to_string())
-                .map(|c| c.value().to_string())
-            else {
-                error!("No access token or refresh token found in cookies");
-                return Ok((None, next.run(req).await));
-            };
-            let Ok((client_id, client_secret)) = get_discord_env(&env) else {
-                error!("Failed to get Discord environment variables");
-                return Err((None, StatusCode::INTERNAL_SERVER_ERROR));
-            };
-            let redirect_uri = format!("{}/api/auth/redirect", server_info.api_host());
-
-            let discord_api =
-                DiscordAPIClient::new(client_id.clone(), client_secret.clone(), redirect_uri);
-
-            let token = discord_api
-                .refresh_access_token(&refresh_token)
-                .await
-                .map_err(|e| {
-                    error!("Failed to refresh access token: {}", e);
-                    (None, StatusCode::UNAUTHORIZED)
-                })?;
-            let cookies = DiscordAPIClient::set_cookies(token.clone());
-
-            let user = User::new(token.access_token().to_string());
-            req.extensions_mut()
-                .insert(RequestedUser::UserWithToken(user));
-            Ok((
-                Some(add_success_cookies(&jar, cookies)),
+        .filter(|id| !id.is_empty())
+    else {
+        // Token cookies from before server side sessions are useless now, let the browser drop
+        // them
+        let legacy = jar
+            .get_chunked(&cookies.name(DiscordCookie::AccessToken))
+            .is_some()
+            || jar
+                .get(&cookies.name(DiscordCookie::RefreshToken))
+                .is_some();
+        let cleared = legacy.then(|| remove_error_cookies(&jar, cookies));
+        return Ok((cleared, next.run(req).await));
+    };
+    if !server_info.allows_auth_method(AuthMethod::Cookie) {
+        warn!("Cookie authentication attempted but it is disabled");
+        return Err((None, StatusCode::UNAUTHORIZED));
+    }
+    let key = match cookie_key(&env) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Can't open sessions: {}", e);
+            return Err((None, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    let session = match app_state.database.get_session(&session_id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            warn!("Session cookie for an unknown or expired session");
+            return Ok((
+                Some(remove_error_cookies(&jar, cookies)),
                 next.run(req).await,
-            ))
+            ));
+        }
+        Err(e) => {
+            error!("Failed to load session: {}", e);
+            return Err((None, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    if session.impersonated && !allowed_while_impersonating(req.method()) {
+        warn!(
+            user_id = %session.user_id,
+            method = %req.method(),
+            "Refusing a write while impersonating"
+        );
+        return Err((None, StatusCode::FORBIDDEN));
+    }
+
+    // A token that doesn't open (COOKIE_SECRET was rotated) is refreshed like an expired one
+    if let Some(token) = usable_token(&key, &session) {
+        req.extensions_mut().insert(RequestedUser::UserWithToken(
+            User::new(token).with_user_id(session.user_id),
+        ));
+        return Ok((None, next.run(req).await));
+    }
+
+    // Refreshing would rotate the user's own grant, an impersonation simply ends with the token
+    if session.impersonated {
+        end_session(&app_state, &session_id).await;
+        return Err((
+            Some(remove_error_cookies(&jar, cookies)),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    let Some(refresh_token) = recover_refresh_token(&app_state, &key, session.user_id).await else {
+        // Nothing left to renew the session with
+        end_session(&app_state, &session_id).await;
+        return Ok((
+            Some(remove_error_cookies(&jar, cookies)),
+            next.run(req).await,
+        ));
+    };
+    let redirect_uri = format!("{}/api/auth/redirect", server_info.api_host());
+
+    // Guard against refresh storms from buggy clients burning through Discord's limits
+    let limiter_key = refresh_limiter_key(&refresh_token);
+    let throttled = match check_rate_limit(&env, &limiter_key, REFRESH_RATE_LIMIT).await {
+        Ok(RateLimitOutcome::Allowed) => false,
+        Ok(RateLimitOutcome::Limited { retry_after }) => {
+            warn!("Token refresh throttled, retry after {}s", retry_after);
+            true
+        }
+        Err(e) => {
+            error!("Failed to check refresh rate limit: {}", e);
+            false
+        }
+    };
+    // Only one request refreshes a given token at a time, the rest wait for what it stores
+    let (busy, mut refresh_lock) = if throttled {
+        (true, None)
+    } else {
+        match lock::acquire(&env, &limiter_key, REFRESH_LOCK_TTL_SECS).await {
+            Ok(Some(guard)) => (false, Some(guard)),
+            Ok(None) => (true, None),
+            Err(e) => {
+                error!("Failed to take the refresh lock: {}", e);
+                (false, None)
+            }
+        }
+    };
+    if busy {
+        // Usually a parallel request of the same session refreshing right now, use its token
+        if let Some(token) = refreshed_token(&app_state, &key, &session_id).await {
+            req.extensions_mut().insert(RequestedUser::UserWithToken(
+                User::new(token).with_user_id(session.user_id),
+            ));
+            return Ok((None, next.run(req).await));
+        }
+        return Err((None, StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let discord_api = DiscordAPIClient::new(
+        &env,
+        app_state.discord.client_id.clone(),
+        app_state.discord.client_secret.clone(),
+        redirect_uri,
+    );
+
+    let refreshed = discord_api.refresh_access_token(&refresh_token).await;
+    if refreshed.is_err() {
+        release_refresh_lock(&env, refresh_lock.take()).await;
+    }
+    let token = match refreshed {
+        Ok(token) => token,
+        Err(TokenError::Rejected(status)) => {
+            // The grant is gone, so is every session built on it
+            error!(
+                status = status.as_u16(),
+                "Discord refused the token refresh"
+            );
+            if let Err(e) = app_state
+                .database
+                .delete_refresh_token(session.user_id)
+                .await
+            {
+                error!("Failed to delete stored refresh token: {}", e);
+            }
+            end_session(&app_state, &session_id).await;
+            return Err((
+                Some(remove_error_cookies(&jar, cookies)),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+        // The refresh token may still be good, keep it for the next attempt
+        Err(e @ TokenError::RateLimited { .. }) => {
+            warn!("Failed to refresh access token: {}", e);
+            return Err((None, StatusCode::TOO_MANY_REQUESTS));
+        }
+        Err(e @ TokenError::Timeout(_)) => {
+            error!("Failed to refresh access token: {}", e);
+            return Err((None, StatusCode::GATEWAY_TIMEOUT));
+        }
+        Err(e) => {
+            error!("Failed to refresh access token: {}", e);
+            return Err((None, StatusCode::BAD_GATEWAY));
+        }
+    };
+    // Discord may rotate the refresh token, keep the stored copy current
+    let sealed = seal(&key, REFRESH_TOKEN_PURPOSE, token.refresh_token());
+    if let Err(e) = app_state
+        .database
+        .store_refresh_token(session.user_id, &sealed)
+        .await
+    {
+        error!("Failed to store refreshed token: {}", e);
+    }
+    let sealed = seal(&key, SESSION_TOKEN_PURPOSE, token.access_token());
+    if let Err(e) = app_state
+        .database
+        .update_session_token(&session_id, &sealed, token.expiry())
+        .await
+    {
+        // Only costs another refresh on the next request
+        error!("Failed to update session token: {}", e);
+    }
+    release_refresh_lock(&env, refresh_lock).await;
+
+    let user = User::new(token.access_token().to_string()).with_user_id(session.user_id);
+    req.extensions_mut()
+        .insert(RequestedUser::UserWithToken(user));
+    Ok((None, next.run(req).await))
+}
+
+/// The session's access token, unless it's about to expire or doesn't open with `key`.
+fn usable_token(key: &Key, session: &Session) -> Option<String> {
+    let expires_soon = session.token_expires_at.timestamp_millis()
+        - (Date::now().as_millis() as i64)
+        < REFRESH_LEEWAY_MS as i64;
+    if expires_soon {
+        return None;
+    }
+    unseal(key, SESSION_TOKEN_PURPOSE, &session.discord_access_token)
+}
+
+/// Impersonation sessions only read, anything that could change state is refused.
+fn allowed_while_impersonating(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Rereads the session until the refresh that throttled this request has stored its token, for
+/// at most [`REFRESHED_TOKEN_CHECKS`] reads.
+async fn refreshed_token(app_state: &AppStateArc, key: &Key, session_id: &str) -> Option<String> {
+    for check in 0..REFRESHED_TOKEN_CHECKS {
+        if check > 0 {
+            Delay::from(REFRESHED_TOKEN_POLL).await;
+        }
+        match app_state.database.get_session(session_id).await {
+            Ok(Some(session)) => {
+                if let Some(token) = usable_token(key, &session) {
+                    return Some(token);
+                }
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Failed to reload session: {}", e);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Frees the refresh lock once the new token is stored, its TTL covers a release that fails.
+async fn release_refresh_lock(env: &Env, guard: Option<LockGuard>) {
+    let Some(guard) = guard else {
+        return;
+    };
+    if let Err(e) = guard.release(env).await {
+        error!("Failed to release the refresh lock: {}", e);
+    }
+}
+
+async fn end_session(app_state: &AppStateArc, session_id: &str) {
+    if let Err(e) = app_state.database.delete_session(session_id).await {
+        error!("Failed to delete session: {}", e);
+    }
+}
+
+/// The stored refresh token of `user_id`, if there is one and it still opens with `key`.
+async fn recover_refresh_token(
+    app_state: &AppStateArc,
+    key: &Key,
+    user_id: Snowflake,
+) -> Option<String> {
+    match app_state.database.stored_refresh_token(user_id).await {
+        Ok(Some(sealed)) => {
+            let token = unseal(key, REFRESH_TOKEN_PURPOSE, &sealed);
+            if token.is_none() {
+                warn!("Stored refresh token doesn't open, was COOKIE_SECRET rotated?");
+            }
+            token
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to load stored refresh token: {}", e);
+            None
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// One limiter per refresh token, hashed so the token itself never ends up in a durable object
+/// name.
+fn refresh_limiter_key(refresh_token: &str) -> String {
+    format!("refresh:{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impersonation_only_reads() {
+        assert!(allowed_while_impersonating(&Method::GET));
+        assert!(allowed_while_impersonating(&Method::HEAD));
+        assert!(allowed_while_impersonating(&Method::OPTIONS));
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            assert!(!allowed_while_impersonating(&method), "{} allowed", method);
+        }
+    }
+
+    #[test]
+    fn refresh_limiter_is_keyed_per_token() {
+        let key = refresh_limiter_key("refresh-token-a");
+        assert_eq!(key, refresh_limiter_key("refresh-token-a"));
+        assert_ne!(key, refresh_limiter_key("refresh-token-b"));
+        assert!(key.starts_with("refresh:"));
+        assert!(!key.contains("refresh-token-a"));
+    }
+
+    #[test]
+    fn bearer_tokens_come_from_the_authorization_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, "Bearer  access-token ".parse().unwrap());
+        assert_eq!(bearer_token(&headers).as_deref(), Some("access-token"));
+
+        for value in ["Bearer ", "Basic dXNlcjpwYXNz", "bearer access-token"] {
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+            assert_eq!(bearer_token(&headers), None, "{}", value);
         }
     }
 }
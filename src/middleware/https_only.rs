@@ -0,0 +1,130 @@
+use axum::{
+    extract::Request,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use tracing::warn;
+
+use crate::state::server_info::ServerInfoArc;
+
+/// Rejects plain HTTP requests since the auth cookies are `Secure` and would silently not be
+/// sent. GETs are redirected to their HTTPS equivalent, anything else gets a 400.
+///
+/// Skipped entirely in development so `wrangler dev` keeps working over HTTP.
+pub async fn middleware(
+    Extension(server_info): Extension<ServerInfoArc>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if server_info.is_development() || is_https(req.headers(), req.uri()) {
+        return next.run(req).await;
+    }
+
+    warn!(
+        "Rejecting insecure {} request to {}",
+        req.method(),
+        req.uri()
+    );
+    if req.method() != Method::GET {
+        return (StatusCode::BAD_REQUEST, "HTTPS is required").into_response();
+    }
+
+    match https_location(req.headers(), req.uri()) {
+        Some(location) => Redirect::permanent(&location).into_response(),
+        None => (StatusCode::BAD_REQUEST, "HTTPS is required").into_response(),
+    }
+}
+
+/// The HTTPS URL a plain HTTP request is redirected to, or `None` when the host is unknown.
+fn https_location(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    let host = uri.host().map(|h| h.to_string()).or_else(|| {
+        headers
+            .get("host")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string())
+    })?;
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Some(format!("https://{}{}", host, path))
+}
+
+fn is_https(headers: &HeaderMap, uri: &Uri) -> bool {
+    if let Some(proto) = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+    {
+        return proto.eq_ignore_ascii_case("https");
+    }
+
+    // Cloudflare sends `CF-Visitor: {"scheme":"https"}`
+    if let Some(visitor) = headers.get("cf-visitor").and_then(|v| v.to_str().ok()) {
+        return !visitor.contains("\"http\"");
+    }
+
+    uri.scheme_str() != Some("http")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_proto_decides_first() {
+        let uri: Uri = "https://api.example.com/".parse().unwrap();
+        assert!(!is_https(&headers(&[("x-forwarded-proto", "http")]), &uri));
+
+        let uri: Uri = "/".parse().unwrap();
+        assert!(is_https(&headers(&[("x-forwarded-proto", "HTTPS")]), &uri));
+    }
+
+    #[test]
+    fn cloudflare_visitor_is_read() {
+        let uri: Uri = "/".parse().unwrap();
+        assert!(!is_https(
+            &headers(&[("cf-visitor", r#"{"scheme":"http"}"#)]),
+            &uri
+        ));
+        assert!(is_https(
+            &headers(&[("cf-visitor", r#"{"scheme":"https"}"#)]),
+            &uri
+        ));
+    }
+
+    #[test]
+    fn plain_http_uri_is_insecure() {
+        let none = HeaderMap::new();
+        assert!(!is_https(
+            &none,
+            &"http://api.example.com/".parse().unwrap()
+        ));
+        assert!(is_https(
+            &none,
+            &"https://api.example.com/".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn redirect_keeps_path_and_query() {
+        let uri: Uri = "http://api.example.com/guilds?page=2".parse().unwrap();
+        assert_eq!(
+            https_location(&HeaderMap::new(), &uri).as_deref(),
+            Some("https://api.example.com/guilds?page=2")
+        );
+
+        let uri: Uri = "/me".parse().unwrap();
+        assert_eq!(
+            https_location(&headers(&[("host", "api.example.com")]), &uri).as_deref(),
+            Some("https://api.example.com/me")
+        );
+        assert_eq!(https_location(&HeaderMap::new(), &uri), None);
+    }
+}
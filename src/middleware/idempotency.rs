@@ -0,0 +1,182 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header::SET_COOKIE, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use worker::Env;
+
+use crate::{
+    api::error::ApiError,
+    services::idempotency::{BeginResponse, IdempotencyKey, StoredResponse},
+    state::user::RequestedUser,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on a response that was replayed rather than produced by running the handler again.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const DEFAULT_TTL_SECS: u64 = 86_400;
+const MAX_KEY_LEN: usize = 255;
+/// Requests and responses are buffered to hash and store them, anything bigger isn't a
+/// dashboard form submit.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Makes `POST`, `PUT`, `PATCH` and `DELETE` requests carrying an `Idempotency-Key` safe to
+/// send twice. The first request with a key runs and its response is stored on the
+/// `IDEMPOTENCY` durable object for `IDEMPOTENCY_TTL_SECS` (a day by default), repeats within
+/// that window get the stored response back with `Idempotent-Replayed: true`.
+///
+/// - Keys are scoped to the caller, the user for session logins (so a token refresh doesn't
+///   change the scope), the token hash for bearer users and the bot, so callers can't see each
+///   other's responses. Anonymous requests pass.
+/// - Reusing a key for a different method, path or body is a 409, as is a repeat that arrives
+///   while the first request is still running.
+/// - 5xx responses aren't stored, the key is released so the client can retry.
+/// - `GET`, `HEAD` and `OPTIONS` are idempotent already and always pass.
+#[worker::send]
+pub async fn middleware(
+    Extension(env): Extension<Env>,
+    requested_user: Option<Extension<RequestedUser>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+        return next.run(req).await;
+    }
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(req).await;
+    };
+    let Some(key) = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+        .map(str::to_string)
+    else {
+        return ApiError::bad_request("Idempotency-Key must be 1 to 255 visible ASCII characters")
+            .into_response();
+    };
+    // Never a credential itself, the scope ends up in durable object names
+    let scope = match requested_user.as_ref().map(|Extension(user)| user) {
+        Some(RequestedUser::UserWithToken(user)) => match user.user_id() {
+            Some(user_id) => format!("user:{}", user_id),
+            // Bearer callers aren't resolved to a user, their token is all there is
+            None => format!("token:{:x}", Sha256::digest(user.access_token().as_bytes())),
+        },
+        Some(RequestedUser::Bot(bot)) => {
+            format!("bot:{:x}", Sha256::digest(bot.token().as_bytes()))
+        }
+        _ => return next.run(req).await,
+    };
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_BYTES).await else {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Request body is too large",
+        )
+        .into_response();
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(b"\n");
+    hasher.update(parts.uri.to_string());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    let fingerprint = format!("{:x}", hasher.finalize());
+    let req = Request::from_parts(parts, Body::from(body));
+
+    let idempotency_key = IdempotencyKey::new(&env, &scope, &key, fingerprint);
+    match idempotency_key.begin().await {
+        Ok(BeginResponse::Started) => {}
+        Ok(BeginResponse::Completed { response }) => return replay(response),
+        Ok(BeginResponse::InFlight) => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "idempotency_in_flight",
+                "A request with this Idempotency-Key is still being processed",
+            )
+            .into_response();
+        }
+        Ok(BeginResponse::Mismatch) => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "idempotency_key_reused",
+                "This Idempotency-Key was already used for a different request",
+            )
+            .into_response();
+        }
+        Err(e) => {
+            // Same as the rate limiter, the store being down shouldn't take writes with it
+            error!("Failed to check idempotency key: {}", e);
+            return next.run(req).await;
+        }
+    }
+
+    let response = next.run(req).await;
+    if response.status().is_server_error() {
+        if let Err(e) = idempotency_key.abort().await {
+            warn!("Failed to release idempotency key: {}", e);
+        }
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to buffer response for idempotency: {}", e);
+            if let Err(e) = idempotency_key.abort().await {
+                warn!("Failed to release idempotency key: {}", e);
+            }
+            return ApiError::internal("Failed to read response").into_response();
+        }
+    };
+    let stored = StoredResponse {
+        status: parts.status.as_u16(),
+        // Cookies belong to the original exchange, a replay must not set them again
+        headers: parts
+            .headers
+            .iter()
+            .filter(|(name, _)| **name != SET_COOKIE)
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: STANDARD.encode(&body),
+    };
+    let ttl_secs = env
+        .var("IDEMPOTENCY_TTL_SECS")
+        .ok()
+        .and_then(|v| v.to_string().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+    if let Err(e) = idempotency_key.complete(&stored, ttl_secs).await {
+        warn!("Failed to store idempotent response: {}", e);
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let Ok(body) = STANDARD.decode(&stored.body) else {
+        error!("Stored idempotent response has an invalid body");
+        return ApiError::internal("Failed to replay response").into_response();
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let headers = response.headers_mut();
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
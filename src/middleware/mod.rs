@@ -1,3 +1,9 @@
 pub mod api_protect;
 pub mod cookie_check;
+pub mod https_only;
+pub mod idempotency;
+pub mod rate_limit;
+pub mod request_id;
 pub mod requested_user;
+pub mod response_time;
+pub mod user_cache;
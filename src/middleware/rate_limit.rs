@@ -0,0 +1,86 @@
+use axum::{
+    extract::Request,
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use tracing::{error, warn};
+use worker::Env;
+
+use crate::{
+    services::rate_limit::{check_rate_limit, RateLimit, RateLimitOutcome},
+    state::user::Caller,
+};
+
+const DEFAULT_BOT_PER_MINUTE: u32 = 600;
+const DEFAULT_GUILD_PER_MINUTE: u32 = 120;
+const DEFAULT_USER_PER_MINUTE: u32 = 120;
+
+/// Per caller budget for the protected routes, counted on the `RATELIMITER` durable object so
+/// it holds across isolates. Has to run after `api_protect`, which identifies the [`Caller`].
+///
+/// Budgets are requests per minute from `BOT_RATE_LIMIT_PER_MINUTE`,
+/// `GUILD_RATE_LIMIT_PER_MINUTE` and `USER_RATE_LIMIT_PER_MINUTE`. `api_protect` already refused requests without a verified
+/// caller, one that still gets here unidentified passes.
+#[worker::send]
+pub async fn middleware(
+    Extension(env): Extension<Env>,
+    caller: Option<Extension<Caller>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(Extension(caller)) = caller else {
+        return next.run(req).await;
+    };
+
+    let (key, var, default) = match &caller {
+        Caller::Bot => (
+            "caller:bot".to_string(),
+            "BOT_RATE_LIMIT_PER_MINUTE",
+            DEFAULT_BOT_PER_MINUTE,
+        ),
+        // Admins are people debugging, the bot's budget is plenty
+        Caller::Admin => (
+            "caller:admin".to_string(),
+            "BOT_RATE_LIMIT_PER_MINUTE",
+            DEFAULT_BOT_PER_MINUTE,
+        ),
+        Caller::Guild(id) => (
+            format!("caller:guild:{}", id),
+            "GUILD_RATE_LIMIT_PER_MINUTE",
+            DEFAULT_GUILD_PER_MINUTE,
+        ),
+        Caller::User(id) => (
+            format!("caller:user:{}", id),
+            "USER_RATE_LIMIT_PER_MINUTE",
+            DEFAULT_USER_PER_MINUTE,
+        ),
+    };
+    let limit = env
+        .var(var)
+        .ok()
+        .and_then(|v| v.to_string().parse::<u32>().ok())
+        .unwrap_or(default);
+
+    let rate_limit = RateLimit {
+        limit,
+        window_secs: 60,
+    };
+    match check_rate_limit(&env, &key, rate_limit).await {
+        Ok(RateLimitOutcome::Allowed) => next.run(req).await,
+        Ok(RateLimitOutcome::Limited { retry_after }) => {
+            warn!("Rate limited {:?}, retry after {}s", caller, retry_after);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response()
+        }
+        Err(e) => {
+            // The limiter being down shouldn't take the API with it
+            error!("Failed to check rate limit for {:?}: {}", caller, e);
+            next.run(req).await
+        }
+    }
+}
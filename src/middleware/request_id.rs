@@ -0,0 +1,77 @@
+use std::{convert::Infallible, fmt};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client supplied id we keep, anything longer is replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlation id of the current request, for tying log lines of one request together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The id the middleware stored for this request, `unknown` outside of it.
+    pub fn from_parts(parts: &Parts) -> RequestId {
+        parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId("unknown".into()))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<S> FromRequestParts<S> for RequestId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RequestId::from_parts(parts))
+    }
+}
+
+/// Keeps the caller's `X-Request-Id` when it looks sane, otherwise generates a UUID. The id is
+/// stored as a [`RequestId`] extension and echoed on the response.
+pub async fn middleware(mut req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Ids end up in logs, so only short runs of unambiguous characters are accepted.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
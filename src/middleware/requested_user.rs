@@ -5,8 +5,8 @@ use tracing::debug;
 pub async fn middleware(headers: HeaderMap, mut req: Request, next: Next) -> Response {
     if let Some(client) = get_client(&headers) {
         if let ["DiscordBot", token] = client.split_whitespace().collect::<Vec<&str>>().as_slice() {
-            // TODO: Validate the bot token here
-            debug!("Discord Bot token: {}", token);
+            // Only parsed here, `api_protect` checks it before any protected route runs
+            debug!("Request claims to come from the Discord bot");
             let val = Bot::new(token.to_string());
             req.extensions_mut().insert(RequestedUser::Bot(val));
             return next.run(req).await;
@@ -16,7 +16,7 @@ pub async fn middleware(headers: HeaderMap, mut req: Request, next: Next) -> Res
     next.run(req).await
 }
 
-fn get_client(headers: &HeaderMap) -> Option<String> {
+pub(crate) fn get_client(headers: &HeaderMap) -> Option<String> {
     headers
         .get("client")
         .and_then(|value| value.to_str().ok())
@@ -0,0 +1,49 @@
+use axum::{
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use worker::Date;
+
+pub const RESPONSE_TIME_HEADER: &str = "x-response-time-ms";
+
+/// Sets `X-Response-Time-Ms` to the time spent in the handler. WebSocket upgrades are left
+/// alone, the durable object owns those responses.
+pub async fn middleware(req: Request, next: Next) -> Response {
+    let started = Date::now().as_millis();
+    let mut response = next.run(req).await;
+    let elapsed = Date::now().as_millis().saturating_sub(started);
+    stamp(&mut response, elapsed);
+    response
+}
+
+fn stamp(response: &mut Response, elapsed_ms: u64) {
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&elapsed_ms.to_string()) {
+        response.headers_mut().insert(RESPONSE_TIME_HEADER, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    #[test]
+    fn responses_carry_the_elapsed_time() {
+        let mut response = (StatusCode::NOT_FOUND, "missing").into_response();
+        stamp(&mut response, 42);
+        assert_eq!(response.headers().get(RESPONSE_TIME_HEADER).unwrap(), "42");
+    }
+
+    #[test]
+    fn websocket_upgrades_are_left_alone() {
+        let mut response = StatusCode::SWITCHING_PROTOCOLS.into_response();
+        stamp(&mut response, 42);
+        assert!(response.headers().get(RESPONSE_TIME_HEADER).is_none());
+    }
+}
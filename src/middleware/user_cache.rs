@@ -0,0 +1,8 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+
+use crate::services::user_cache::UserLookupCache;
+
+pub async fn middleware(mut req: Request, next: Next) -> Response {
+    req.extensions_mut().insert(UserLookupCache::default());
+    next.run(req).await
+}
@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+use sea_query::{Alias, Cond, Expr, Iden, Order, PostgresQueryBuilder, Query, Values};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio_postgres::{GenericClient, Row};
+use tracing::info;
+use worker::{Error, Result};
+
+use crate::services::{
+    repo::{column, FromRow},
+    snowflake::Snowflake,
+    upstream::timed,
+};
+
+/// Actor of everything done with `ADMIN_API_TOKEN`, admins share the one token.
+pub const ADMIN_ACTOR: &str = "admin";
+
+/// Replaces [`user_actor`] on the entries of an erased user, the entries stay for the trail
+/// without saying who took them.
+pub const ERASED_ACTOR: &str = "user:erased";
+
+/// Actor of an action a logged in user took themselves.
+pub fn user_actor(user_id: Snowflake) -> String {
+    format!("user:{}", user_id)
+}
+
+/// The audit_log table and its columns, see `migrations/0006_create_audit_log.sql`.
+#[derive(Debug, Clone, Copy)]
+pub enum AuditLog {
+    Table,
+    Id,
+    Actor,
+    Action,
+    SubjectUserId,
+    Details,
+    CreatedAt,
+}
+
+impl Iden for AuditLog {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        let name = match self {
+            Self::Table => "audit_log",
+            Self::Id => "id",
+            Self::Actor => "actor",
+            Self::Action => "action",
+            Self::SubjectUserId => "subject_user_id",
+            Self::Details => "details",
+            Self::CreatedAt => "created_at",
+        };
+        s.write_str(name).unwrap();
+    }
+}
+
+/// A recorded action.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub action: String,
+    pub subject_user_id: Option<Snowflake>,
+    pub details: JsonValue,
+    pub created_at: DateTime<Utc>,
+}
+
+impl FromRow for AuditEntry {
+    /// Expects `id, actor, action, subject_user_id, details::text, created_at` in that order.
+    fn from_row(row: &Row) -> Result<Self> {
+        let subject_user_id: Option<i64> = column(row, 3)?;
+        let details: String = column(row, 4)?;
+        Ok(Self {
+            id: column(row, 0)?,
+            actor: column(row, 1)?,
+            action: column(row, 2)?,
+            subject_user_id: subject_user_id.map(|id| Snowflake::new(id as u64)),
+            details: serde_json::from_str(&details).map_err(|e| {
+                Error::RustError(format!("Stored audit details are invalid: {}", e))
+            })?,
+            created_at: column(row, 5)?,
+        })
+    }
+}
+
+impl AuditEntry {
+    /// Every entry the user took (actor [`user_actor`]) or that was taken on them, oldest first.
+    pub fn for_user(user_id: Snowflake) -> (String, Values) {
+        Query::select()
+            .columns([
+                AuditLog::Id,
+                AuditLog::Actor,
+                AuditLog::Action,
+                AuditLog::SubjectUserId,
+            ])
+            .expr(Expr::col(AuditLog::Details).cast_as(Alias::new("text")))
+            .column(AuditLog::CreatedAt)
+            .from(AuditLog::Table)
+            .cond_where(
+                Cond::any()
+                    .add(Expr::col(AuditLog::SubjectUserId).eq(user_id.get() as i64))
+                    .add(Expr::col(AuditLog::Actor).eq(user_actor(user_id))),
+            )
+            .order_by(AuditLog::Id, Order::Asc)
+            .build(PostgresQueryBuilder)
+    }
+}
+
+/// An action about to be recorded with [`record`].
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+    pub actor: String,
+    pub action: &'static str,
+    pub subject_user_id: Option<Snowflake>,
+    pub details: JsonValue,
+}
+
+/// Writes `entry` through `client`, a plain connection or a transaction the action is part of,
+/// and logs it under the `audit` target so it also shows up where the logs go.
+pub async fn record(client: &impl GenericClient, entry: &NewAuditEntry) -> Result<()> {
+    info!(
+        target: "audit",
+        actor = %entry.actor,
+        action = entry.action,
+        subject_user_id = ?entry.subject_user_id.map(|id| id.get()),
+        details = %entry.details,
+        "Audit"
+    );
+    let subject_user_id = entry.subject_user_id.map(|id| id.get() as i64);
+    timed(
+        "db insert audit_log",
+        client.execute(
+            "INSERT INTO audit_log (actor, action, subject_user_id, details) \
+             VALUES ($1, $2, $3, ($4::text)::jsonb)",
+            &[
+                &entry.actor,
+                &entry.action,
+                &subject_user_id,
+                &entry.details.to_string(),
+            ],
+        ),
+    )
+    .await
+    .map_err(|e| Error::RustError(format!("Failed to write audit entry: {}", e)))?;
+    Ok(())
+}
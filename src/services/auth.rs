@@ -1,11 +1,28 @@
+use std::future::Future;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
 use cookie::{Cookie, SameSite};
+use futures::future::{select, Either};
 use reqwest::ClientBuilder;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use time::Duration;
-use worker::{console_error, Result, Url};
+use tracing::{error, warn};
+use worker::{Delay, Env, Result, Url};
 
-use crate::{services::cookie::CookieJar, DISCORD_API_BASE_URL};
+use crate::{
+    services::{
+        cookie::CookieJar,
+        discord_rate_limit::{self, SendError},
+        json::response_json,
+        session::SESSION_TTL_DAYS,
+    },
+    state::server_info::CookieConfig,
+    DISCORD_API_BASE_URL,
+};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DiscordOAuth2Scope {
     Identify,
     Guilds,
@@ -52,6 +69,93 @@ pub enum DiscordOAuth2Scope {
     Openid,
 }
 
+impl DiscordOAuth2Scope {
+    /// Every scope Discord documents, keep in sync with the enum when adding variants.
+    pub fn all() -> &'static [DiscordOAuth2Scope] {
+        &[
+            DiscordOAuth2Scope::Identify,
+            DiscordOAuth2Scope::Guilds,
+            DiscordOAuth2Scope::Email,
+            DiscordOAuth2Scope::GuildsChannelsRead,
+            DiscordOAuth2Scope::Rpc,
+            DiscordOAuth2Scope::RpcVoiceWrite,
+            DiscordOAuth2Scope::RpcScreenshareRead,
+            DiscordOAuth2Scope::ApplicationsBuildsRead,
+            DiscordOAuth2Scope::WebhookIncoming,
+            DiscordOAuth2Scope::ApplicationsEntitlements,
+            DiscordOAuth2Scope::ActivitiesInvitesWrite,
+            DiscordOAuth2Scope::Voice,
+            DiscordOAuth2Scope::DmChannelsMessagesRead,
+            DiscordOAuth2Scope::PresencesRead,
+            DiscordOAuth2Scope::AccountGlobalNameUpdate,
+            DiscordOAuth2Scope::SdkSocialLayer,
+            DiscordOAuth2Scope::ApplicationsCommandsPermissionsUpdate,
+            DiscordOAuth2Scope::LobbiesWrite,
+            DiscordOAuth2Scope::DmChannelsMessagesWrite,
+            DiscordOAuth2Scope::PresencesWrite,
+            DiscordOAuth2Scope::PaymentSourcesCountryCode,
+            DiscordOAuth2Scope::DmChannelsRead,
+            DiscordOAuth2Scope::RelationshipsRead,
+            DiscordOAuth2Scope::ActivitiesRead,
+            DiscordOAuth2Scope::MessagesRead,
+            DiscordOAuth2Scope::RpcScreenshareWrite,
+            DiscordOAuth2Scope::RpcVideoRead,
+            DiscordOAuth2Scope::ApplicationsCommands,
+            DiscordOAuth2Scope::RpcNotificationsRead,
+            DiscordOAuth2Scope::GdmJoin,
+            DiscordOAuth2Scope::GuildsJoin,
+            DiscordOAuth2Scope::GuildsMembersRead,
+            DiscordOAuth2Scope::Connections,
+            DiscordOAuth2Scope::Bot,
+            DiscordOAuth2Scope::RpcVoiceRead,
+            DiscordOAuth2Scope::RpcVideoWrite,
+            DiscordOAuth2Scope::RpcActivitiesWrite,
+            DiscordOAuth2Scope::ApplicationsBuildsUpload,
+            DiscordOAuth2Scope::ApplicationsStoreUpdate,
+            DiscordOAuth2Scope::ActivitiesWrite,
+            DiscordOAuth2Scope::RelationshipsWrite,
+            DiscordOAuth2Scope::RoleConnectionsWrite,
+            DiscordOAuth2Scope::Openid,
+        ]
+    }
+
+    /// Parses a space separated scope string (as returned in the token response) into the
+    /// scopes we know about and the ones we don't.
+    pub fn from_space_separated(scopes: &str) -> (Vec<DiscordOAuth2Scope>, Vec<String>) {
+        let mut known = Vec::new();
+        let mut unknown = Vec::new();
+        for scope in scopes.split_whitespace() {
+            match scope.parse() {
+                Ok(s) => known.push(s),
+                Err(_) => unknown.push(scope.to_string()),
+            }
+        }
+        (known, unknown)
+    }
+}
+
+impl std::str::FromStr for DiscordOAuth2Scope {
+    type Err = String;
+
+    /// Parses the scope as Discord spells it, e.g. `guilds.members.read`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        Self::all()
+            .iter()
+            .find(|scope| scope.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown OAuth2 scope: {}", s))
+    }
+}
+
+impl TryFrom<&str> for DiscordOAuth2Scope {
+    type Error = String;
+
+    fn try_from(s: &str) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
 impl std::fmt::Display for DiscordOAuth2Scope {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -122,18 +226,67 @@ impl DiscordOAuthAccessToken {
     pub fn refresh_token(&self) -> &str {
         &self.refresh_token
     }
+
+    /// Lifetime of the access token in seconds, as Discord sent it.
+    pub fn expires_in(&self) -> i64 {
+        self.expires_in
+    }
+
+    /// The granted scopes. Ones this crate doesn't know are left out.
+    pub fn scopes(&self) -> Vec<DiscordOAuth2Scope> {
+        DiscordOAuth2Scope::from_space_separated(&self.scope).0
+    }
+
+    /// The scopes of `required` the user didn't grant, Discord lets them untick some on the
+    /// consent screen.
+    pub fn missing_scopes(&self, required: &[DiscordOAuth2Scope]) -> Vec<DiscordOAuth2Scope> {
+        let granted = self.scopes();
+        required
+            .iter()
+            .filter(|scope| !granted.contains(scope))
+            .copied()
+            .collect()
+    }
+
+    /// Absolute expiry (ms since epoch) of a token issued at `issued_at_ms`.
+    pub fn expires_at(&self, issued_at_ms: u64) -> u64 {
+        issued_at_ms + self.expires_in.max(0) as u64 * 1000
+    }
+
+    /// When a token that was issued just now expires.
+    pub fn expiry(&self) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(self.expires_in.max(0))
+    }
+
+    /// Whether a token issued at `issued_at_ms` has expired by now.
+    pub fn is_expired(&self, issued_at_ms: u64) -> bool {
+        worker::Date::now().as_millis() >= self.expires_at(issued_at_ms)
+    }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum DiscordCookie {
+    /// The opaque id of the browser's row in the sessions table, the only auth cookie set.
+    Session,
+    OAuthState,
+    CodeVerifier,
+    /// Cookies from before server side sessions, only ever cleared.
     AccessToken,
     RefreshToken,
+    TokenExpiry,
+    UserId,
 }
 
 impl std::fmt::Display for DiscordCookie {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
+            DiscordCookie::Session => "discord_session",
             DiscordCookie::AccessToken => "discord_token",
             DiscordCookie::RefreshToken => "discord_refresh_token",
+            DiscordCookie::OAuthState => "oauth_state",
+            DiscordCookie::CodeVerifier => "oauth_code_verifier",
+            DiscordCookie::TokenExpiry => "discord_token_expiry",
+            DiscordCookie::UserId => "discord_user",
         };
         write!(f, "{}", s)
     }
@@ -157,9 +310,35 @@ pub struct DiscordAccessCodeBody {
     code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_verifier: Option<String>,
     redirect_uri: String,
 }
 
+/// Which kind of token is being revoked, lets Discord skip a lookup.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TokenTypeHint {
+    #[serde(rename = "access_token")]
+    AccessToken,
+    #[serde(rename = "refresh_token")]
+    RefreshToken,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DiscordRevokeBody<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
+    token: &'a str,
+    token_type_hint: TokenTypeHint,
+}
+
+/// An authorize URL plus the per-flow secrets that must survive until the redirect.
+pub struct AuthorizationRequest {
+    pub url: Url,
+    pub state: String,
+    pub code_verifier: String,
+}
+
 pub struct DiscordOAuth2 {
     pub client_id: String,
     pub redirect_uri: String,
@@ -170,7 +349,22 @@ impl DiscordOAuth2 {
     fn setup_url(&self) -> Url {
         Url::parse(&format!("{}/oauth2/authorize", DISCORD_API_BASE_URL)).unwrap()
     }
-    pub fn get_auth_url(&self) -> Url {
+    /// Builds the authorize URL with a fresh random `state` and PKCE pair. The state and
+    /// verifier are returned alongside so the caller can store them for the `redirect` step.
+    pub fn get_auth_url(&self) -> Result<AuthorizationRequest> {
+        let state = generate_state()?;
+        let code_verifier = generate_code_verifier()?;
+        let url = self.get_auth_url_with_nonce(&state, Some(&code_challenge(&code_verifier)));
+        Ok(AuthorizationRequest {
+            url,
+            state,
+            code_verifier,
+        })
+    }
+
+    /// Deterministic variant of [`DiscordOAuth2::get_auth_url`], the nonces are passed in instead
+    /// of generated so the resulting URL can be asserted exactly.
+    pub fn get_auth_url_with_nonce(&self, state: &str, code_challenge: Option<&str>) -> Url {
         let mut discord_url = self.setup_url();
         let scope_string = self
             .scopes
@@ -180,12 +374,19 @@ impl DiscordOAuth2 {
             .join("+");
 
         // Manually build the query string to avoid encoding the '+' in scope
-        let query = format!(
-            "client_id={}&response_type=code&redirect_uri={}&scope={}",
+        let mut query = format!(
+            "client_id={}&response_type=code&redirect_uri={}&scope={}&state={}",
             &self.client_id,
             urlencoding::encode(&self.redirect_uri),
-            scope_string // do not encode scope_string
+            scope_string, // do not encode scope_string
+            urlencoding::encode(state)
         );
+        if let Some(code_challenge) = code_challenge {
+            query.push_str(&format!(
+                "&code_challenge={}&code_challenge_method=S256",
+                urlencoding::encode(code_challenge)
+            ));
+        }
 
         discord_url.set_query(Some(&query));
         discord_url
@@ -209,146 +410,670 @@ impl DiscordOAuth2 {
     }
 }
 
+/// Rate limits on the token endpoint shorter than this are waited out once.
+const MAX_TOKEN_RETRY_WAIT_SECS: f64 = 2.0;
+
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const DEFAULT_CONNECT_RETRIES: u32 = 1;
+
+/// Why a token exchange or refresh failed.
+#[derive(Debug, Clone)]
+pub enum TokenError {
+    /// Discord couldn't be reached, retried as configured by
+    /// [`DiscordAPIClient::with_connect_retries`].
+    Network(String),
+    /// No complete answer within [`DiscordAPIClient::with_timeout`], never retried.
+    Timeout(std::time::Duration),
+    /// `retry_after` is in seconds, from the 429 body or its headers.
+    RateLimited {
+        retry_after: f64,
+        global: bool,
+    },
+    /// Discord refused the grant, e.g. an expired code or a revoked refresh token.
+    Rejected(reqwest::StatusCode),
+    Deserialize(String),
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Network(e) => write!(f, "Failed to reach Discord: {}", e),
+            TokenError::Timeout(timeout) => write!(f, "Discord didn't answer within {:?}", timeout),
+            TokenError::RateLimited {
+                retry_after,
+                global,
+            } => write!(
+                f,
+                "Rate limited by Discord for {}s (global: {})",
+                retry_after, global
+            ),
+            TokenError::Rejected(status) => write!(f, "Discord rejected the grant: {}", status),
+            TokenError::Deserialize(e) => write!(f, "Unexpected token response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// How long to wait out a rate limit on the token endpoint before retrying, `None` when it
+/// shouldn't be retried: global limits and long waits go straight back to the caller.
+fn token_retry_wait(error: &TokenError) -> Option<std::time::Duration> {
+    match error {
+        TokenError::RateLimited {
+            retry_after,
+            global: false,
+        } if *retry_after <= MAX_TOKEN_RETRY_WAIT_SECS => {
+            Some(std::time::Duration::from_secs_f64(retry_after.max(0.0)))
+        }
+        _ => None,
+    }
+}
+
 pub struct DiscordAPIClient {
     client: reqwest::Client,
+    env: Env,
     client_id: String,
     client_secret: String,
     redirect_uri: String,
+    timeout: std::time::Duration,
+    connect_retries: u32,
 }
 
 impl DiscordAPIClient {
     pub fn new(
+        env: &Env,
         discord_client_id: String,
         discord_client_secret: String,
         redirect_uri: String,
     ) -> Self {
-        let client = ClientBuilder::new();
+        let client = ClientBuilder::new().user_agent(crate::USER_AGENT);
 
         Self {
             client: client.build().expect("Failed to build reqwest client"),
+            env: env.clone(),
             client_id: discord_client_id,
             client_secret: discord_client_secret,
             redirect_uri,
+            timeout: DEFAULT_TIMEOUT,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
         }
     }
 
-    pub async fn get_access_token(&self, code: String) -> Result<DiscordOAuthAccessToken> {
-        let url = format!("{}/oauth2/token", DISCORD_API_BASE_URL);
+    /// How long a call to Discord may take, response body included. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How often a token request is sent again when Discord couldn't be reached at all.
+    /// Defaults to once, `0` disables retries.
+    pub fn with_connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Runs `fut` within the timeout, `None` when it ran out. reqwest's own timeout isn't
+    /// available on every target and on wasm only covers the fetch up to the headers, racing a
+    /// `worker::Delay` works on Workers and covers the body too. Dropping the losing fetch
+    /// aborts it.
+    async fn within_timeout<F: Future>(&self, fut: F) -> Option<F::Output> {
+        match select(Box::pin(fut), Delay::from(self.timeout)).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    pub async fn get_access_token(
+        &self,
+        code: String,
+        code_verifier: String,
+    ) -> std::result::Result<DiscordOAuthAccessToken, TokenError> {
         let params = DiscordAccessCodeBody {
             client_id: self.client_id.clone(),
             client_secret: self.client_secret.clone(),
             grant_type: DiscordOAuthGrantType::AuthorizationCode,
             code: Some(code),
             refresh_token: None,
+            code_verifier: Some(code_verifier),
             redirect_uri: self.redirect_uri.clone(),
         };
-
-        let response = match self.client.post(&url).form(&params).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                console_error!("Error sending request to Discord API: {}", e);
-                return Err(worker::Error::RustError(
-                    "Failed to send request to Discord API".into(),
-                ));
-            }
-        };
-
-        let token = match response.json::<DiscordOAuthAccessToken>().await {
-            Ok(token) => token,
-            Err(e) => {
-                console_error!("Error parsing response from Discord API: {}", e);
-                return Err(worker::Error::RustError(
-                    "Failed to parse response from Discord API".into(),
-                ));
-            }
-        };
-
-        Ok(token)
+        self.token_request_with_retry(&params).await
     }
 
-    pub async fn refresh_access_token(&self, code: &str) -> Result<DiscordOAuthAccessToken> {
-        let url = format!("{}/oauth2/token", DISCORD_API_BASE_URL);
+    pub async fn refresh_access_token(
+        &self,
+        code: &str,
+    ) -> std::result::Result<DiscordOAuthAccessToken, TokenError> {
         let params = DiscordAccessCodeBody {
             client_id: self.client_id.to_string(),
             client_secret: self.client_secret.to_string(),
             grant_type: DiscordOAuthGrantType::RefreshToken,
             code: None,
             refresh_token: Some(code.to_string()),
+            code_verifier: None,
             redirect_uri: self.redirect_uri.to_string(),
         };
+        self.token_request_with_retry(&params).await
+    }
+
+    /// Retries once after a short, non-global rate limit and up to `connect_retries` times when
+    /// Discord couldn't be reached. Global limits, long waits and timeouts go straight back to
+    /// the caller, retrying those would only dig the hole deeper.
+    async fn token_request_with_retry(
+        &self,
+        params: &DiscordAccessCodeBody,
+    ) -> std::result::Result<DiscordOAuthAccessToken, TokenError> {
+        let mut connect_retries = self.connect_retries;
+        let mut rate_limit_retry = true;
+        loop {
+            match self.token_request(params).await {
+                Err(TokenError::Network(e)) if connect_retries > 0 => {
+                    connect_retries -= 1;
+                    warn!(
+                        endpoint = "POST /oauth2/token",
+                        error = %e,
+                        "Failed to reach Discord, retrying"
+                    );
+                }
+                Err(e) => match token_retry_wait(&e).filter(|_| rate_limit_retry) {
+                    Some(wait) => {
+                        rate_limit_retry = false;
+                        warn!(
+                            endpoint = "POST /oauth2/token",
+                            retry_after = wait.as_secs_f64(),
+                            "Rate limited, retrying once"
+                        );
+                        Delay::from(wait).await;
+                    }
+                    None => return Err(e),
+                },
+                Ok(token) => return Ok(token),
+            }
+        }
+    }
+
+    async fn token_request(
+        &self,
+        params: &DiscordAccessCodeBody,
+    ) -> std::result::Result<DiscordOAuthAccessToken, TokenError> {
+        match self.within_timeout(self.send_token_request(params)).await {
+            Some(result) => result,
+            None => {
+                error!(
+                    endpoint = "POST /oauth2/token",
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "Discord API request timed out"
+                );
+                Err(TokenError::Timeout(self.timeout))
+            }
+        }
+    }
 
-        let response = match self.client.post(&url).form(&params).send().await {
+    async fn send_token_request(
+        &self,
+        params: &DiscordAccessCodeBody,
+    ) -> std::result::Result<DiscordOAuthAccessToken, TokenError> {
+        let url = format!("{}/oauth2/token", DISCORD_API_BASE_URL);
+        let response = match discord_rate_limit::send(
+            &self.env,
+            "POST /oauth2/token",
+            self.client.post(&url).form(params),
+        )
+        .await
+        {
             Ok(resp) => resp,
-            Err(e) => {
-                console_error!("Error sending request to Discord API: {}", e);
-                return Err(worker::Error::RustError(
-                    "Failed to send request to Discord API".into(),
-                ));
+            Err(SendError::CoolingDown(secs)) => {
+                return Err(TokenError::RateLimited {
+                    retry_after: secs as f64,
+                    global: true,
+                });
+            }
+            // Short enough for `token_request_with_retry` to wait out once
+            Err(SendError::Busy) => {
+                return Err(TokenError::RateLimited {
+                    retry_after: 1.0,
+                    global: false,
+                });
+            }
+            Err(SendError::Request(e)) => {
+                error!(
+                    endpoint = "POST /oauth2/token",
+                    error = %e,
+                    "Error sending request to Discord API"
+                );
+                return Err(TokenError::Network(e.to_string()));
             }
         };
 
-        let token = match response.json::<DiscordOAuthAccessToken>().await {
-            Ok(token) => token,
-            Err(e) => {
-                console_error!("Error parsing response from Discord API: {}", e);
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let (retry_after, global) = discord_rate_limit::retry_hint(response).await;
+            warn!(
+                endpoint = "POST /oauth2/token",
+                retry_after, global, "Discord rate limited the token request"
+            );
+            return Err(TokenError::RateLimited {
+                retry_after,
+                global,
+            });
+        }
+        if !status.is_success() {
+            warn!(
+                endpoint = "POST /oauth2/token",
+                status = status.as_u16(),
+                "Discord rejected the token request"
+            );
+            return Err(TokenError::Rejected(status));
+        }
+
+        response_json::<DiscordOAuthAccessToken>(response, "POST /oauth2/token")
+            .await
+            .map_err(TokenError::Deserialize)
+    }
+
+    /// Invalidates `token` on Discord's side, revoking either token of a grant ends the whole
+    /// authorization.
+    pub async fn revoke_token(&self, token: &str, token_type_hint: TokenTypeHint) -> Result<()> {
+        let url = format!("{}/oauth2/token/revoke", DISCORD_API_BASE_URL);
+        let params = DiscordRevokeBody {
+            client_id: &self.client_id,
+            client_secret: &self.client_secret,
+            token,
+            token_type_hint,
+        };
+
+        let send = discord_rate_limit::send(
+            &self.env,
+            "POST /oauth2/token/revoke",
+            self.client.post(&url).form(&params),
+        );
+        let response = match self.within_timeout(send).await {
+            Some(Ok(resp)) => resp,
+            None => {
+                error!(
+                    endpoint = "POST /oauth2/token/revoke",
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "Discord API request timed out"
+                );
+                return Err(worker::Error::RustError(
+                    "Discord API request timed out".into(),
+                ));
+            }
+            Some(Err(e)) => {
+                error!(
+                    endpoint = "POST /oauth2/token/revoke",
+                    error = %e,
+                    "Error sending request to Discord API"
+                );
                 return Err(worker::Error::RustError(
-                    "Failed to parse response from Discord API".into(),
+                    "Failed to send request to Discord API".into(),
                 ));
             }
         };
+        if !response.status().is_success() {
+            warn!(
+                endpoint = "POST /oauth2/token/revoke",
+                status = response.status().as_u16(),
+                "Discord rejected the revocation"
+            );
+            return Err(worker::Error::RustError(format!(
+                "Token revocation failed with status {}",
+                response.status()
+            )));
+        }
 
-        Ok(token)
+        Ok(())
     }
+}
 
-    pub fn set_cookies(tokens: DiscordOAuthAccessToken) -> [Cookie<'static>; 2] {
-        let access_cookie = Cookie::build((
-            DiscordCookie::AccessToken.to_string(),
-            tokens.access_token.clone(),
-        ))
-        .path("/")
-        .http_only(true)
-        .secure(true)
-        .same_site(SameSite::None)
-        .max_age(cookie::time::Duration::seconds(tokens.expires_in))
-        .build();
+/// Generates a random nonce for the OAuth2 `state` parameter.
+pub fn generate_state() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| worker::Error::RustError(format!("Failed to generate state: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
 
-        let refresh_cookie = Cookie::build((
-            DiscordCookie::RefreshToken.to_string(),
-            tokens.refresh_token.clone(),
-        ))
-        .path("/")
-        .http_only(true)
-        .secure(true)
-        .same_site(SameSite::None)
-        .build();
+/// Generates a PKCE code verifier (RFC 7636): 32 random bytes as unpadded base64url, which is
+/// 43 characters from the unreserved set.
+pub fn generate_code_verifier() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| {
+        worker::Error::RustError(format!("Failed to generate code verifier: {}", e))
+    })?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
 
-        [access_cookie, refresh_cookie]
-    }
+/// The `S256` challenge for a verifier, `BASE64URL(SHA256(verifier))` without padding.
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
 }
 
-pub fn remove_error_cookies(jar: &CookieJar) -> ((CookieJar, CookieJar)) {
-    let discord_token = Cookie::build((DiscordCookie::AccessToken.to_string(), ""))
-        .path("/")
+/// Short lived cookie carrying a secret from `login` to `redirect`, e.g. the state or verifier.
+/// Set through a [`crate::services::cookie::PrivateCookieJar`] so the verifier never leaves the
+/// server readable.
+pub fn oauth_flow_cookie(
+    kind: DiscordCookie,
+    value: String,
+    config: &CookieConfig,
+) -> Cookie<'static> {
+    Cookie::build((kind.to_string(), value))
+        .path("/api/auth")
         .http_only(true)
-        .max_age(Duration::ZERO)
-        .build();
-    let discord_refresh_token = Cookie::build((DiscordCookie::RefreshToken.to_string(), ""))
-        .path("/")
+        .secure(config.secure())
+        .same_site(SameSite::Lax)
+        .max_age(Duration::minutes(10))
+        .build()
+}
+
+/// Expires an [`oauth_flow_cookie`] once the redirect has consumed it.
+pub fn clear_oauth_flow_cookie(kind: DiscordCookie, config: &CookieConfig) -> Cookie<'static> {
+    Cookie::build((kind.to_string(), ""))
+        .path("/api/auth")
         .http_only(true)
+        .secure(config.secure())
         .max_age(Duration::ZERO)
-        .build();
-    (
-        jar.clone().add(discord_token),
-        jar.clone().add(discord_refresh_token),
-    )
+        .build()
 }
 
-pub fn add_success_cookies(
-    jar: &CookieJar,
-    cookies: [Cookie<'static>; 2],
-) -> (CookieJar, CookieJar) {
+pub fn remove_error_cookies(jar: &CookieJar, config: &CookieConfig) -> (CookieJar, CookieJar) {
+    // Removal has to match the name, path and domain the cookie was set with, and prefixed
+    // cookies are only accepted with Secure even when they're being cleared
+    let removal = |cookie: DiscordCookie| {
+        let mut removal = Cookie::build((config.name(cookie), ""))
+            .path("/")
+            .http_only(true)
+            .secure(config.secure())
+            .max_age(Duration::ZERO)
+            .build();
+        if let Some(domain) = config.domain() {
+            removal.set_domain(domain.to_string());
+        }
+        removal
+    };
     (
-        jar.clone().add(cookies[0].clone()),
-        jar.clone().add(cookies[1].clone()),
+        jar.clone()
+            .remove_chunked(removal(DiscordCookie::AccessToken))
+            .add(removal(DiscordCookie::AccessToken))
+            .add(removal(DiscordCookie::TokenExpiry)),
+        jar.clone()
+            .add(removal(DiscordCookie::Session))
+            .add(removal(DiscordCookie::RefreshToken))
+            .add(removal(DiscordCookie::UserId)),
     )
 }
+
+/// Purpose the stored refresh tokens are sealed under, see [`crate::services::crypto::seal`].
+pub const REFRESH_TOKEN_PURPOSE: &str = "discord_refresh_token";
+
+/// The session cookie, lives as long as the session row it points at.
+pub fn session_cookie(session_id: String, config: &CookieConfig) -> Cookie<'static> {
+    // Path=/ and Secure satisfy both cookie prefixes, Secure is only dropped without a prefix
+    let mut cookie = Cookie::build((config.name(DiscordCookie::Session), session_id))
+        .path("/")
+        .http_only(true)
+        .secure(config.secure())
+        .same_site(config.same_site())
+        .max_age(Duration::days(SESSION_TTL_DAYS))
+        .build();
+    if let Some(domain) = config.domain() {
+        cookie.set_domain(domain.to_string());
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::header::SET_COOKIE, response::IntoResponse};
+
+    use super::*;
+    use crate::state::server_info::CookiePrefix;
+
+    /// Every prefix with the domains it may be combined with.
+    fn configs() -> Vec<(CookiePrefix, CookieConfig)> {
+        [
+            (CookiePrefix::None, None),
+            (CookiePrefix::None, Some("example.com")),
+            (CookiePrefix::Secure, None),
+            (CookiePrefix::Secure, Some("example.com")),
+            (CookiePrefix::Host, None),
+        ]
+        .into_iter()
+        .map(|(prefix, domain)| {
+            let config = CookieConfig::new(prefix, domain.map(String::from)).unwrap();
+            (prefix, config)
+        })
+        .collect()
+    }
+
+    /// The attributes browsers require before they store a prefixed cookie.
+    fn assert_prefix_rules(cookie: &Cookie) {
+        let name = cookie.name();
+        if name.starts_with("__Secure-") || name.starts_with("__Host-") {
+            assert_eq!(cookie.secure(), Some(true), "{}", cookie);
+        }
+        if name.starts_with("__Host-") {
+            assert_eq!(cookie.path(), Some("/"), "{}", cookie);
+            assert_eq!(cookie.domain(), None, "{}", cookie);
+        }
+    }
+
+    fn set_cookie_headers(jar: CookieJar) -> Vec<Cookie<'static>> {
+        jar.into_response()
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap().to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn prefixed_names() {
+        let name = |prefix| {
+            CookieConfig::new(prefix, None)
+                .unwrap()
+                .name(DiscordCookie::Session)
+        };
+        assert_eq!(name(CookiePrefix::None), "discord_session");
+        assert_eq!(name(CookiePrefix::Secure), "__Secure-discord_session");
+        assert_eq!(name(CookiePrefix::Host), "__Host-discord_session");
+    }
+
+    #[test]
+    fn session_cookie_meets_its_prefix_rules() {
+        for (_, config) in configs() {
+            let cookie = session_cookie("id".to_string(), &config);
+            assert_eq!(cookie.name(), config.name(DiscordCookie::Session));
+            assert_prefix_rules(&cookie);
+        }
+    }
+
+    #[test]
+    fn removals_meet_their_prefix_rules() {
+        for (prefix, config) in configs() {
+            let (tokens, session) = remove_error_cookies(&CookieJar::new(), &config);
+            let removals: Vec<_> = set_cookie_headers(tokens)
+                .into_iter()
+                .chain(set_cookie_headers(session))
+                .collect();
+            assert!(!removals.is_empty());
+            for removal in &removals {
+                assert!(removal.name().starts_with(prefix.as_str()), "{}", removal);
+                assert_prefix_rules(removal);
+            }
+        }
+    }
+
+    #[test]
+    fn host_prefix_refuses_a_domain() {
+        assert!(CookieConfig::new(CookiePrefix::Host, Some("example.com".into())).is_err());
+        assert!(CookieConfig::new(CookiePrefix::Secure, Some("example.com".into())).is_ok());
+    }
+
+    #[test]
+    fn prefixes_need_secure_cookies() {
+        for prefix in [CookiePrefix::Secure, CookiePrefix::Host] {
+            let config = CookieConfig::new(prefix, None).unwrap();
+            assert!(config.insecure().is_err());
+        }
+        let local = CookieConfig::new(CookiePrefix::None, None)
+            .unwrap()
+            .insecure()
+            .unwrap();
+        assert!(!local.secure());
+    }
+
+    /// Stops compiling when a variant is added: number it here and add it to
+    /// [`DiscordOAuth2Scope::all`].
+    fn variant_number(scope: DiscordOAuth2Scope) -> usize {
+        use DiscordOAuth2Scope::*;
+        match scope {
+            Identify => 0,
+            Guilds => 1,
+            Email => 2,
+            GuildsChannelsRead => 3,
+            Rpc => 4,
+            RpcVoiceWrite => 5,
+            RpcScreenshareRead => 6,
+            ApplicationsBuildsRead => 7,
+            WebhookIncoming => 8,
+            ApplicationsEntitlements => 9,
+            ActivitiesInvitesWrite => 10,
+            Voice => 11,
+            DmChannelsMessagesRead => 12,
+            PresencesRead => 13,
+            AccountGlobalNameUpdate => 14,
+            SdkSocialLayer => 15,
+            ApplicationsCommandsPermissionsUpdate => 16,
+            LobbiesWrite => 17,
+            DmChannelsMessagesWrite => 18,
+            PresencesWrite => 19,
+            PaymentSourcesCountryCode => 20,
+            DmChannelsRead => 21,
+            RelationshipsRead => 22,
+            ActivitiesRead => 23,
+            MessagesRead => 24,
+            RpcScreenshareWrite => 25,
+            RpcVideoRead => 26,
+            ApplicationsCommands => 27,
+            RpcNotificationsRead => 28,
+            GdmJoin => 29,
+            GuildsJoin => 30,
+            GuildsMembersRead => 31,
+            Connections => 32,
+            Bot => 33,
+            RpcVoiceRead => 34,
+            RpcVideoWrite => 35,
+            RpcActivitiesWrite => 36,
+            ApplicationsBuildsUpload => 37,
+            ApplicationsStoreUpdate => 38,
+            ActivitiesWrite => 39,
+            RelationshipsWrite => 40,
+            RoleConnectionsWrite => 41,
+            Openid => 42,
+        }
+    }
+
+    #[test]
+    fn all_covers_every_variant() {
+        let mut listed = [0; 43];
+        for scope in DiscordOAuth2Scope::all() {
+            listed[variant_number(*scope)] += 1;
+        }
+        assert!(listed.iter().all(|count| *count == 1), "{:?}", listed);
+    }
+
+    #[test]
+    fn every_scope_round_trips_through_its_name() {
+        for scope in DiscordOAuth2Scope::all() {
+            let name = scope.to_string();
+            assert_eq!(name.parse::<DiscordOAuth2Scope>(), Ok(*scope), "{}", name);
+            assert_eq!(DiscordOAuth2Scope::try_from(name.as_str()), Ok(*scope));
+        }
+    }
+
+    #[test]
+    fn unknown_scopes_are_an_error() {
+        assert!("identify.everything".parse::<DiscordOAuth2Scope>().is_err());
+        assert!("".parse::<DiscordOAuth2Scope>().is_err());
+        // Names are case sensitive, Discord only knows the lowercase ones
+        assert!("Identify".parse::<DiscordOAuth2Scope>().is_err());
+        assert_eq!(" guilds ".parse(), Ok(DiscordOAuth2Scope::Guilds));
+    }
+
+    #[test]
+    fn parses_a_mixed_scope_string() {
+        let (known, unknown) = DiscordOAuth2Scope::from_space_separated(
+            "identify  guilds.members.read made.up\temail",
+        );
+        assert_eq!(
+            known,
+            [
+                DiscordOAuth2Scope::Identify,
+                DiscordOAuth2Scope::GuildsMembersRead,
+                DiscordOAuth2Scope::Email,
+            ]
+        );
+        assert_eq!(unknown, ["made.up"]);
+    }
+
+    #[test]
+    fn parses_an_empty_scope_string() {
+        let (known, unknown) = DiscordOAuth2Scope::from_space_separated(" ");
+        assert!(known.is_empty());
+        assert!(unknown.is_empty());
+    }
+
+    fn oauth() -> DiscordOAuth2 {
+        DiscordOAuth2 {
+            client_id: "1234".to_string(),
+            redirect_uri: "https://api.example.com/api/auth/redirect".to_string(),
+            scopes: vec![DiscordOAuth2Scope::Identify, DiscordOAuth2Scope::Guilds],
+        }
+    }
+
+    #[test]
+    fn auth_url_with_nonce_is_deterministic() {
+        let url = oauth().get_auth_url_with_nonce("st&te", Some("challenge"));
+        assert_eq!(
+            url.as_str(),
+            "https://discord.com/api/v10/oauth2/authorize?client_id=1234&response_type=code\
+             &redirect_uri=https%3A%2F%2Fapi.example.com%2Fapi%2Fauth%2Fredirect\
+             &scope=identify+guilds&state=st%26te\
+             &code_challenge=challenge&code_challenge_method=S256"
+        );
+        assert_eq!(
+            url,
+            oauth().get_auth_url_with_nonce("st&te", Some("challenge"))
+        );
+    }
+
+    #[test]
+    fn auth_url_without_a_challenge_skips_pkce() {
+        let url = oauth().get_auth_url_with_nonce("state", None);
+        assert!(url.as_str().ends_with("&state=state"), "{}", url);
+    }
+
+    #[test]
+    fn only_short_route_limits_are_retried() {
+        let limited = |retry_after, global| TokenError::RateLimited {
+            retry_after,
+            global,
+        };
+        assert_eq!(
+            token_retry_wait(&limited(0.5, false)),
+            Some(std::time::Duration::from_millis(500))
+        );
+        assert_eq!(
+            token_retry_wait(&limited(MAX_TOKEN_RETRY_WAIT_SECS, false)),
+            Some(std::time::Duration::from_secs_f64(
+                MAX_TOKEN_RETRY_WAIT_SECS
+            ))
+        );
+        assert_eq!(token_retry_wait(&limited(0.5, true)), None);
+        assert_eq!(token_retry_wait(&limited(30.0, false)), None);
+
+        let rejected = TokenError::Rejected(reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(token_retry_wait(&rejected), None);
+    }
+}
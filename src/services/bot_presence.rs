@@ -0,0 +1,57 @@
+use tracing::warn;
+use worker::{Cache, Headers, Response};
+
+use crate::services::snowflake::Snowflake;
+
+/// How long a guild's bot presence is served from the cache. Short so the "invite the bot"
+/// prompt goes away soon after the bot joins.
+pub const BOT_PRESENCE_TTL_SECS: u64 = 30;
+
+/// The Cache API entry for `guild_id`. Keys have to be URLs, this one is never routed.
+fn cache_key(api_host: &str, guild_id: Snowflake) -> String {
+    format!("{}/internal/bot-presence/{}", api_host, guild_id)
+}
+
+/// Whether the bot was in `guild_id` the last time anyone asked, if that's recent enough.
+/// Cache failures count as a miss.
+pub async fn cached(api_host: &str, guild_id: Snowflake) -> Option<bool> {
+    let key = cache_key(api_host, guild_id);
+    let mut response = match Cache::default().get(key.as_str(), false).await {
+        Ok(response) => response?,
+        Err(e) => {
+            warn!("Bot presence cache lookup failed: {}", e);
+            return None;
+        }
+    };
+    match response.text().await.as_deref() {
+        Ok("true") => Some(true),
+        Ok("false") => Some(false),
+        _ => None,
+    }
+}
+
+/// Caches `bot_present` for [`BOT_PRESENCE_TTL_SECS`], failures are only logged.
+pub async fn remember(api_host: &str, guild_id: Snowflake, bot_present: bool) {
+    let result = async {
+        let headers = Headers::new();
+        headers.set(
+            "cache-control",
+            &format!("max-age={}", BOT_PRESENCE_TTL_SECS),
+        )?;
+        let response = Response::ok(bot_present.to_string())?.with_headers(headers);
+        let key = cache_key(api_host, guild_id);
+        Cache::default().put(key.as_str(), response).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to cache bot presence for guild {}: {}", guild_id, e);
+    }
+}
+
+/// Drops the cached presence of `guild_id` so the next check asks Discord.
+pub async fn forget(api_host: &str, guild_id: Snowflake) {
+    let key = cache_key(api_host, guild_id);
+    if let Err(e) = Cache::default().delete(key.as_str(), false).await {
+        warn!("Failed to evict bot presence for guild {}: {}", guild_id, e);
+    }
+}
@@ -1,6 +1,7 @@
 //! Cookie parsing and cookie jar management.
 //!
-//! See [`CookieJar`], [`SignedCookieJar`], and [`PrivateCookieJar`] for more details.
+//! See [`CookieJar`] and [`PrivateCookieJar`] for more details. The private jar is keyed from
+//! the `COOKIE_SECRET` secret, see [`cookie_key`].
 
 use axum::http::{
     header::{COOKIE, SET_COOKIE},
@@ -9,10 +10,16 @@ use axum::http::{
 };
 use axum::{
     extract::FromRequestParts,
+    http::StatusCode,
     response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
 };
-use cookie::Cookie;
+use cookie::{Cookie, Key};
 use std::convert::Infallible;
+use tracing::error;
+use worker::Env;
+
+/// `COOKIE_SECRET` must be at least this long, the jar keys are derived from it with HKDF.
+const MIN_COOKIE_SECRET_LEN: usize = 32;
 
 /// Extractor that grabs cookies from the request and manages the jar.
 ///
@@ -87,13 +94,19 @@ where
     }
 }
 
+/// Collects the cookies from every `Cookie` header, some clients and proxies split them across
+/// several headers instead of joining them with `;`.
 fn cookies_from_request(headers: &HeaderMap) -> impl Iterator<Item = Cookie<'static>> + '_ {
     headers
         .get_all(COOKIE)
         .into_iter()
         .filter_map(|value| value.to_str().ok())
         .flat_map(|value| value.split(';'))
-        .filter_map(move |cookie| Cookie::parse(cookie.trim()).ok())
+        .map(str::trim)
+        // Empty headers, trailing `;` and doubled `;;` leave empty segments behind
+        .filter(|cookie| !cookie.is_empty())
+        .filter_map(move |cookie| Cookie::parse(cookie).ok())
+        .filter(|cookie| !cookie.name().is_empty())
         .map(|cookie| {
             // Convert Cookie<'_> to Cookie<'static> by allocating owned name and value
             let name = cookie.name().to_owned();
@@ -215,6 +228,82 @@ impl CookieJar {
     pub fn iter(&self) -> impl Iterator<Item = &'_ Cookie<'static>> {
         self.jar.iter()
     }
+
+    /// Add a cookie whose value may not fit a single `Set-Cookie`, splitting it across
+    /// `name.0`, `name.1`, ... cookies that share the attributes of `cookie`.
+    ///
+    /// Chunks left over from a longer previous value, and an unchunked `name` cookie, are
+    /// removed so [`CookieJar::get_chunked`] can't stitch stale data back together.
+    pub fn add_chunked<C: Into<Cookie<'static>>>(mut self, cookie: C) -> Self {
+        let cookie = cookie.into();
+        let name = cookie.name().to_string();
+        let chunks = split_chunks(cookie.value(), MAX_CHUNK_LEN);
+
+        self.jar.remove(cookie.clone());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut chunk_cookie = cookie.clone();
+            chunk_cookie.set_name(chunk_name(&name, i));
+            chunk_cookie.set_value(chunk.clone());
+            self.jar.add(chunk_cookie);
+        }
+        self.remove_chunks_from(&cookie, chunks.len())
+    }
+
+    /// Reassemble a value written by [`CookieJar::add_chunked`], falling back to a plain `name`
+    /// cookie so values set before chunking still read.
+    #[must_use]
+    pub fn get_chunked(&self, name: &str) -> Option<String> {
+        if self.jar.get(&chunk_name(name, 0)).is_none() {
+            return self.jar.get(name).map(|c| c.value().to_string());
+        }
+        let value = (0..)
+            .map_while(|i| self.jar.get(&chunk_name(name, i)))
+            .map(|c| c.value())
+            .collect();
+        Some(value)
+    }
+
+    /// Remove every chunk of a value written by [`CookieJar::add_chunked`]. `cookie` needs the
+    /// path and domain the chunks were set with.
+    pub fn remove_chunked<C: Into<Cookie<'static>>>(mut self, cookie: C) -> Self {
+        let cookie = cookie.into();
+        self.jar.remove(cookie.clone());
+        self.remove_chunks_from(&cookie, 0)
+    }
+
+    fn remove_chunks_from(mut self, cookie: &Cookie<'static>, first: usize) -> Self {
+        let name = cookie.name().to_string();
+        let mut i = first;
+        while self.jar.get(&chunk_name(&name, i)).is_some() {
+            let mut stale = cookie.clone();
+            stale.set_name(chunk_name(&name, i));
+            self.jar.remove(stale);
+            i += 1;
+        }
+        self
+    }
+}
+
+/// Room left for the value once the name and attributes are counted against the 4096 byte
+/// `Set-Cookie` limit browsers enforce.
+const MAX_CHUNK_LEN: usize = 3800;
+
+fn chunk_name(name: &str, index: usize) -> String {
+    format!("{}.{}", name, index)
+}
+
+/// Splits `value` into pieces of at most `max_len` bytes without cutting a UTF-8 character.
+fn split_chunks(value: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for c in value.chars() {
+        if current.len() + c.len_utf8() > max_len {
+            chunks.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    chunks.push(current);
+    chunks
 }
 
 impl IntoResponseParts for CookieJar {
@@ -232,6 +321,131 @@ impl IntoResponse for CookieJar {
     }
 }
 
+/// Loads the key for [`PrivateCookieJar`] from the `COOKIE_SECRET` secret, failing with a
+/// message naming the problem when it's missing or too short.
+pub fn cookie_key(env: &Env) -> Result<Key, String> {
+    let secret = env
+        .secret("COOKIE_SECRET")
+        .map(|s| s.to_string())
+        .map_err(|_| "COOKIE_SECRET is not set".to_string())?;
+    if secret.len() < MIN_COOKIE_SECRET_LEN {
+        return Err(format!(
+            "COOKIE_SECRET must be at least {} bytes long",
+            MIN_COOKIE_SECRET_LEN
+        ));
+    }
+    Ok(Key::derive_from(secret.as_bytes()))
+}
+
+/// Key for the request's private jar, taken from the [`Env`] extension the app is layered with.
+fn key_from_parts(parts: &Parts) -> Result<Key, (StatusCode, &'static str)> {
+    let Some(env) = parts.extensions.get::<Env>() else {
+        error!("Env extension missing, can't load the cookie key");
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"));
+    };
+    cookie_key(env).map_err(|e| {
+        error!("Failed to load cookie key: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+    })
+}
+
+/// Like [`CookieJar`] but values are encrypted and authenticated (AES-256-GCM), the client can
+/// neither read nor tamper with them.
+///
+/// Cookies that fail to decrypt are dropped when the jar is extracted.
+#[must_use = "`PrivateCookieJar` should be returned as part of a `Response`, otherwise it does nothing."]
+#[derive(Clone)]
+pub struct PrivateCookieJar {
+    jar: cookie::CookieJar,
+    key: Key,
+}
+
+impl std::fmt::Debug for PrivateCookieJar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrivateCookieJar")
+            .field("jar", &self.jar)
+            .field("key", &"REDACTED")
+            .finish()
+    }
+}
+
+impl<S> FromRequestParts<S> for PrivateCookieJar
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let key = key_from_parts(parts)?;
+        Ok(Self::from_headers(&parts.headers, key))
+    }
+}
+
+impl PrivateCookieJar {
+    /// Create a new `PrivateCookieJar` from a map of request headers, keeping only the cookies
+    /// that decrypt with `key`.
+    pub fn from_headers(headers: &HeaderMap, key: Key) -> Self {
+        let mut jar = cookie::CookieJar::new();
+        let mut private_jar = jar.private_mut(&key);
+        for cookie in cookies_from_request(headers) {
+            if let Some(cookie) = private_jar.decrypt(cookie) {
+                private_jar.add_original(cookie);
+            }
+        }
+        Self { jar, key }
+    }
+
+    /// Create a new empty `PrivateCookieJar`.
+    pub fn new(key: Key) -> Self {
+        Self {
+            jar: Default::default(),
+            key,
+        }
+    }
+
+    /// Get a cookie from the jar, its value is already decrypted.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Cookie<'static>> {
+        self.jar.private(&self.key).get(name)
+    }
+
+    /// Remove a cookie from the jar.
+    pub fn remove<C: Into<Cookie<'static>>>(mut self, cookie: C) -> Self {
+        self.jar.private_mut(&self.key).remove(cookie);
+        self
+    }
+
+    /// Add a cookie to the jar, encrypting its value.
+    #[allow(clippy::should_implement_trait)]
+    pub fn add<C: Into<Cookie<'static>>>(mut self, cookie: C) -> Self {
+        self.jar.private_mut(&self.key).add(cookie);
+        self
+    }
+
+    /// Get an iterator over all decrypted cookies in the jar.
+    pub fn iter(&self) -> impl Iterator<Item = Cookie<'static>> + '_ {
+        let private = self.jar.private(&self.key);
+        self.jar
+            .iter()
+            .filter_map(move |cookie| private.get(cookie.name()))
+    }
+}
+
+impl IntoResponseParts for PrivateCookieJar {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        set_cookies(&self.jar, res.headers_mut());
+        Ok(res)
+    }
+}
+
+impl IntoResponse for PrivateCookieJar {
+    fn into_response(self) -> Response {
+        (self, ()).into_response()
+    }
+}
+
 fn set_cookies(jar: &cookie::CookieJar, headers: &mut HeaderMap) {
     for cookie in jar.delta() {
         if let Ok(header_value) = cookie.to_string().parse() {
@@ -242,3 +456,51 @@ fn set_cookies(jar: &cookie::CookieJar, headers: &mut HeaderMap) {
     // we don't need to call `jar.reset_delta()` because `into_response_parts` consumes the cookie
     // jar so it cannot be called multiple times.
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn jar(cookie_headers: &[&'static str]) -> CookieJar {
+        let mut headers = HeaderMap::new();
+        for value in cookie_headers {
+            headers.append(COOKIE, HeaderValue::from_static(value));
+        }
+        CookieJar::from_headers(&headers)
+    }
+
+    fn names(jar: &CookieJar) -> Vec<String> {
+        let mut names: Vec<String> = jar.iter().map(|c| c.name().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reads_cookies_from_one_header() {
+        let jar = jar(&["session=abc; theme=dark"]);
+        assert_eq!(jar.get("session").map(|c| c.value()), Some("abc"));
+        assert_eq!(jar.get("theme").map(|c| c.value()), Some("dark"));
+    }
+
+    #[test]
+    fn reads_cookies_split_across_headers() {
+        let jar = jar(&["session=abc", "theme=dark; lang=en"]);
+        assert_eq!(names(&jar), ["lang", "session", "theme"]);
+        assert_eq!(jar.get("lang").map(|c| c.value()), Some("en"));
+    }
+
+    #[test]
+    fn empty_segments_and_headers_add_nothing() {
+        let jar = jar(&["", " ; ;; session=abc ;", "=orphan", "   "]);
+        assert_eq!(names(&jar), ["session"]);
+        assert_eq!(jar.get("session").map(|c| c.value()), Some("abc"));
+    }
+
+    #[test]
+    fn no_cookie_header_is_an_empty_jar() {
+        assert_eq!(jar(&[]).iter().count(), 0);
+        assert_eq!(jar(&[""]).iter().count(), 0);
+    }
+}
@@ -0,0 +1,101 @@
+use cookie::{Cookie, Key};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::services::snowflake::Snowflake;
+
+/// Compares two secrets in constant time. Both sides are hashed first, so neither the length
+/// nor the position of the first difference leaks through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let a = Sha256::digest(a);
+    let b = Sha256::digest(b);
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+/// The credential a guild scoped client presents, an HMAC-SHA256 of the guild id keyed with
+/// `secret`. Hex encoded so it fits in a header.
+pub fn guild_credential(secret: &[u8], guild_id: Snowflake) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(guild_id.to_string().as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Encrypts `value` for storage with the cookie key, the same authenticated encryption the
+/// private cookie jar uses. `purpose` is bound in, a value sealed for one purpose won't open
+/// for another.
+pub fn seal(key: &Key, purpose: &str, value: &str) -> String {
+    let mut jar = cookie::CookieJar::new();
+    jar.private_mut(key)
+        .add(Cookie::new(purpose.to_string(), value.to_string()));
+    jar.get(purpose)
+        .map(|c| c.value().to_string())
+        .unwrap_or_default()
+}
+
+/// Reverses [`seal`], `None` when `sealed` was tampered with or sealed under another key or
+/// purpose.
+pub fn unseal(key: &Key, purpose: &str, sealed: &str) -> Option<String> {
+    let mut jar = cookie::CookieJar::new();
+    jar.add_original(Cookie::new(purpose.to_string(), sealed.to_string()));
+    jar.private(key).get(purpose).map(|c| c.value().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_inputs_match() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"bot-token", b"bot-token"));
+        let long = vec![7u8; 4096];
+        assert!(constant_time_eq(&long, &long.clone()));
+    }
+
+    #[test]
+    fn different_inputs_of_the_same_length_differ() {
+        assert!(!constant_time_eq(b"bot-token", b"bot-tokem"));
+        assert!(!constant_time_eq(b"bot-token", b"Bot-token"));
+    }
+
+    #[test]
+    fn different_lengths_differ() {
+        assert!(!constant_time_eq(b"bot-token", b"bot-token "));
+        assert!(!constant_time_eq(b"bot-token", b"bot"));
+        assert!(!constant_time_eq(b"", b"bot-token"));
+        assert!(!constant_time_eq(b"bot-token", b""));
+    }
+
+    #[test]
+    fn guild_credentials_are_bound_to_guild_and_secret() {
+        let guild = Snowflake::new(81384788765712384);
+        let credential = guild_credential(b"secret", guild);
+        assert_eq!(credential.len(), 64);
+        assert_eq!(credential, guild_credential(b"secret", guild));
+        assert_ne!(
+            credential,
+            guild_credential(b"secret", Snowflake::new(81384788765712385))
+        );
+        assert_ne!(credential, guild_credential(b"other", guild));
+    }
+
+    #[test]
+    fn sealed_values_only_open_for_their_key_and_purpose() {
+        let key = Key::derive_from(&[7u8; 32]);
+        let sealed = seal(&key, "purpose", "secret");
+        assert_ne!(sealed, "secret");
+        assert_eq!(unseal(&key, "purpose", &sealed).as_deref(), Some("secret"));
+        assert_eq!(unseal(&key, "other", &sealed), None);
+        assert_eq!(
+            unseal(&Key::derive_from(&[8u8; 32]), "purpose", &sealed),
+            None
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Caps how many requests talk to Discord at the same time across all isolates.
+//!
+//! Disabled unless `DISCORD_CONCURRENCY_LIMIT` is set. Beyond the limit a Discord call either
+//! fails straight away or, with `DISCORD_CONCURRENCY_POLICY=queue`, waits up to
+//! `DISCORD_CONCURRENCY_MAX_WAIT_MS` (default 2000) for a free slot. Only the outbound call holds
+//! a slot, see [`crate::services::discord_rate_limit::send`].
+
+use std::time::Duration;
+
+use tracing::{error, warn};
+use worker::{Date, Delay, Env};
+
+use crate::services::rate_limit::{acquire_permit, Permit};
+
+const CONCURRENCY_KEY: &str = "discord:concurrency";
+/// Upper bound for a call holding a slot, a worker request can't run much longer anyway.
+const LEASE_SECS: u64 = 30;
+const QUEUE_POLL_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    FailFast,
+    Queue { max_wait_ms: u64 },
+}
+
+impl Policy {
+    /// From `DISCORD_CONCURRENCY_POLICY` and `DISCORD_CONCURRENCY_MAX_WAIT_MS`, anything but
+    /// `queue` fails fast.
+    fn from_vars(policy: Option<&str>, max_wait_ms: Option<&str>) -> Self {
+        match policy {
+            Some("queue") => Policy::Queue {
+                max_wait_ms: max_wait_ms.and_then(|v| v.parse().ok()).unwrap_or(2_000),
+            },
+            _ => Policy::FailFast,
+        }
+    }
+
+    /// Whether a caller that has waited `waited_ms` for a slot may keep polling.
+    fn keeps_waiting(self, waited_ms: u64) -> bool {
+        matches!(self, Policy::Queue { max_wait_ms } if waited_ms < max_wait_ms)
+    }
+}
+
+/// All slots are taken and the policy doesn't allow waiting any longer.
+#[derive(Debug, Clone, Copy)]
+pub struct Full;
+
+/// Takes a slot for one Discord call. `Ok(None)` when there's no limit or the limiter can't be
+/// reached, the limiter is there to smooth traffic not to gate it.
+pub async fn acquire(env: &Env) -> Result<Option<Permit>, Full> {
+    let Some(limit) = env
+        .var("DISCORD_CONCURRENCY_LIMIT")
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+    else {
+        return Ok(None);
+    };
+
+    let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+    let policy = Policy::from_vars(
+        var("DISCORD_CONCURRENCY_POLICY").as_deref(),
+        var("DISCORD_CONCURRENCY_MAX_WAIT_MS").as_deref(),
+    );
+
+    let started = Date::now().as_millis();
+    loop {
+        match acquire_permit(env, CONCURRENCY_KEY, limit, LEASE_SECS).await {
+            Ok(Some(permit)) => return Ok(Some(permit)),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to acquire a Discord concurrency permit: {}", e);
+                return Ok(None);
+            }
+        }
+
+        let waited = Date::now().as_millis().saturating_sub(started);
+        if !policy.keeps_waiting(waited) {
+            warn!("Discord concurrency limit of {} reached", limit);
+            return Err(Full);
+        }
+        Delay::from(Duration::from_millis(QUEUE_POLL_MS)).await;
+    }
+}
+
+/// Gives back a slot from [`acquire`].
+pub async fn release(env: &Env, permit: Option<Permit>) {
+    if let Some(permit) = permit {
+        if let Err(e) = permit.release(env).await {
+            error!("Failed to release a Discord concurrency permit: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_queue_waits() {
+        assert_eq!(Policy::from_vars(None, None), Policy::FailFast);
+        assert_eq!(
+            Policy::from_vars(Some("fail"), Some("500")),
+            Policy::FailFast
+        );
+        assert_eq!(
+            Policy::from_vars(Some("queue"), None),
+            Policy::Queue { max_wait_ms: 2_000 }
+        );
+        assert_eq!(
+            Policy::from_vars(Some("queue"), Some("500")),
+            Policy::Queue { max_wait_ms: 500 }
+        );
+    }
+
+    #[test]
+    fn queue_gives_up_after_the_max_wait() {
+        assert!(!Policy::FailFast.keeps_waiting(0));
+
+        let queue = Policy::Queue { max_wait_ms: 500 };
+        assert!(queue.keeps_waiting(0));
+        assert!(queue.keeps_waiting(499));
+        assert!(!queue.keeps_waiting(500));
+    }
+}
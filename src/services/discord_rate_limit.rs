@@ -0,0 +1,286 @@
+//! Tracking of Discord's global rate limit.
+//!
+//! Discord flags global limits with `X-RateLimit-Global: true` on a 429. When that happens
+//! every Discord call is affected, so [`send`] notes the cooldown here, shares it with the other
+//! isolates through the `RATELIMITER` durable object and refuses further calls until it passes.
+//! Routes that don't talk to Discord are never held up by it.
+
+use std::cell::Cell;
+
+use serde::Deserialize;
+use tracing::{error, warn};
+use worker::{Date, Env};
+
+use crate::services::{discord_concurrency, rate_limit::cooldown, upstream::timed};
+
+const GLOBAL_COOLDOWN_KEY: &str = "discord:global";
+
+/// How often an isolate asks the durable object whether another isolate hit the global limit.
+const SYNC_INTERVAL_MS: u64 = 5_000;
+
+thread_local! {
+    /// Deadline (ms since epoch) of the global cooldown as known by this isolate.
+    static COOLDOWN_UNTIL: Cell<u64> = const { Cell::new(0) };
+    /// A cooldown noticed by this isolate that hasn't been shared with the others yet.
+    static PENDING: Cell<Option<u64>> = const { Cell::new(None) };
+    static LAST_SYNC: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Why [`send`] didn't get a response.
+#[derive(Debug)]
+pub enum SendError {
+    /// The global cooldown is running for this many more seconds, the request wasn't sent.
+    CoolingDown(u64),
+    /// Every concurrency slot is taken, see [`crate::services::discord_concurrency`]. The
+    /// request wasn't sent.
+    Busy,
+    /// The request was sent but failed.
+    Request(reqwest::Error),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::CoolingDown(secs) => {
+                write!(f, "Discord global rate limit active for {}s", secs)
+            }
+            SendError::Busy => write!(f, "Too many Discord requests in flight"),
+            SendError::Request(e) => write!(f, "Failed to send request to Discord API: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Sends a request to Discord unless the global cooldown is running or no concurrency slot is
+/// free, timing it as `endpoint` (see [`timed`]) and recording a global limit the response
+/// announces. The slot is held until the response headers arrive.
+pub async fn send(
+    env: &Env,
+    endpoint: &str,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, SendError> {
+    sync_cooldown(env).await;
+    let remaining = local_cooldown();
+    if remaining > 0 {
+        warn!(
+            endpoint,
+            "Discord global rate limit active for {}s", remaining
+        );
+        return Err(SendError::CoolingDown(remaining));
+    }
+
+    let permit = discord_concurrency::acquire(env)
+        .await
+        .map_err(|_| SendError::Busy)?;
+    let result = timed(endpoint, request.send()).await;
+    discord_concurrency::release(env, permit).await;
+    let response = result.map_err(SendError::Request)?;
+    note_response(&response);
+
+    if let Some(secs) = take_pending() {
+        if let Err(e) = cooldown(env, GLOBAL_COOLDOWN_KEY, Some(secs)).await {
+            error!("Failed to share the Discord global cooldown: {}", e);
+        }
+    }
+    Ok(response)
+}
+
+/// Picks up a cooldown another isolate shared, at most every [`SYNC_INTERVAL_MS`].
+async fn sync_cooldown(env: &Env) {
+    let now = Date::now().as_millis();
+    if now.saturating_sub(LAST_SYNC.with(|last| last.get())) < SYNC_INTERVAL_MS {
+        return;
+    }
+    LAST_SYNC.with(|last| last.set(now));
+    match cooldown(env, GLOBAL_COOLDOWN_KEY, None).await {
+        Ok(remaining) if remaining > 0 => set_local_cooldown(remaining),
+        Ok(_) => {}
+        Err(e) => error!("Failed to read the Discord global cooldown: {}", e),
+    }
+}
+
+/// Inspects a Discord response and records a global cooldown if it carries one.
+fn note_response(response: &reqwest::Response) {
+    let Some(retry_after) = global_retry_after(response.status(), response.headers()) else {
+        return;
+    };
+
+    set_local_cooldown(retry_after);
+    PENDING.with(|pending| pending.set(Some(retry_after)));
+}
+
+/// Seconds to hold off every Discord call for, if the response is a global 429.
+fn global_retry_after(
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+) -> Option<u64> {
+    if status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+
+    let is_global = headers
+        .get("x-ratelimit-global")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true"));
+    if !is_global {
+        return None;
+    }
+
+    let retry_after = headers
+        .get("retry-after")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(|secs| secs.ceil() as u64)
+        .unwrap_or(1)
+        .max(1);
+    Some(retry_after)
+}
+
+/// Remaining global cooldown known to this isolate, in seconds.
+fn local_cooldown() -> u64 {
+    local_cooldown_at(Date::now().as_millis())
+}
+
+fn local_cooldown_at(now: u64) -> u64 {
+    COOLDOWN_UNTIL
+        .with(|until| until.get())
+        .saturating_sub(now)
+        .div_ceil(1000)
+}
+
+fn set_local_cooldown(secs: u64) {
+    set_local_cooldown_at(secs, Date::now().as_millis());
+}
+
+/// Never shortens a running cooldown, a later and shorter 429 doesn't lift the first one.
+fn set_local_cooldown_at(secs: u64, now: u64) {
+    let until = now + secs * 1000;
+    COOLDOWN_UNTIL.with(|current| current.set(current.get().max(until)));
+}
+
+/// Takes the cooldown noticed since the last call, if any, so it can be shared.
+fn take_pending() -> Option<u64> {
+    PENDING.with(|pending| pending.take())
+}
+
+/// Body Discord sends with a 429.
+#[derive(Debug, Deserialize)]
+struct RateLimitBody {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+/// How long to wait after a 429 and whether the limit is global. The JSON body is preferred,
+/// then `Retry-After` / `X-RateLimit-Reset-After` and `X-RateLimit-Global`, then one second.
+pub async fn retry_hint(response: reqwest::Response) -> (f64, bool) {
+    let headers = response.headers();
+    let header_retry_after = ["retry-after", "x-ratelimit-reset-after"]
+        .iter()
+        .find_map(|name| {
+            headers
+                .get(*name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<f64>().ok())
+        });
+    let header_global = headers
+        .get("x-ratelimit-global")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true"));
+
+    let body = response
+        .text()
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_str::<RateLimitBody>(&body).ok());
+    match body {
+        Some(body) => (body.retry_after, body.global || header_global),
+        None => (header_retry_after.unwrap_or(1.0), header_global),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn only_global_429s_start_a_cooldown() {
+        let global = headers(&[("x-ratelimit-global", "true"), ("retry-after", "2.5")]);
+        assert_eq!(
+            global_retry_after(StatusCode::TOO_MANY_REQUESTS, &global),
+            Some(3)
+        );
+        assert_eq!(global_retry_after(StatusCode::OK, &global), None);
+
+        let route = headers(&[("retry-after", "2.5")]);
+        assert_eq!(
+            global_retry_after(StatusCode::TOO_MANY_REQUESTS, &route),
+            None
+        );
+    }
+
+    #[test]
+    fn a_missing_retry_after_waits_a_second() {
+        let global = headers(&[("x-ratelimit-global", "True")]);
+        assert_eq!(
+            global_retry_after(StatusCode::TOO_MANY_REQUESTS, &global),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn cooldown_runs_down_and_is_never_shortened() {
+        set_local_cooldown_at(5, 0);
+        assert_eq!(local_cooldown_at(1_000), 4);
+
+        set_local_cooldown_at(1, 1_000);
+        assert_eq!(local_cooldown_at(1_000), 4);
+        assert_eq!(local_cooldown_at(5_000), 0);
+    }
+
+    fn too_many_requests(
+        headers: &[(&'static str, &'static str)],
+        body: &'static str,
+    ) -> reqwest::Response {
+        let mut response = axum::http::Response::builder().status(StatusCode::TOO_MANY_REQUESTS);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        reqwest::Response::from(response.body(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn retry_hint_prefers_the_body() {
+        let response = too_many_requests(
+            &[("retry-after", "9")],
+            r#"{"message": "You are being rate limited.", "retry_after": 0.25, "global": false}"#,
+        );
+        assert_eq!(retry_hint(response).await, (0.25, false));
+
+        let response = too_many_requests(
+            &[("x-ratelimit-global", "true")],
+            r#"{"retry_after": 0.25}"#,
+        );
+        assert_eq!(retry_hint(response).await, (0.25, true));
+    }
+
+    #[tokio::test]
+    async fn retry_hint_falls_back_to_headers_then_a_second() {
+        let response = too_many_requests(&[("x-ratelimit-reset-after", " 1.5 ")], "");
+        assert_eq!(retry_hint(response).await, (1.5, false));
+
+        let response = too_many_requests(&[], "<html>Too Many Requests</html>");
+        assert_eq!(retry_hint(response).await, (1.0, false));
+    }
+}
@@ -0,0 +1,107 @@
+use cookie::Key;
+
+use crate::services::{
+    auth::{TokenTypeHint, REFRESH_TOKEN_PURPOSE},
+    crypto::unseal,
+    session::SESSION_TOKEN_PURPOSE,
+};
+
+/// What [`crate::services::database::Database::erase_user`] removed. The profile, sessions and
+/// stored refresh token are deleted, audit entries stay for the trail with the user replaced by
+/// [`crate::services::audit::ERASED_ACTOR`] or no subject.
+#[derive(Debug, Clone, Default)]
+pub struct ErasedUser {
+    /// Access tokens of the deleted sessions, sealed with [`SESSION_TOKEN_PURPOSE`].
+    /// Impersonation sessions borrow the token of another session, so one can repeat.
+    pub sealed_access_tokens: Vec<String>,
+    /// Sealed with [`REFRESH_TOKEN_PURPOSE`].
+    pub sealed_refresh_token: Option<String>,
+    pub profile_deleted: bool,
+    pub audit_entries_anonymized: u64,
+}
+
+impl ErasedUser {
+    /// The deleted tokens to revoke with Discord, each once. Tokens that no longer unseal, e.g.
+    /// after a key rotation, are skipped, nothing can use them anymore either.
+    pub fn tokens(&self, key: &Key) -> Vec<(String, TokenTypeHint)> {
+        let access_tokens = self
+            .sealed_access_tokens
+            .iter()
+            .map(|sealed| (SESSION_TOKEN_PURPOSE, sealed, TokenTypeHint::AccessToken));
+        let refresh_token = self
+            .sealed_refresh_token
+            .iter()
+            .map(|sealed| (REFRESH_TOKEN_PURPOSE, sealed, TokenTypeHint::RefreshToken));
+
+        let mut tokens: Vec<(String, TokenTypeHint)> = Vec::new();
+        for (purpose, sealed, hint) in access_tokens.chain(refresh_token) {
+            let Some(token) = unseal(key, purpose, sealed).filter(|t| !t.is_empty()) else {
+                continue;
+            };
+            if !tokens.iter().any(|(seen, _)| *seen == token) {
+                tokens.push((token, hint));
+            }
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::crypto::seal;
+
+    fn key() -> Key {
+        Key::derive_from(&[7u8; 32])
+    }
+
+    fn revoked(tokens: &[(String, TokenTypeHint)]) -> Vec<&str> {
+        tokens.iter().map(|(token, _)| token.as_str()).collect()
+    }
+
+    #[test]
+    fn every_deleted_token_is_revoked_once() {
+        let key = key();
+        let erased = ErasedUser {
+            sealed_access_tokens: vec![
+                seal(&key, SESSION_TOKEN_PURPOSE, "access-a"),
+                seal(&key, SESSION_TOKEN_PURPOSE, "access-b"),
+                // An impersonation session on the first one
+                seal(&key, SESSION_TOKEN_PURPOSE, "access-a"),
+            ],
+            sealed_refresh_token: Some(seal(&key, REFRESH_TOKEN_PURPOSE, "refresh")),
+            profile_deleted: true,
+            audit_entries_anonymized: 2,
+        };
+
+        let tokens = erased.tokens(&key);
+        assert_eq!(revoked(&tokens), ["access-a", "access-b", "refresh"]);
+        assert!(matches!(tokens[2].1, TokenTypeHint::RefreshToken));
+    }
+
+    #[test]
+    fn unreadable_tokens_are_skipped() {
+        let key = key();
+        let erased = ErasedUser {
+            sealed_access_tokens: vec![
+                "not sealed".to_string(),
+                seal(
+                    &Key::derive_from(&[8u8; 32]),
+                    SESSION_TOKEN_PURPOSE,
+                    "old-key",
+                ),
+                // Sealed for the other purpose, must not pass as an access token
+                seal(&key, REFRESH_TOKEN_PURPOSE, "refresh"),
+            ],
+            sealed_refresh_token: None,
+            ..ErasedUser::default()
+        };
+
+        assert!(erased.tokens(&key).is_empty());
+    }
+
+    #[test]
+    fn nothing_stored_revokes_nothing() {
+        assert!(ErasedUser::default().tokens(&key()).is_empty());
+    }
+}
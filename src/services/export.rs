@@ -0,0 +1,124 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::services::{
+    audit::AuditEntry,
+    session::{Session, SessionSummary},
+    user::StoredUser,
+};
+
+/// Everything stored about a user, for a data access request. Sections are always present,
+/// empty when there is nothing, and never carry tokens: sessions go out as
+/// [`SessionSummary`] and the stored refresh token only as whether there is one.
+///
+/// Guild settings aren't part of it, they are written by the bot and admins and record no
+/// user.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserExport {
+    pub exported_at: DateTime<Utc>,
+    /// The profile saved on the last login, `None` if the user never logged in.
+    pub profile: Option<StoredUser>,
+    pub sessions: Vec<SessionSummary>,
+    pub refresh_token_stored: bool,
+    /// Entries the user took or that were taken on them, oldest first.
+    pub audit_log: Vec<AuditEntry>,
+}
+
+impl UserExport {
+    pub fn new(
+        exported_at: DateTime<Utc>,
+        profile: Option<StoredUser>,
+        sessions: Vec<Session>,
+        refresh_token_stored: bool,
+        audit_log: Vec<AuditEntry>,
+    ) -> Self {
+        Self {
+            exported_at,
+            profile,
+            sessions: sessions.into_iter().map(SessionSummary::from).collect(),
+            refresh_token_stored,
+            audit_log,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::services::snowflake::Snowflake;
+
+    const SEALED_TOKEN: &str = "sealed-access-token";
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    fn export_json(export: UserExport) -> Value {
+        serde_json::to_value(export).unwrap()
+    }
+
+    #[test]
+    fn seeded_user_has_every_section() {
+        let user_id = Snowflake::new(80351110224678912);
+        let profile = StoredUser {
+            id: user_id,
+            username: "nelly".to_string(),
+            global_name: Some("Nelly".to_string()),
+            avatar: None,
+            email: Some("nelly@example.com".to_string()),
+            last_login_at: at(1_700_000_000),
+        };
+        let session = Session {
+            session_id: "stored-id".to_string(),
+            user_id,
+            discord_access_token: SEALED_TOKEN.to_string(),
+            token_expires_at: at(1_700_604_800),
+            created_at: at(1_700_000_000),
+            expires_at: at(1_702_592_000),
+            impersonated: false,
+        };
+        let entry = AuditEntry {
+            id: 1,
+            actor: "admin".to_string(),
+            action: "impersonate".to_string(),
+            subject_user_id: Some(user_id),
+            details: json!({ "request_id": "req" }),
+            created_at: at(1_700_000_100),
+        };
+
+        let export = export_json(UserExport::new(
+            at(1_700_001_000),
+            Some(profile),
+            vec![session],
+            true,
+            vec![entry],
+        ));
+
+        assert_eq!(export["profile"]["username"], "nelly");
+        assert_eq!(export["profile"]["email"], "nelly@example.com");
+        assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+        assert_eq!(export["sessions"][0]["id"], "stored-id");
+        assert_eq!(export["refresh_token_stored"], true);
+        assert_eq!(export["audit_log"][0]["action"], "impersonate");
+        assert!(!export.to_string().contains(SEALED_TOKEN));
+    }
+
+    #[test]
+    fn user_without_data_gets_empty_sections() {
+        let export = export_json(UserExport::new(
+            at(1_700_001_000),
+            None,
+            Vec::new(),
+            false,
+            Vec::new(),
+        ));
+
+        assert_eq!(export["profile"], Value::Null);
+        assert_eq!(export["sessions"], json!([]));
+        assert_eq!(export["refresh_token_stored"], false);
+        assert_eq!(export["audit_log"], json!([]));
+        assert!(export["exported_at"].is_string());
+    }
+}
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use sea_query::{
+    DynIden, Expr, Iden, IntoIden, Order, PostgresQueryBuilder, Query, SelectStatement, SimpleExpr,
+    Values,
+};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+use worker::Result;
+
+use crate::services::{
+    repo::{column, FromRow, Table},
+    snowflake::Snowflake,
+};
+
+/// The guilds table and its columns, see `migrations/0001_create_guilds.sql`. Every query
+/// against the table names its columns through this so they can't drift apart.
+#[derive(Debug, Clone, Copy)]
+pub enum Guilds {
+    Table,
+    Id,
+    Name,
+    Icon,
+    OwnerId,
+    JoinedAt,
+    MemberCount,
+}
+
+impl Iden for Guilds {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        let name = match self {
+            Self::Table => "guilds",
+            Self::Id => "id",
+            Self::Name => "name",
+            Self::Icon => "icon",
+            Self::OwnerId => "owner_id",
+            Self::JoinedAt => "joined_at",
+            Self::MemberCount => "member_count",
+        };
+        s.write_str(name).unwrap();
+    }
+}
+
+/// A guild the bot knows about, as stored in the guilds table.
+#[derive(Debug, Clone, Serialize)]
+pub struct Guild {
+    pub id: Snowflake,
+    pub name: String,
+    pub icon: Option<String>,
+    pub owner_id: Snowflake,
+    pub joined_at: DateTime<Utc>,
+    pub member_count: i32,
+}
+
+impl Guild {
+    /// The columns [`Guild::from_row`] reads, in order.
+    pub const COLUMNS: [Guilds; 6] = [
+        Guilds::Id,
+        Guilds::Name,
+        Guilds::Icon,
+        Guilds::OwnerId,
+        Guilds::JoinedAt,
+        Guilds::MemberCount,
+    ];
+
+    /// `SELECT <COLUMNS> FROM guilds ORDER BY id`, ready for a limit or a filter.
+    pub fn select() -> SelectStatement {
+        Query::select()
+            .columns(Self::COLUMNS)
+            .from(Guilds::Table)
+            .order_by(Guilds::Id, Order::Asc)
+            .to_owned()
+    }
+
+    /// `SELECT 1 FROM guilds WHERE id = $1 LIMIT 1`
+    pub fn exists(id: Snowflake) -> (String, Values) {
+        Query::select()
+            .expr(Expr::val(1))
+            .from(Guilds::Table)
+            .and_where(Expr::col(Guilds::Id).eq(id.get() as i64))
+            .limit(1)
+            .build(PostgresQueryBuilder)
+    }
+}
+
+impl FromRow for Guild {
+    /// Expects [`Guild::COLUMNS`] in that order, see [`Guild::select`].
+    fn from_row(row: &Row) -> Result<Self> {
+        let id: i64 = column(row, 0)?;
+        let owner_id: i64 = column(row, 3)?;
+        Ok(Self {
+            id: Snowflake::new(id as u64),
+            name: column(row, 1)?,
+            icon: column(row, 2)?,
+            owner_id: Snowflake::new(owner_id as u64),
+            joined_at: column(row, 4)?,
+            member_count: column(row, 5)?,
+        })
+    }
+}
+
+impl Table for Guild {
+    fn table() -> DynIden {
+        Guilds::Table.into_iden()
+    }
+    fn id_column() -> DynIden {
+        Guilds::Id.into_iden()
+    }
+    fn columns() -> Vec<DynIden> {
+        Self::COLUMNS.into_iter().map(IntoIden::into_iden).collect()
+    }
+    fn values(&self) -> Vec<SimpleExpr> {
+        vec![
+            (self.id.get() as i64).into(),
+            self.name.clone().into(),
+            self.icon.clone().into(),
+            (self.owner_id.get() as i64).into(),
+            self.joined_at.into(),
+            self.member_count.into(),
+        ]
+    }
+}
+
+/// `?limit=&offset=` for listing guilds.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct GuildListQuery {
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+impl GuildListQuery {
+    pub const DEFAULT_LIMIT: u64 = 50;
+    pub const MAX_LIMIT: u64 = 100;
+
+    /// The requested limit, defaulted and capped at [`GuildListQuery::MAX_LIMIT`].
+    pub fn limit(&self) -> u64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+    pub fn offset(&self) -> u64 {
+        self.offset.unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIGRATION: &str = include_str!("../../migrations/0001_create_guilds.sql");
+
+    #[test]
+    fn migration_defines_every_column() {
+        let defined: Vec<&str> = MIGRATION
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+        assert!(MIGRATION.contains(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (",
+            Guilds::Table.to_string()
+        )));
+        for column in Guild::COLUMNS {
+            let name = column.to_string();
+            assert!(defined.contains(&name.as_str()), "{} is missing", name);
+        }
+    }
+
+    #[test]
+    fn queries_name_the_table_through_the_iden() {
+        let sql = Guild::select().limit(10).to_string(PostgresQueryBuilder);
+        assert_eq!(
+            sql,
+            r#"SELECT "id", "name", "icon", "owner_id", "joined_at", "member_count" FROM "guilds" ORDER BY "id" ASC LIMIT 10"#
+        );
+
+        let (sql, values) = Guild::exists(Snowflake::new(81384788765712384));
+        assert_eq!(sql, r#"SELECT $1 FROM "guilds" WHERE "id" = $2 LIMIT $3"#);
+        assert_eq!(
+            values.0[1],
+            sea_query::Value::BigInt(Some(81384788765712384))
+        );
+    }
+}
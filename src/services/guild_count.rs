@@ -0,0 +1,88 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use sha2::{Digest, Sha256};
+use worker::Date;
+
+/// How long a user's guild count is reused, joining a server shows up after at most this.
+const GUILD_COUNT_TTL_MS: u64 = 60_000;
+/// Bounds the guild counts kept per isolate.
+const GUILD_COUNT_CAPACITY: usize = 1024;
+
+thread_local! {
+    /// Guild counts by [`token_key`], with when (ms since epoch) they stop being trusted.
+    static GUILD_COUNTS: RefCell<HashMap<String, (usize, u64)>> = RefCell::new(HashMap::new());
+}
+
+/// How many guilds the holder of `access_token` was in when their full list was last fetched.
+/// Kept for the isolate, not the request, so the dashboard's badge doesn't refetch every time.
+pub fn cached(access_token: &str) -> Option<usize> {
+    cached_at(access_token, Date::now().as_millis())
+}
+
+/// Records the length of a complete guild list for [`cached`].
+pub fn remember(access_token: &str, count: usize) {
+    remember_at(access_token, count, Date::now().as_millis());
+}
+
+fn cached_at(access_token: &str, now: u64) -> Option<usize> {
+    let key = token_key(access_token);
+    GUILD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        match counts.get(&key) {
+            Some(&(count, expires)) if expires > now => Some(count),
+            Some(_) => {
+                counts.remove(&key);
+                None
+            }
+            None => None,
+        }
+    })
+}
+
+fn remember_at(access_token: &str, count: usize, now: u64) {
+    GUILD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        if counts.len() >= GUILD_COUNT_CAPACITY {
+            counts.retain(|_, &mut (_, expires)| expires > now);
+            if counts.len() >= GUILD_COUNT_CAPACITY {
+                counts.clear();
+            }
+        }
+        counts.insert(token_key(access_token), (count, now + GUILD_COUNT_TTL_MS));
+    });
+}
+
+/// What a token's count is kept under, the token itself never sits in memory longer than the
+/// request.
+fn token_key(access_token: &str) -> String {
+    format!("{:x}", Sha256::digest(access_token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_is_reused_until_the_ttl() {
+        remember_at("token-a", 3, 0);
+        assert_eq!(cached_at("token-a", GUILD_COUNT_TTL_MS - 1), Some(3));
+        assert_eq!(cached_at("token-b", 0), None);
+        assert_eq!(cached_at("token-a", GUILD_COUNT_TTL_MS), None);
+    }
+
+    #[test]
+    fn tokens_are_not_kept_in_the_clear() {
+        remember_at("secret-token", 1, 0);
+        GUILD_COUNTS.with(|counts| {
+            assert!(!counts.borrow().contains_key("secret-token"));
+        });
+    }
+
+    #[test]
+    fn counts_stay_bounded() {
+        for i in 0..=GUILD_COUNT_CAPACITY {
+            remember_at(&format!("bounded-{}", i), i, 0);
+        }
+        GUILD_COUNTS.with(|counts| assert!(counts.borrow().len() <= GUILD_COUNT_CAPACITY));
+    }
+}
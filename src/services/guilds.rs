@@ -1,8 +1,11 @@
 use axum::response::IntoResponse;
 use serde::{Deserialize, Serialize};
-use worker::console_debug;
+use worker::Env;
 
-use crate::DISCORD_API_BASE_URL;
+use crate::{
+    services::{discord_rate_limit, json::response_json},
+    DISCORD_API_BASE_URL,
+};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PartialDiscordGuild {
@@ -29,11 +32,13 @@ impl IntoResponse for PartialDiscordGuild {
 
 pub struct DiscordGuildHTTP {
     client: reqwest::Client,
+    env: Env,
 }
 
 impl DiscordGuildHTTP {
-    pub fn new(authorization: String) -> Self {
+    pub fn new(env: &Env, authorization: String) -> Self {
         let client = reqwest::Client::builder()
+            .user_agent(crate::USER_AGENT)
             .default_headers({
                 let mut headers = reqwest::header::HeaderMap::new();
                 headers.insert(
@@ -45,28 +50,42 @@ impl DiscordGuildHTTP {
             .build()
             .unwrap();
 
-        Self { client }
+        Self {
+            client,
+            env: env.clone(),
+        }
+    }
+
+    /// A client acting as the user who owns `access_token`.
+    pub fn from_access_token(env: &Env, access_token: &str) -> Self {
+        Self::new(env, format!("Bearer {}", access_token))
     }
 
     pub async fn get_guilds(&self) -> Result<Vec<PartialDiscordGuild>, String> {
         let url = format!("{}/users/@me/guilds", DISCORD_API_BASE_URL);
-        let response = self
-            .client
-            .get(&url)
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
-        if response.status().is_success() {
-            let guilds = response
-                .json::<Vec<PartialDiscordGuild>>()
+        let response =
+            discord_rate_limit::send(&self.env, "GET /users/@me/guilds", self.client.get(&url))
                 .await
                 .map_err(|e| e.to_string())?;
-            Ok(guilds)
+        if response.status().is_success() {
+            response_json::<Vec<PartialDiscordGuild>>(response, "GET /users/@me/guilds").await
         } else {
             Err(format!("Failed to fetch guilds: {}", response.status()))
         }
     }
 
+    /// Whether the client's identity (normally the bot) can see `guild_id`. Discord answers
+    /// 403 or 404 for guilds the bot isn't in.
+    pub async fn has_guild(&self, guild_id: &str) -> Result<bool, String> {
+        let url = format!("{}/guilds/{}", DISCORD_API_BASE_URL, guild_id);
+        let response =
+            discord_rate_limit::send(&self.env, "GET /guilds/{id}", self.client.get(&url))
+                .await
+                .map_err(|e| e.to_string())?;
+        bot_presence(response.status())
+            .map_err(|status| format!("Failed to fetch guild {}: {}", guild_id, status))
+    }
+
     pub async fn get_mutual_guilds(&self, other: Self) -> Result<Vec<PartialDiscordGuild>, String> {
         let self_guilds = self.get_guilds().await?;
         let other_guilds = other.get_guilds().await?;
@@ -77,3 +96,37 @@ impl DiscordGuildHTTP {
         Ok(mutual_guilds)
     }
 }
+
+/// Reads bot presence off the status of `GET /guilds/{id}`, any other status is handed back.
+fn bot_presence(status: reqwest::StatusCode) -> Result<bool, reqwest::StatusCode> {
+    match status {
+        status if status.is_success() => Ok(true),
+        reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::NOT_FOUND => Ok(false),
+        status => Err(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn guilds_the_bot_cannot_see_are_absent() {
+        assert_eq!(bot_presence(StatusCode::OK), Ok(true));
+        assert_eq!(bot_presence(StatusCode::FORBIDDEN), Ok(false));
+        assert_eq!(bot_presence(StatusCode::NOT_FOUND), Ok(false));
+    }
+
+    #[test]
+    fn other_failures_are_not_read_as_absence() {
+        for status in [
+            StatusCode::UNAUTHORIZED,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::BAD_GATEWAY,
+        ] {
+            assert_eq!(bot_presence(status), Err(status));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use worker::{Env, Method, Request, RequestInit, Result};
+
+pub use crate::durables::idempotency::{BeginResponse, StoredResponse};
+
+/// One `Idempotency-Key` of one caller on the `IDEMPOTENCY` durable object.
+///
+/// Every scoped key gets its own object, so unrelated keys never contend with each other.
+pub struct IdempotencyKey<'a> {
+    env: &'a Env,
+    /// `{scope}:{key}`, the name of the durable object.
+    name: String,
+    fingerprint: String,
+}
+
+impl<'a> IdempotencyKey<'a> {
+    pub fn new(env: &'a Env, scope: &str, key: &str, fingerprint: String) -> Self {
+        Self {
+            env,
+            name: format!("{}:{}", scope, key),
+            fingerprint,
+        }
+    }
+
+    /// Claims the key, or reports why it can't be claimed.
+    pub async fn begin(&self) -> Result<BeginResponse> {
+        let mut response = self.fetch("begin", Method::Get, None).await?;
+        response.json::<BeginResponse>().await
+    }
+
+    /// Stores the response for replays during the next `ttl_secs`.
+    pub async fn complete(&self, response: &StoredResponse, ttl_secs: u64) -> Result<()> {
+        let body = serde_json::to_string(response)?;
+        let path = format!("complete?ttl={}", ttl_secs);
+        self.fetch(&path, Method::Post, Some(body)).await?;
+        Ok(())
+    }
+
+    /// Releases the claim without a response, so a retry runs the request again.
+    pub async fn abort(&self) -> Result<()> {
+        self.fetch("abort", Method::Get, None).await?;
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        path: &str,
+        method: Method,
+        body: Option<String>,
+    ) -> Result<worker::Response> {
+        let namespace = self.env.durable_object("IDEMPOTENCY")?;
+        let stub = namespace.id_from_name(&self.name)?.get_stub()?;
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "https://idempotency/{}{}fingerprint={}",
+            path,
+            separator,
+            urlencoding::encode(&self.fingerprint)
+        );
+        let mut init = RequestInit::new();
+        init.with_method(method);
+        if let Some(body) = body {
+            init.with_body(Some(body.into()));
+        }
+        stub.fetch_with_request(Request::new_with_init(&url, &init)?)
+            .await
+    }
+}
@@ -0,0 +1,111 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use worker::console_error;
+
+/// Reads a Discord response body as `T`, naming the offending field when it doesn't match.
+///
+/// `what` describes the payload in logs and errors, e.g. `GET /users/@me/guilds`.
+pub async fn response_json<T: DeserializeOwned>(
+    response: reqwest::Response,
+    what: &str,
+) -> Result<T, String> {
+    let bytes = response.bytes().await.map_err(|e| {
+        console_error!("Failed to read {} response: {}", what, e);
+        format!("Failed to read {} response", what)
+    })?;
+    decode_json(&bytes, what)
+}
+
+/// Parses into [`Value`] first so a body that isn't JSON at all is told apart from one whose
+/// shape changed, then reports the path of the first mismatching field.
+pub fn decode_json<T: DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T, String> {
+    decode(bytes).map_err(|e| match e {
+        DecodeError::NotJson(e) => {
+            console_error!("{} returned invalid JSON: {}", what, e);
+            format!("{} returned invalid JSON", what)
+        }
+        DecodeError::Mismatch { path, error } => {
+            console_error!("Unexpected {} payload at `{}`: {}", what, path, error);
+            format!("Unexpected {} payload at `{}`", what, path)
+        }
+    })
+}
+
+/// Why [`decode`] failed.
+#[derive(Debug)]
+enum DecodeError {
+    NotJson(serde_json::Error),
+    /// `path` is the field that didn't match, e.g. `[1].owner`.
+    Mismatch {
+        path: String,
+        error: serde_json::Error,
+    },
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let value: Value = serde_json::from_slice(bytes).map_err(DecodeError::NotJson)?;
+    serde_path_to_error::deserialize(value).map_err(|e| DecodeError::Mismatch {
+        path: e.path().to_string(),
+        error: e.into_inner(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Guild {
+        id: String,
+        name: String,
+        owner: bool,
+    }
+
+    fn mismatch_path<T: DeserializeOwned + std::fmt::Debug>(json: &str) -> String {
+        match decode::<T>(json.as_bytes()).unwrap_err() {
+            DecodeError::Mismatch { path, .. } => path,
+            e => panic!("Expected a mismatch, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn decodes_a_matching_payload() {
+        let guild: Guild = decode(br#"{ "id": "1", "name": "a", "owner": true }"#).unwrap();
+        assert_eq!(
+            (guild.id.as_str(), guild.name.as_str(), guild.owner),
+            ("1", "a", true)
+        );
+    }
+
+    #[test]
+    fn names_the_wrong_typed_field() {
+        let path = mismatch_path::<Vec<Guild>>(
+            r#"[
+                { "id": "1", "name": "a", "owner": true },
+                { "id": "2", "name": "b", "owner": "yes" }
+            ]"#,
+        );
+        assert_eq!(path, "[1].owner");
+    }
+
+    #[test]
+    fn points_at_the_object_missing_a_field() {
+        let path = mismatch_path::<Vec<Guild>>(
+            r#"[
+                { "id": "1", "name": "a", "owner": true },
+                { "id": "2", "owner": true }
+            ]"#,
+        );
+        assert_eq!(path, "[1]");
+    }
+
+    #[test]
+    fn tells_invalid_json_apart() {
+        assert!(matches!(
+            decode::<Guild>(b"<html>502</html>"),
+            Err(DecodeError::NotJson(_))
+        ));
+    }
+}
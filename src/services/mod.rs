@@ -1,19 +1,26 @@
-use worker::{console_error, Env};
-
+pub mod audit;
 pub mod auth;
+pub mod bot_presence;
 pub mod cookie;
+pub mod crypto;
 pub mod database;
+pub mod discord_concurrency;
+pub mod discord_rate_limit;
+pub mod erasure;
+pub mod export;
+pub mod guild;
+pub mod guild_count;
 pub mod guilds;
+pub mod idempotency;
+pub mod json;
+pub mod pagination;
+pub mod permissions;
+pub mod rate_limit;
+pub mod repo;
+pub mod session;
+pub mod settings;
+pub mod snowflake;
+pub mod upstream;
 pub mod user;
-
-pub fn get_discord_env(env: &Env) -> Result<(String, String), String> {
-    let Ok(client_id) = env.var("DISCORD_CLIENT_ID").map(|s| s.to_string()) else {
-        console_error!("DISCORD_CLIENT_ID not set");
-        return Err("DISCORD_CLIENT_ID not set".into());
-    };
-    let Ok(client_secret) = env.secret("DISCORD_CLIENT_SECRET").map(|s| s.to_string()) else {
-        console_error!("DISCORD_CLIENT_SECRET not set");
-        return Err("DISCORD_CLIENT_SECRET not set".into());
-    };
-    Ok((client_id, client_secret))
-}
+pub mod user_cache;
+pub mod validation;
@@ -0,0 +1,197 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sea_query::{Expr, IntoColumnRef, Order, SelectStatement};
+use serde::{Deserialize, Serialize};
+
+/// Position after the last row of a page, handed to clients as an opaque string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub sort_key: i64,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from rows fetched with [`keyset_paginate`], which asks for one extra row
+    /// to find out whether there is a next page.
+    pub fn from_rows(mut rows: Vec<T>, limit: u64, cursor_of: impl Fn(&T) -> Cursor) -> Self {
+        let has_more = rows.len() as u64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = if has_more {
+            rows.last().map(|row| cursor_of(row).encode())
+        } else {
+            None
+        };
+        Self {
+            items: rows,
+            next_cursor,
+        }
+    }
+}
+
+/// Applies keyset pagination to `query`, newest first:
+/// `WHERE (sort_key, id) < ($1, $2) ORDER BY sort_key DESC, id DESC LIMIT limit + 1`.
+///
+/// Unlike `OFFSET`, this stays cheap on large tables as long as `(sort_key, id)` is indexed.
+pub fn keyset_paginate<S, I>(
+    query: &mut SelectStatement,
+    sort_column: S,
+    id_column: I,
+    cursor: Option<&Cursor>,
+    limit: u64,
+) where
+    S: IntoColumnRef + Clone,
+    I: IntoColumnRef + Clone,
+{
+    if let Some(cursor) = cursor {
+        query.and_where(
+            Expr::tuple([
+                Expr::col(sort_column.clone()).into(),
+                Expr::col(id_column.clone()).into(),
+            ])
+            .lt(Expr::tuple([
+                Expr::val(cursor.sort_key).into(),
+                Expr::val(cursor.id).into(),
+            ])),
+        );
+    }
+
+    query
+        .order_by(sort_column, Order::Desc)
+        .order_by(id_column, Order::Desc)
+        .limit(limit + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_query::{Alias, PostgresQueryBuilder, Query};
+
+    use super::*;
+
+    /// Rows of `(sort_key, id)` with repeated sort keys, so ties have to be broken by id.
+    fn seeded(rows: i64) -> Vec<(i64, i64)> {
+        (1..=rows).map(|id| (id / 3, id)).collect()
+    }
+
+    /// What the statement from [`keyset_paginate`] selects from `table`.
+    fn fetch(table: &[(i64, i64)], cursor: Option<&Cursor>, limit: u64) -> Vec<(i64, i64)> {
+        let mut rows: Vec<(i64, i64)> = table
+            .iter()
+            .copied()
+            .filter(|row| cursor.is_none_or(|c| *row < (c.sort_key, c.id)))
+            .collect();
+        rows.sort_by(|a, b| b.cmp(a));
+        rows.truncate(limit as usize + 1);
+        rows
+    }
+
+    fn cursor_of(row: &(i64, i64)) -> Cursor {
+        Cursor {
+            sort_key: row.0,
+            id: row.1,
+        }
+    }
+
+    /// Follows the cursors from the first page to the last, returning every page.
+    fn page_through(table: &[(i64, i64)], limit: u64) -> Vec<Page<(i64, i64)>> {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = Page::from_rows(fetch(table, cursor.as_ref(), limit), limit, cursor_of);
+            cursor = page
+                .next_cursor
+                .as_deref()
+                .map(|c| Cursor::decode(c).unwrap());
+            pages.push(page);
+            if cursor.is_none() {
+                return pages;
+            }
+        }
+    }
+
+    #[test]
+    fn builds_the_keyset_clause() {
+        let mut query = Query::select();
+        query.column(Alias::new("id")).from(Alias::new("audit_log"));
+        let cursor = Cursor {
+            sort_key: 7,
+            id: 42,
+        };
+        keyset_paginate(
+            &mut query,
+            Alias::new("created_at"),
+            Alias::new("id"),
+            Some(&cursor),
+            10,
+        );
+        assert_eq!(
+            query.to_string(PostgresQueryBuilder),
+            concat!(
+                r#"SELECT "id" FROM "audit_log" WHERE ("created_at", "id") < (7, 42) "#,
+                r#"ORDER BY "created_at" DESC, "id" DESC LIMIT 11"#,
+            )
+        );
+    }
+
+    #[test]
+    fn pages_have_no_gaps_or_duplicates() {
+        let table = seeded(23);
+        let pages = page_through(&table, 5);
+        assert_eq!(pages.len(), 5);
+        assert!(pages[..4].iter().all(|page| page.items.len() == 5));
+        assert_eq!(pages[4].items.len(), 3);
+
+        let seen: Vec<(i64, i64)> = pages.into_iter().flat_map(|page| page.items).collect();
+        let mut expected = table;
+        expected.sort_by(|a, b| b.cmp(a));
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn a_full_last_page_has_no_cursor() {
+        let pages = page_through(&seeded(10), 5);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].items.len(), 5);
+        assert_eq!(pages[1].next_cursor, None);
+    }
+
+    #[test]
+    fn past_the_last_row_is_an_empty_page() {
+        let table = seeded(4);
+        let oldest = Cursor { sort_key: 0, id: 1 };
+        let page = Page::from_rows(fetch(&table, Some(&oldest), 5), 5, cursor_of);
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_cursor, None);
+
+        let pages = page_through(&[], 5);
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].items.is_empty());
+    }
+
+    #[test]
+    fn cursor_round_trips_and_rejects_garbage() {
+        let cursor = Cursor {
+            sort_key: 1_700_000_000_000,
+            id: -3,
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("{}")), None);
+    }
+}
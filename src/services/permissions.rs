@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Permissions(u64);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    pub const ALL: Permissions = Permissions(u64::MAX);
+
+    pub const CREATE_INSTANT_INVITE: Permissions = Permissions(1 << 0);
+    pub const KICK_MEMBERS: Permissions = Permissions(1 << 1);
+    pub const BAN_MEMBERS: Permissions = Permissions(1 << 2);
+    pub const ADMINISTRATOR: Permissions = Permissions(1 << 3);
+    pub const MANAGE_CHANNELS: Permissions = Permissions(1 << 4);
+    pub const MANAGE_GUILD: Permissions = Permissions(1 << 5);
+    pub const VIEW_CHANNEL: Permissions = Permissions(1 << 10);
+    pub const SEND_MESSAGES: Permissions = Permissions(1 << 11);
+    pub const MANAGE_MESSAGES: Permissions = Permissions(1 << 13);
+    pub const MANAGE_ROLES: Permissions = Permissions(1 << 28);
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Permissions(bits)
+    }
+
+    pub fn contains(&self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Permissions) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Permissions) {
+        self.0 &= !other.0;
+    }
+}
+
+impl std::str::FromStr for Permissions {
+    type Err = std::num::ParseIntError;
+
+    // Discord serializes permission bitsets as strings since they overflow JS numbers
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u64>().map(Permissions)
+    }
+}
+
+impl std::fmt::Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DiscordRole {
+    pub id: String,
+    pub name: String,
+    pub permissions: String,
+}
+
+impl DiscordRole {
+    pub fn permissions(&self) -> Permissions {
+        self.permissions.parse().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u8", into = "u8")]
+pub enum OverwriteType {
+    Role,
+    Member,
+}
+
+impl From<u8> for OverwriteType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => OverwriteType::Member,
+            _ => OverwriteType::Role,
+        }
+    }
+}
+
+impl From<OverwriteType> for u8 {
+    fn from(value: OverwriteType) -> Self {
+        match value {
+            OverwriteType::Role => 0,
+            OverwriteType::Member => 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PermissionOverwrite {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: OverwriteType,
+    pub allow: String,
+    pub deny: String,
+}
+
+impl PermissionOverwrite {
+    fn allow(&self) -> Permissions {
+        self.allow.parse().unwrap_or_default()
+    }
+
+    fn deny(&self) -> Permissions {
+        self.deny.parse().unwrap_or_default()
+    }
+}
+
+/// Computes a member's effective permissions in a channel following Discord's documented
+/// algorithm: `@everyone` base, role union, administrator short-circuit, then the `@everyone`,
+/// role and member channel overwrites in that order.
+///
+/// Guild ownership is not considered here, callers should check `owner_id` themselves.
+pub fn compute_permissions(
+    member_id: &str,
+    member_roles: &[String],
+    guild_roles: &[DiscordRole],
+    everyone_role: &DiscordRole,
+    channel_overwrites: &[PermissionOverwrite],
+) -> Permissions {
+    let mut permissions = everyone_role.permissions();
+    for role in guild_roles
+        .iter()
+        .filter(|role| member_roles.contains(&role.id))
+    {
+        permissions.insert(role.permissions());
+    }
+
+    if permissions.contains(Permissions::ADMINISTRATOR) {
+        return Permissions::ALL;
+    }
+
+    if let Some(overwrite) = channel_overwrites
+        .iter()
+        .find(|o| o.kind == OverwriteType::Role && o.id == everyone_role.id)
+    {
+        permissions.remove(overwrite.deny());
+        permissions.insert(overwrite.allow());
+    }
+
+    let mut allow = Permissions::NONE;
+    let mut deny = Permissions::NONE;
+    for overwrite in channel_overwrites
+        .iter()
+        .filter(|o| o.kind == OverwriteType::Role && member_roles.contains(&o.id))
+    {
+        allow.insert(overwrite.allow());
+        deny.insert(overwrite.deny());
+    }
+    permissions.remove(deny);
+    permissions.insert(allow);
+
+    if let Some(overwrite) = channel_overwrites
+        .iter()
+        .find(|o| o.kind == OverwriteType::Member && o.id == member_id)
+    {
+        permissions.remove(overwrite.deny());
+        permissions.insert(overwrite.allow());
+    }
+
+    permissions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: &str = "100";
+    const MEMBER: &str = "200";
+    const MODS: &str = "300";
+
+    fn role(id: &str, permissions: Permissions) -> DiscordRole {
+        DiscordRole {
+            id: id.to_string(),
+            name: id.to_string(),
+            permissions: permissions.to_string(),
+        }
+    }
+
+    fn overwrite(
+        id: &str,
+        kind: OverwriteType,
+        allow: Permissions,
+        deny: Permissions,
+    ) -> PermissionOverwrite {
+        PermissionOverwrite {
+            id: id.to_string(),
+            kind,
+            allow: allow.to_string(),
+            deny: deny.to_string(),
+        }
+    }
+
+    fn everyone() -> DiscordRole {
+        // The @everyone role shares the guild's id
+        role(GUILD, Permissions::VIEW_CHANNEL)
+    }
+
+    fn send_and_view() -> Permissions {
+        let mut permissions = Permissions::VIEW_CHANNEL;
+        permissions.insert(Permissions::SEND_MESSAGES);
+        permissions
+    }
+
+    #[test]
+    fn roles_add_to_everyone() {
+        let guild_roles = [role(MODS, Permissions::MANAGE_MESSAGES)];
+        let permissions =
+            compute_permissions(MEMBER, &[MODS.to_string()], &guild_roles, &everyone(), &[]);
+        assert!(permissions.contains(Permissions::VIEW_CHANNEL));
+        assert!(permissions.contains(Permissions::MANAGE_MESSAGES));
+        assert!(!permissions.contains(Permissions::SEND_MESSAGES));
+    }
+
+    #[test]
+    fn administrator_overrides_every_overwrite() {
+        let guild_roles = [role(MODS, Permissions::ADMINISTRATOR)];
+        let overwrites = [
+            overwrite(
+                GUILD,
+                OverwriteType::Role,
+                Permissions::NONE,
+                Permissions::ALL,
+            ),
+            overwrite(
+                MEMBER,
+                OverwriteType::Member,
+                Permissions::NONE,
+                Permissions::ALL,
+            ),
+        ];
+        let permissions = compute_permissions(
+            MEMBER,
+            &[MODS.to_string()],
+            &guild_roles,
+            &everyone(),
+            &overwrites,
+        );
+        assert_eq!(permissions, Permissions::ALL);
+    }
+
+    #[test]
+    fn deny_overwrite_removes_a_base_permission() {
+        let overwrites = [overwrite(
+            GUILD,
+            OverwriteType::Role,
+            Permissions::NONE,
+            Permissions::VIEW_CHANNEL,
+        )];
+        let permissions = compute_permissions(MEMBER, &[], &[], &everyone(), &overwrites);
+        assert!(!permissions.contains(Permissions::VIEW_CHANNEL));
+    }
+
+    #[test]
+    fn role_allow_beats_role_deny_and_member_beats_both() {
+        let guild_roles = [
+            role(MODS, Permissions::NONE),
+            role("301", Permissions::NONE),
+        ];
+        let member_roles = [MODS.to_string(), "301".to_string()];
+        let role_overwrites = [
+            overwrite(
+                MODS,
+                OverwriteType::Role,
+                Permissions::NONE,
+                send_and_view(),
+            ),
+            overwrite(
+                "301",
+                OverwriteType::Role,
+                Permissions::SEND_MESSAGES,
+                Permissions::NONE,
+            ),
+        ];
+        let permissions = compute_permissions(
+            MEMBER,
+            &member_roles,
+            &guild_roles,
+            &everyone(),
+            &role_overwrites,
+        );
+        // Role denies and allows are merged before applying, the allow wins
+        assert!(permissions.contains(Permissions::SEND_MESSAGES));
+        assert!(!permissions.contains(Permissions::VIEW_CHANNEL));
+
+        let mut overwrites = role_overwrites.to_vec();
+        overwrites.push(overwrite(
+            MEMBER,
+            OverwriteType::Member,
+            Permissions::VIEW_CHANNEL,
+            Permissions::SEND_MESSAGES,
+        ));
+        let permissions = compute_permissions(
+            MEMBER,
+            &member_roles,
+            &guild_roles,
+            &everyone(),
+            &overwrites,
+        );
+        assert!(permissions.contains(Permissions::VIEW_CHANNEL));
+        assert!(!permissions.contains(Permissions::SEND_MESSAGES));
+    }
+
+    #[test]
+    fn overwrites_for_other_roles_and_members_are_ignored() {
+        let overwrites = [
+            overwrite(
+                "999",
+                OverwriteType::Role,
+                Permissions::NONE,
+                Permissions::VIEW_CHANNEL,
+            ),
+            overwrite(
+                "998",
+                OverwriteType::Member,
+                Permissions::NONE,
+                Permissions::VIEW_CHANNEL,
+            ),
+        ];
+        let permissions = compute_permissions(MEMBER, &[], &[], &everyone(), &overwrites);
+        assert_eq!(permissions, Permissions::VIEW_CHANNEL);
+    }
+}
@@ -0,0 +1,118 @@
+use serde::Deserialize;
+use worker::{Env, Result};
+
+/// Token bucket settings: bursts of up to `limit` requests, refilled evenly so an empty bucket is
+/// full again after `window_secs`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitOutcome {
+    Allowed,
+    Limited { retry_after: u64 },
+}
+
+#[derive(Deserialize)]
+struct AcquireResponse {
+    lease: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CooldownResponse {
+    remaining: u64,
+}
+
+#[derive(Deserialize)]
+struct HitResponse {
+    allowed: bool,
+    retry_after: u64,
+}
+
+/// Records a hit for `key` on the `RATELIMITER` durable object.
+///
+/// Every key gets its own object so unrelated callers never contend with each other.
+pub async fn check_rate_limit(
+    env: &Env,
+    key: &str,
+    rate_limit: RateLimit,
+) -> Result<RateLimitOutcome> {
+    let namespace = env.durable_object("RATELIMITER")?;
+    let stub = namespace.id_from_name(key)?.get_stub()?;
+    let url = format!(
+        "https://rate-limiter/hit?limit={}&window={}",
+        rate_limit.limit, rate_limit.window_secs
+    );
+
+    let mut response = stub.fetch_with_str(&url).await?;
+    let hit = response.json::<HitResponse>().await?;
+    if hit.allowed {
+        Ok(RateLimitOutcome::Allowed)
+    } else {
+        Ok(RateLimitOutcome::Limited {
+            retry_after: hit.retry_after,
+        })
+    }
+}
+
+/// Reads the cooldown stored for `key`, optionally extending it by `set` seconds first.
+/// Returns the remaining cooldown in seconds.
+pub async fn cooldown(env: &Env, key: &str, set: Option<u64>) -> Result<u64> {
+    let namespace = env.durable_object("RATELIMITER")?;
+    let stub = namespace.id_from_name(key)?.get_stub()?;
+    let url = match set {
+        Some(secs) => format!("https://rate-limiter/cooldown?set={}", secs),
+        None => "https://rate-limiter/cooldown".to_string(),
+    };
+
+    let mut response = stub.fetch_with_str(&url).await?;
+    Ok(response.json::<CooldownResponse>().await?.remaining)
+}
+
+/// A leased slot from [`acquire_permit`], give it back with [`Permit::release`].
+#[derive(Debug)]
+pub struct Permit {
+    key: String,
+    lease: String,
+}
+
+impl Permit {
+    pub async fn release(self, env: &Env) -> Result<()> {
+        let namespace = env.durable_object("RATELIMITER")?;
+        let stub = namespace.id_from_name(&self.key)?.get_stub()?;
+        let url = format!(
+            "https://rate-limiter/release?lease={}",
+            urlencoding::encode(&self.lease)
+        );
+        stub.fetch_with_str(&url).await?;
+        Ok(())
+    }
+}
+
+/// Tries to take one of `max` slots for `key`. The slot is released automatically after
+/// `lease_secs` in case the holder never gives it back.
+pub async fn acquire_permit(
+    env: &Env,
+    key: &str,
+    max: usize,
+    lease_secs: u64,
+) -> Result<Option<Permit>> {
+    let namespace = env.durable_object("RATELIMITER")?;
+    let stub = namespace.id_from_name(key)?.get_stub()?;
+    let url = format!(
+        "https://rate-limiter/acquire?max={}&lease={}",
+        max, lease_secs
+    );
+
+    let mut response = stub.fetch_with_str(&url).await?;
+    Ok(response
+        .json::<AcquireResponse>()
+        .await?
+        .lease
+        .map(|lease| Permit {
+            key: key.to_string(),
+            lease,
+        }))
+}
@@ -0,0 +1,103 @@
+use std::fmt::Display;
+
+use sea_query::{DynIden, Expr, PostgresQueryBuilder, Query, SimpleExpr, Value, Values};
+use tokio_postgres::{row::RowIndex, types::FromSql, Row};
+use worker::{Error, Result};
+
+use crate::services::database::Database;
+
+/// Builds a model from a row whose columns come in the order the model documents, usually
+/// [`Table::columns`].
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+/// Reads column `index` (a position or a name) as `T`. When the column is missing or its type
+/// doesn't fit, the error names the column and the Rust type (the underlying error adds the
+/// Postgres type) instead of panicking like `Row::get`.
+pub fn column<'a, T, I>(row: &'a Row, index: I) -> Result<T>
+where
+    T: FromSql<'a>,
+    I: RowIndex + Display + Copy,
+{
+    row.try_get(index).map_err(|e| {
+        Error::RustError(format!(
+            "Can't read column {} as {}: {}",
+            index,
+            std::any::type_name::<T>(),
+            e
+        ))
+    })
+}
+
+/// Like [`column`] for columns a query may leave out, `None` when the row doesn't have it. A
+/// column that is there but `NULL` is `None` as well.
+pub fn optional_column<'a, T>(row: &'a Row, name: &str) -> Result<Option<T>>
+where
+    T: FromSql<'a>,
+{
+    if row.columns().iter().any(|c| c.name() == name) {
+        column::<Option<T>, _>(row, name)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Runs `statement` and maps every row, e.g. `query_as::<DiscordUser>(..)`.
+pub async fn query_as<T: FromRow>(
+    database: &Database,
+    statement: (String, Values),
+) -> Result<Vec<T>> {
+    database
+        .query(statement)
+        .await?
+        .iter()
+        .map(T::from_row)
+        .collect()
+}
+
+/// A model that maps one to one onto a table with a single column primary key, which is all
+/// [`find_by_id`], [`insert`] and [`delete_by_id`] need to know about it.
+pub trait Table: FromRow {
+    fn table() -> DynIden;
+    fn id_column() -> DynIden;
+    /// Every column, in the order [`FromRow::from_row`] reads and [`Table::values`] writes them.
+    fn columns() -> Vec<DynIden>;
+    fn values(&self) -> Vec<SimpleExpr>;
+}
+
+/// The row with primary key `id`, if there is one.
+pub async fn find_by_id<T: Table>(database: &Database, id: impl Into<Value>) -> Result<Option<T>> {
+    let statement = Query::select()
+        .columns(T::columns())
+        .from(T::table())
+        .and_where(Expr::col(T::id_column()).eq(id.into()))
+        .limit(1)
+        .build(PostgresQueryBuilder);
+    database
+        .query(statement)
+        .await?
+        .first()
+        .map(T::from_row)
+        .transpose()
+}
+
+/// Inserts `row` as is, a duplicate primary key is an error. Returns the rows inserted.
+pub async fn insert<T: Table>(database: &Database, row: &T) -> Result<u64> {
+    let statement = Query::insert()
+        .into_table(T::table())
+        .columns(T::columns())
+        .values(row.values())
+        .map_err(|e| Error::RustError(format!("Invalid insert: {}", e)))?
+        .build(PostgresQueryBuilder);
+    database.execute(statement).await
+}
+
+/// Deletes the row with primary key `id`, returns whether there was one.
+pub async fn delete_by_id<T: Table>(database: &Database, id: impl Into<Value>) -> Result<bool> {
+    let statement = Query::delete()
+        .from_table(T::table())
+        .and_where(Expr::col(T::id_column()).eq(id.into()))
+        .build(PostgresQueryBuilder);
+    Ok(database.execute(statement).await? > 0)
+}
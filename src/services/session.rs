@@ -0,0 +1,158 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use sea_query::{DynIden, Iden, IntoIden, SimpleExpr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio_postgres::Row;
+use worker::{Error, Result};
+
+use crate::services::{
+    repo::{column, FromRow, Table},
+    snowflake::Snowflake,
+};
+
+/// How long a login lasts. The access token inside is refreshed as often as needed in that
+/// time, the session itself is never extended.
+pub const SESSION_TTL_DAYS: i64 = 30;
+
+/// How long an impersonation session lasts, it's for a support look around and not renewed.
+pub const IMPERSONATION_TTL_MINUTES: i64 = 15;
+
+/// Purpose the session's access token is sealed under, see [`crate::services::crypto::seal`].
+pub const SESSION_TOKEN_PURPOSE: &str = "session_access_token";
+
+/// The sessions table and its columns, see `migrations/0002_create_sessions.sql` and
+/// `migrations/0007_add_session_impersonation.sql`.
+#[derive(Debug, Clone, Copy)]
+pub enum Sessions {
+    Table,
+    SessionId,
+    UserId,
+    DiscordAccessToken,
+    TokenExpiresAt,
+    CreatedAt,
+    ExpiresAt,
+    Impersonated,
+}
+
+impl Iden for Sessions {
+    fn unquoted(&self, s: &mut dyn std::fmt::Write) {
+        let name = match self {
+            Self::Table => "sessions",
+            Self::SessionId => "session_id",
+            Self::UserId => "user_id",
+            Self::DiscordAccessToken => "discord_access_token",
+            Self::TokenExpiresAt => "token_expires_at",
+            Self::CreatedAt => "created_at",
+            Self::ExpiresAt => "expires_at",
+            Self::Impersonated => "impersonated",
+        };
+        s.write_str(name).unwrap();
+    }
+}
+
+/// A logged in browser. The browser only holds the opaque id, the Discord token stays here.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// [`hash_session_id`] of the cookie value, a leaked table doesn't hand out sessions.
+    pub session_id: String,
+    pub user_id: Snowflake,
+    /// Sealed with [`SESSION_TOKEN_PURPOSE`].
+    pub discord_access_token: String,
+    pub token_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Opened by an admin to see the dashboard as this user. Only reads go through and the
+    /// token is never refreshed or revoked through it, it's the user's own.
+    pub impersonated: bool,
+}
+
+impl Session {
+    /// The columns [`Session::from_row`] reads, in order.
+    pub const COLUMNS: [Sessions; 7] = [
+        Sessions::SessionId,
+        Sessions::UserId,
+        Sessions::DiscordAccessToken,
+        Sessions::TokenExpiresAt,
+        Sessions::CreatedAt,
+        Sessions::ExpiresAt,
+        Sessions::Impersonated,
+    ];
+}
+
+/// What the admin routes show of a session, everything but the token.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    /// The stored id, enough to revoke the session but not to use it.
+    pub id: String,
+    pub user_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub token_expires_at: DateTime<Utc>,
+    pub impersonated: bool,
+}
+
+impl From<Session> for SessionSummary {
+    fn from(session: Session) -> Self {
+        Self {
+            id: session.session_id,
+            user_id: session.user_id,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+            token_expires_at: session.token_expires_at,
+            impersonated: session.impersonated,
+        }
+    }
+}
+
+/// A new session id for the cookie: 32 random bytes as unpadded base64url.
+pub fn generate_session_id() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| Error::RustError(format!("Failed to generate session id: {}", e)))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// What a session id is stored under. The id is random, a plain hash is enough.
+pub fn hash_session_id(session_id: &str) -> String {
+    format!("{:x}", Sha256::digest(session_id.as_bytes()))
+}
+
+impl FromRow for Session {
+    /// Expects [`Session::COLUMNS`] in that order.
+    fn from_row(row: &Row) -> Result<Self> {
+        let user_id: i64 = column(row, 1)?;
+        Ok(Self {
+            session_id: column(row, 0)?,
+            user_id: Snowflake::new(user_id as u64),
+            discord_access_token: column(row, 2)?,
+            token_expires_at: column(row, 3)?,
+            created_at: column(row, 4)?,
+            expires_at: column(row, 5)?,
+            impersonated: column(row, 6)?,
+        })
+    }
+}
+
+impl Table for Session {
+    fn table() -> DynIden {
+        Sessions::Table.into_iden()
+    }
+    fn id_column() -> DynIden {
+        Sessions::SessionId.into_iden()
+    }
+    fn columns() -> Vec<DynIden> {
+        Self::COLUMNS.into_iter().map(IntoIden::into_iden).collect()
+    }
+    fn values(&self) -> Vec<SimpleExpr> {
+        vec![
+            self.session_id.clone().into(),
+            (self.user_id.get() as i64).into(),
+            self.discord_access_token.clone().into(),
+            self.token_expires_at.into(),
+            self.created_at.into(),
+            self.expires_at.into(),
+            self.impersonated.into(),
+        ]
+    }
+}
@@ -0,0 +1,146 @@
+use serde_json::{Map, Value};
+
+use crate::services::validation::ValidationError;
+
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_STRING_LENGTH: usize = 2000;
+const MAX_DEPTH: usize = 8;
+
+/// Validates a settings patch, collecting every problem instead of stopping at the first.
+///
+/// Field paths are dotted, e.g. `welcome.message`.
+pub fn validate_settings_patch(patch: &Value) -> Result<(), ValidationError> {
+    let mut errors = ValidationError::new();
+    match patch {
+        Value::Object(map) => validate_object(map, "", 1, &mut errors),
+        _ => errors.push("", "Settings patch must be a JSON object"),
+    }
+    errors.into_result()
+}
+
+fn validate_object(
+    map: &Map<String, Value>,
+    prefix: &str,
+    depth: usize,
+    errors: &mut ValidationError,
+) {
+    for (key, value) in map {
+        let field = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            errors.push(
+                &field,
+                format!("Keys must be 1 to {} characters long", MAX_KEY_LENGTH),
+            );
+        } else if !key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            errors.push(
+                &field,
+                "Keys may only contain lowercase letters, digits and underscores",
+            );
+        }
+
+        match value {
+            // An empty object has nothing below it, so it's fine at any depth
+            Value::Object(nested) if depth >= MAX_DEPTH && !nested.is_empty() => {
+                errors.push(
+                    &field,
+                    format!("Settings may not nest deeper than {} levels", MAX_DEPTH),
+                );
+            }
+            Value::Object(nested) => validate_object(nested, &field, depth + 1, errors),
+            Value::String(s) if s.chars().count() > MAX_STRING_LENGTH => {
+                errors.push(
+                    &field,
+                    format!("Must be at most {} characters", MAX_STRING_LENGTH),
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Applies a JSON Merge Patch (RFC 7386) to `target` in place.
+///
+/// Object members in `patch` are merged recursively, `null` members delete the key and any
+/// non-object patch replaces the target outright.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn merge_patch_sets_a_field() {
+        let mut settings = json!({ "prefix": "!" });
+        merge_patch(&mut settings, &json!({ "welcome": { "message": "hi" } }));
+        assert_eq!(
+            settings,
+            json!({ "prefix": "!", "welcome": { "message": "hi" } })
+        );
+    }
+
+    #[test]
+    fn merge_patch_deletes_a_field_with_null() {
+        let mut settings = json!({ "prefix": "!", "welcome": { "message": "hi", "channel": "1" } });
+        merge_patch(&mut settings, &json!({ "welcome": { "channel": null } }));
+        assert_eq!(
+            settings,
+            json!({ "prefix": "!", "welcome": { "message": "hi" } })
+        );
+    }
+
+    #[test]
+    fn merge_patch_leaves_other_fields_untouched() {
+        let mut settings = json!({ "prefix": "!", "welcome": { "message": "hi" } });
+        merge_patch(&mut settings, &json!({ "prefix": "?" }));
+        assert_eq!(
+            settings,
+            json!({ "prefix": "?", "welcome": { "message": "hi" } })
+        );
+    }
+
+    #[test]
+    fn validation_rejects_non_objects() {
+        assert!(validate_settings_patch(&json!(["prefix"])).is_err());
+        assert!(validate_settings_patch(&json!({ "prefix": "!" })).is_ok());
+    }
+
+    #[test]
+    fn validation_reports_every_invalid_field() {
+        let patch = json!({
+            "Prefix": "!",
+            "welcome": { "message": "a".repeat(MAX_STRING_LENGTH + 1), "channel": "1" },
+        });
+        let errors = validate_settings_patch(&patch).unwrap_err().errors;
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["Prefix", "welcome.message"]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fmt, str::FromStr};
+
+use axum::{
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A Discord id. Discord sends these as strings, so they are (de)serialized as strings too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Snowflake(u64);
+
+impl Snowflake {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSnowflake;
+
+impl fmt::Display for InvalidSnowflake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid snowflake")
+    }
+}
+
+impl FromStr for Snowflake {
+    type Err = InvalidSnowflake;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `u64::from_str` accepts a leading `+` which Discord never sends
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(InvalidSnowflake);
+        }
+        match s.parse::<u64>() {
+            Ok(0) | Err(_) => Err(InvalidSnowflake),
+            Ok(id) => Ok(Self(id)),
+        }
+    }
+}
+
+impl fmt::Display for Snowflake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Snowflake {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Snowflake {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Extractor for routes shaped like `/guild/{guild_id}/channels/{channel_id}`.
+///
+/// Both ids are validated, the rejection names the one that was malformed.
+#[derive(Debug, Clone, Copy)]
+pub struct GuildChildPath {
+    pub guild: Snowflake,
+    pub child: Snowflake,
+}
+
+impl<S> FromRequestParts<S> for GuildChildPath
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path((guild, child)) = Path::<(String, String)>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+
+        let guild = guild.parse().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid guild id: {}", guild),
+            )
+        })?;
+        let child = child.parse().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid child id: {}", child),
+            )
+        })?;
+
+        Ok(Self { guild, child })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn parses_plain_ids_only() {
+        assert_eq!(
+            "80351110224678912".parse(),
+            Ok(Snowflake::new(80351110224678912))
+        );
+        for bad in ["", "0", "+1", "-1", "12a", "18446744073709551616"] {
+            assert_eq!(bad.parse::<Snowflake>(), Err(InvalidSnowflake), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn serializes_as_a_string() {
+        let id = Snowflake::new(80351110224678912);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, "\"80351110224678912\"");
+        assert_eq!(serde_json::from_str::<Snowflake>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<Snowflake>("80351110224678912").is_err());
+    }
+
+    async fn extract(uri: &str) -> (StatusCode, String) {
+        let router = Router::new().route(
+            "/guild/{guild_id}/channels/{channel_id}",
+            get(|path: GuildChildPath| async move { format!("{} {}", path.guild, path.child) }),
+        );
+        let response = router
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn guild_child_path_names_the_malformed_id() {
+        assert_eq!(
+            extract("/guild/1/channels/2").await,
+            (StatusCode::OK, "1 2".to_string())
+        );
+        assert_eq!(
+            extract("/guild/abc/channels/2").await,
+            (StatusCode::BAD_REQUEST, "Invalid guild id: abc".to_string())
+        );
+        assert_eq!(
+            extract("/guild/1/channels/0").await,
+            (StatusCode::BAD_REQUEST, "Invalid child id: 0".to_string())
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{cell::Cell, future::Future};
+
+use tracing::warn;
+use worker::Date;
+
+pub const DEFAULT_SLOW_THRESHOLD_MS: u64 = 2_000;
+
+thread_local! {
+    static SLOW_THRESHOLD_MS: Cell<u64> = const { Cell::new(DEFAULT_SLOW_THRESHOLD_MS) };
+}
+
+/// Overrides the slow call threshold for this isolate, see `SLOW_UPSTREAM_MS`.
+pub fn set_slow_threshold(ms: u64) {
+    SLOW_THRESHOLD_MS.with(|threshold| threshold.set(ms));
+}
+
+/// Awaits `fut` and logs a warning when it took longer than the configured threshold.
+///
+/// `endpoint` should identify the call without leaking ids or tokens, e.g. `GET /users/@me`.
+pub async fn timed<F: Future>(endpoint: &str, fut: F) -> F::Output {
+    timed_with_threshold(endpoint, slow_threshold(), fut).await
+}
+
+pub async fn timed_with_threshold<F: Future>(
+    endpoint: &str,
+    threshold_ms: u64,
+    fut: F,
+) -> F::Output {
+    let started = Date::now().as_millis();
+    let output = fut.await;
+    if let Some(elapsed) = slow_elapsed(started, Date::now().as_millis(), threshold_ms) {
+        warn!(
+            endpoint,
+            elapsed_ms = elapsed,
+            threshold_ms,
+            "Slow upstream call"
+        );
+    }
+    output
+}
+
+fn slow_threshold() -> u64 {
+    SLOW_THRESHOLD_MS.with(|threshold| threshold.get())
+}
+
+/// The time a call took, if that is over `threshold_ms`.
+fn slow_elapsed(started: u64, finished: u64, threshold_ms: u64) -> Option<u64> {
+    let elapsed = finished.saturating_sub(started);
+    (elapsed > threshold_ms).then_some(elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_calls_over_the_threshold_are_slow() {
+        assert_eq!(slow_elapsed(1_000, 3_000, 2_000), None);
+        assert_eq!(slow_elapsed(1_000, 3_001, 2_000), Some(2_001));
+        // A clock going backwards is never slow
+        assert_eq!(slow_elapsed(3_000, 1_000, 0), None);
+    }
+
+    #[test]
+    fn threshold_can_be_overridden() {
+        assert_eq!(slow_threshold(), DEFAULT_SLOW_THRESHOLD_MS);
+        set_slow_threshold(500);
+        assert_eq!(slow_threshold(), 500);
+    }
+}
@@ -1,7 +1,22 @@
-use std::fmt::Error;
+use std::{future::Future, time::Duration};
 
 use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use sea_query::{Alias, DynIden, IntoIden, SimpleExpr};
 use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+use worker::{Delay, Env};
+
+use crate::{
+    services::{
+        discord_rate_limit::{self, SendError},
+        json::response_json,
+        repo::{column, optional_column, FromRow, Table},
+        snowflake::Snowflake,
+        user_cache::UserLookupCache,
+    },
+    state::user::RequestedUser,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscordUser {
@@ -11,13 +26,106 @@ pub struct DiscordUser {
     pub global_name: Option<String>,
     pub bot: Option<bool>,
     pub avatar: Option<String>,
+    // `verified`, `email`, `flags` and `premium_type` are only sent for the OAuth2 user
+    #[serde(default)]
     pub verified: bool,
     pub email: Option<String>,
+    #[serde(default)]
     pub flags: u64,
     pub banner: Option<String>,
     pub accent_color: Option<u32>,
+    #[serde(default)]
     pub premium_type: u8,
+    #[serde(default)]
     pub public_flags: u64,
+    /// The user's Discord client language, sent with the `identify` scope.
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+/// Reads columns by name so any query over the users table works, whatever subset of
+/// columns it selects. `id` and `username` are required, the rest fall back to what Discord
+/// sends when a field is absent. `accent_color` is stored as `integer`, the flags as `bigint`
+/// and `premium_type` as `smallint`.
+impl FromRow for DiscordUser {
+    fn from_row(row: &Row) -> worker::Result<Self> {
+        let id: i64 = column(row, "id")?;
+        let accent_color: Option<i32> = optional_column(row, "accent_color")?;
+        let flags: Option<i64> = optional_column(row, "flags")?;
+        let public_flags: Option<i64> = optional_column(row, "public_flags")?;
+        let premium_type: Option<i16> = optional_column(row, "premium_type")?;
+        Ok(Self {
+            id: id.to_string(),
+            username: column(row, "username")?,
+            discriminator: optional_column(row, "discriminator")?.unwrap_or_else(|| "0".into()),
+            global_name: optional_column(row, "global_name")?,
+            bot: optional_column(row, "bot")?,
+            avatar: optional_column(row, "avatar")?,
+            verified: optional_column(row, "verified")?.unwrap_or_default(),
+            email: optional_column(row, "email")?,
+            flags: flags.unwrap_or_default() as u64,
+            banner: optional_column(row, "banner")?,
+            accent_color: accent_color.map(|color| color as u32),
+            premium_type: premium_type.unwrap_or_default() as u8,
+            public_flags: public_flags.unwrap_or_default() as u64,
+            locale: optional_column(row, "locale")?,
+        })
+    }
+}
+
+impl DiscordUser {
+    /// Accounts moved to Discord's unique usernames have a discriminator of `"0"`.
+    pub fn is_migrated(&self) -> bool {
+        self.discriminator == "0"
+    }
+
+    /// Name to show in the dashboard, `#discriminator` is only meaningful for legacy accounts.
+    pub fn display_name(&self) -> String {
+        let name = self.global_name.as_deref().unwrap_or(&self.username);
+        if self.is_migrated() || self.global_name.is_some() {
+            name.to_string()
+        } else {
+            format!("{}#{}", name, self.discriminator)
+        }
+    }
+
+    /// Index of the default embed avatar used when the user has none.
+    pub fn default_avatar_index(&self) -> u64 {
+        if self.is_migrated() {
+            self.id.parse::<u64>().map(|id| (id >> 22) % 6).unwrap_or(0)
+        } else {
+            self.discriminator
+                .parse::<u64>()
+                .map(|d| d % 5)
+                .unwrap_or(0)
+        }
+    }
+
+    /// CDN URL of the user's avatar, or of their default avatar when they haven't set one.
+    ///
+    /// `size` is clamped to 16..=4096 and rounded up to a power of two since the CDN rejects
+    /// anything else.
+    pub fn avatar_url(&self, size: u16) -> String {
+        let size = size.clamp(16, 4096).next_power_of_two();
+        match &self.avatar {
+            Some(hash) => {
+                let ext = if hash.starts_with("a_") { "gif" } else { "png" };
+                format!(
+                    "{}/avatars/{}/{}.{}?size={}",
+                    crate::DISCORD_CDN_BASE_URL,
+                    self.id,
+                    hash,
+                    ext,
+                    size
+                )
+            }
+            None => format!(
+                "{}/embed/avatars/{}.png",
+                crate::DISCORD_CDN_BASE_URL,
+                self.default_avatar_index()
+            ),
+        }
+    }
 }
 
 impl IntoResponse for DiscordUser {
@@ -30,42 +138,573 @@ impl IntoResponse for DiscordUser {
     }
 }
 
+/// A member as stored in the users table, refreshed from Discord on every login.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredUser {
+    pub id: Snowflake,
+    pub username: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<String>,
+    pub email: Option<String>,
+    pub last_login_at: DateTime<Utc>,
+}
+
+impl FromRow for StoredUser {
+    /// Expects `id, username, global_name, avatar, email, last_login_at` in that order.
+    fn from_row(row: &Row) -> worker::Result<Self> {
+        let id: i64 = column(row, 0)?;
+        Ok(Self {
+            id: Snowflake::new(id as u64),
+            username: column(row, 1)?,
+            global_name: column(row, 2)?,
+            avatar: column(row, 3)?,
+            email: column(row, 4)?,
+            last_login_at: column(row, 5)?,
+        })
+    }
+}
+
+impl Table for StoredUser {
+    fn table() -> DynIden {
+        Alias::new("users").into_iden()
+    }
+    fn id_column() -> DynIden {
+        Alias::new("id").into_iden()
+    }
+    fn columns() -> Vec<DynIden> {
+        [
+            "id",
+            "username",
+            "global_name",
+            "avatar",
+            "email",
+            "last_login_at",
+        ]
+        .into_iter()
+        .map(|column| Alias::new(column).into_iden())
+        .collect()
+    }
+    fn values(&self) -> Vec<SimpleExpr> {
+        vec![
+            (self.id.get() as i64).into(),
+            self.username.clone().into(),
+            self.global_name.clone().into(),
+            self.avatar.clone().into(),
+            self.email.clone().into(),
+            self.last_login_at.into(),
+        ]
+    }
+}
+
+/// A guild as listed by `GET /users/@me/guilds`, only the fields the dashboard uses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialGuild {
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+    pub owner: bool,
+    pub permissions: String,
+}
+
+/// The user's membership in one guild, from `GET /users/@me/guilds/{guild_id}/member`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuildMember {
+    /// Role ids, the implicit `@everyone` role is never listed.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub nick: Option<String>,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// Paging for [`DiscordUserApi::get_user_guilds`], Discord returns at most 200 guilds per call
+/// sorted by id and pages with `before`/`after` guild ids.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuildsPage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u8>,
+}
+
+impl GuildsPage {
+    pub const MAX_LIMIT: u8 = 200;
+}
+
+/// How often a single page of guilds is retried after a 429.
+const GUILD_PAGE_RETRIES: u32 = 3;
+/// Longer waits are handed back to the caller rather than holding the request open.
+const MAX_GUILD_PAGE_WAIT_SECS: f64 = 5.0;
+
+/// Body Discord sends with a 429.
+#[derive(Debug, Deserialize)]
+struct RateLimitBody {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+#[derive(Debug)]
+pub enum UserApiError {
+    /// The request never got an answer from Discord.
+    Network(String),
+    /// Discord rejected the token, it's expired or revoked.
+    Unauthorized,
+    /// Any other non-success status.
+    Status(reqwest::StatusCode),
+    /// Discord asked us to back off, `retry_after` is in seconds.
+    RateLimited { retry_after: f64, global: bool },
+    /// The body didn't match the expected type.
+    Deserialize(String),
+    /// The user isn't in the guild that was asked about.
+    NotMember,
+}
+
+impl std::fmt::Display for UserApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserApiError::Network(e) => write!(f, "Failed to send request to Discord API: {}", e),
+            UserApiError::Unauthorized => write!(f, "Discord rejected the access token"),
+            UserApiError::Status(status) => write!(f, "Failed to fetch user data: {}", status),
+            UserApiError::RateLimited {
+                retry_after,
+                global,
+            } => write!(
+                f,
+                "Rate limited by Discord for {}s (global: {})",
+                retry_after, global
+            ),
+            UserApiError::Deserialize(e) => write!(f, "Failed to parse user data: {}", e),
+            UserApiError::NotMember => write!(f, "User is not a member of the guild"),
+        }
+    }
+}
+
+impl std::error::Error for UserApiError {}
+
+impl From<SendError> for UserApiError {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::CoolingDown(secs) => UserApiError::RateLimited {
+                retry_after: secs as f64,
+                global: true,
+            },
+            // Try again shortly, same as a short per-route limit
+            SendError::Busy => UserApiError::RateLimited {
+                retry_after: 1.0,
+                global: false,
+            },
+            SendError::Request(e) => UserApiError::Network(e.to_string()),
+        }
+    }
+}
+
 pub struct DiscordUserApi {
     client: reqwest::Client,
+    env: Env,
 }
 
 impl DiscordUserApi {
-    pub fn new(authorization: String) -> Self {
+    pub fn new(env: &Env, authorization: String) -> Self {
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(
             reqwest::header::AUTHORIZATION,
             authorization.parse().unwrap(),
         );
         let client = reqwest::Client::builder()
+            .user_agent(crate::USER_AGENT)
             .default_headers(headers)
             .build()
             .expect("Failed to create HTTP client");
 
-        Self { client }
+        Self {
+            client,
+            env: env.clone(),
+        }
     }
 
-    pub async fn get_user(&self) -> Result<DiscordUser, Error> {
+    /// A client acting as the user behind `access_token`.
+    pub fn from_access_token(env: &Env, access_token: &str) -> Self {
+        Self::new(env, format!("Bearer {}", access_token))
+    }
+
+    /// A client for the requesting user, `None` unless `cookie_check` resolved an access token
+    /// for the request, from the session cookie or an `Authorization` header.
+    pub fn from_requested_user(env: &Env, requested_user: &RequestedUser) -> Option<Self> {
+        match requested_user {
+            RequestedUser::UserWithToken(user) => {
+                Some(Self::from_access_token(env, user.access_token()))
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn get_user(&self) -> Result<DiscordUser, UserApiError> {
         let url = format!("{}/users/@me", crate::DISCORD_API_BASE_URL);
-        let response = self
-            .client
-            .get(&url)
-            .send()
-            .await
-            .map_err(|e| panic!("Failed to send request to Discord API: {}", e))?;
+        let response =
+            discord_rate_limit::send(&self.env, "GET /users/@me", self.client.get(&url)).await?;
 
-        if response.status().is_success() {
-            let user: DiscordUser = response
-                .json()
+        match response.status() {
+            status if status.is_success() => {
+                response_json::<DiscordUser>(response, "GET /users/@me")
+                    .await
+                    .map_err(UserApiError::Deserialize)
+            }
+            reqwest::StatusCode::UNAUTHORIZED => Err(UserApiError::Unauthorized),
+            status => Err(UserApiError::Status(status)),
+        }
+    }
+
+    /// Lists the guilds the user is in, needs the `guilds` scope.
+    pub async fn get_user_guilds(
+        &self,
+        page: &GuildsPage,
+    ) -> Result<Vec<PartialGuild>, UserApiError> {
+        let url = format!("{}/users/@me/guilds", crate::DISCORD_API_BASE_URL);
+        let page = GuildsPage {
+            limit: page.limit.map(|l| l.clamp(1, GuildsPage::MAX_LIMIT)),
+            ..page.clone()
+        };
+        let response = discord_rate_limit::send(
+            &self.env,
+            "GET /users/@me/guilds",
+            self.client.get(&url).query(&page),
+        )
+        .await?;
+
+        match response.status() {
+            status if status.is_success() => {
+                response_json::<Vec<PartialGuild>>(response, "GET /users/@me/guilds")
+                    .await
+                    .map_err(UserApiError::Deserialize)
+            }
+            reqwest::StatusCode::UNAUTHORIZED => Err(UserApiError::Unauthorized),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let body =
+                    response_json::<RateLimitBody>(response, "GET /users/@me/guilds 429").await;
+                Err(match body {
+                    Ok(body) => UserApiError::RateLimited {
+                        retry_after: body.retry_after,
+                        global: body.global,
+                    },
+                    Err(_) => UserApiError::Status(reqwest::StatusCode::TOO_MANY_REQUESTS),
+                })
+            }
+            status => Err(UserApiError::Status(status)),
+        }
+    }
+
+    /// The user's roles, nickname and join date in `guild_id`. Needs the `guilds.members.read`
+    /// scope, see `DISCORD_MEMBER_SCOPE`.
+    pub async fn get_guild_member(&self, guild_id: Snowflake) -> Result<GuildMember, UserApiError> {
+        let url = format!(
+            "{}/users/@me/guilds/{}/member",
+            crate::DISCORD_API_BASE_URL,
+            guild_id
+        );
+        let response = discord_rate_limit::send(
+            &self.env,
+            "GET /users/@me/guilds/{guild_id}/member",
+            self.client.get(&url),
+        )
+        .await?;
+
+        match response.status() {
+            status if status.is_success() => {
+                response_json::<GuildMember>(response, "GET /users/@me/guilds/{guild_id}/member")
+                    .await
+                    .map_err(UserApiError::Deserialize)
+            }
+            reqwest::StatusCode::UNAUTHORIZED => Err(UserApiError::Unauthorized),
+            // Discord answers "Unknown Guild" for guilds the user isn't in
+            reqwest::StatusCode::NOT_FOUND => Err(UserApiError::NotMember),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let (retry_after, global) = discord_rate_limit::retry_hint(response).await;
+                Err(UserApiError::RateLimited {
+                    retry_after,
+                    global,
+                })
+            }
+            status => Err(UserApiError::Status(status)),
+        }
+    }
+
+    /// One full page of guilds after `after`, the first page when `None`.
+    pub async fn get_user_guilds_page(
+        &self,
+        after: Option<Snowflake>,
+    ) -> Result<Vec<PartialGuild>, UserApiError> {
+        self.get_user_guilds(&GuildsPage {
+            after,
+            limit: Some(GuildsPage::MAX_LIMIT),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Every guild the user is in, following `after` until a short page comes back. A 429
+    /// between pages waits out `Retry-After` and retries the same page, a few times at most.
+    pub async fn get_user_guilds_all(&self) -> Result<Vec<PartialGuild>, UserApiError> {
+        collect_guild_pages(|after| self.get_user_guilds_page_with_retry(after)).await
+    }
+
+    async fn get_user_guilds_page_with_retry(
+        &self,
+        after: Option<Snowflake>,
+    ) -> Result<Vec<PartialGuild>, UserApiError> {
+        let mut retries = 0;
+        loop {
+            match self.get_user_guilds_page(after).await {
+                Err(UserApiError::RateLimited { retry_after, .. })
+                    if retries < GUILD_PAGE_RETRIES && retry_after <= MAX_GUILD_PAGE_WAIT_SECS =>
+                {
+                    retries += 1;
+                    Delay::from(Duration::from_secs_f64(retry_after.max(0.0))).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Fetches any user by id, this needs the client to be built with a bot authorization.
+    pub async fn get_user_by_id(&self, id: &str) -> Result<DiscordUser, String> {
+        let url = format!("{}/users/{}", crate::DISCORD_API_BASE_URL, id);
+        let response =
+            discord_rate_limit::send(&self.env, "GET /users/{id}", self.client.get(&url))
                 .await
-                .map_err(|e| panic!("Failed to parse user data: {}", e))?;
-            Ok(user)
+                .map_err(|e| e.to_string())?;
+
+        if response.status().is_success() {
+            response_json::<DiscordUser>(response, "GET /users/{id}").await
         } else {
-            panic!("Failed to fetch user data: {}", response.status())
+            Err(format!(
+                "Failed to fetch user {}: {}",
+                id,
+                response.status()
+            ))
+        }
+    }
+
+    /// Same as [`DiscordUserApi::get_user_by_id`] but goes through the request's cache first.
+    pub async fn get_user_by_id_cached(
+        &self,
+        id: &str,
+        cache: &UserLookupCache,
+    ) -> Result<DiscordUser, String> {
+        if let Some(user) = cache.get(id) {
+            return Ok(user);
+        }
+        let user = self.get_user_by_id(id).await?;
+        cache.insert(user.clone());
+        Ok(user)
+    }
+}
+
+/// Follows `after` through the pages `fetch` returns until a short page comes back.
+pub(crate) async fn collect_guild_pages<F, Fut>(
+    mut fetch: F,
+) -> Result<Vec<PartialGuild>, UserApiError>
+where
+    F: FnMut(Option<Snowflake>) -> Fut,
+    Fut: Future<Output = Result<Vec<PartialGuild>, UserApiError>>,
+{
+    let mut guilds = Vec::new();
+    let mut after = None;
+    loop {
+        let page = fetch(after).await?;
+        let full = page.len() >= GuildsPage::MAX_LIMIT as usize;
+        after = page.last().and_then(|g| g.id.parse::<Snowflake>().ok());
+        guilds.extend(page);
+        if !full || after.is_none() {
+            return Ok(guilds);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::services::database::local_client;
+
+    fn user(discriminator: &str, global_name: Option<&str>, avatar: Option<&str>) -> DiscordUser {
+        serde_json::from_value(json!({
+            "id": "80351110224678912",
+            "username": "nelly",
+            "discriminator": discriminator,
+            "global_name": global_name,
+            "avatar": avatar,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn legacy_account_keeps_its_discriminator() {
+        let legacy = user("1337", None, None);
+        assert!(!legacy.is_migrated());
+        assert_eq!(legacy.display_name(), "nelly#1337");
+        assert_eq!(legacy.default_avatar_index(), 1337 % 5);
+    }
+
+    #[test]
+    fn migrated_account_hides_the_discriminator() {
+        let migrated = user("0", None, None);
+        assert!(migrated.is_migrated());
+        assert_eq!(migrated.display_name(), "nelly");
+        // (80351110224678912 >> 22) % 6
+        assert_eq!(migrated.default_avatar_index(), 5);
+    }
+
+    #[test]
+    fn avatar_url_picks_the_extension_from_the_hash() {
+        assert_eq!(
+            user("0", None, Some("abc123")).avatar_url(128),
+            "https://cdn.discordapp.com/avatars/80351110224678912/abc123.png?size=128"
+        );
+        assert_eq!(
+            user("0", None, Some("a_abc123")).avatar_url(128),
+            "https://cdn.discordapp.com/avatars/80351110224678912/a_abc123.gif?size=128"
+        );
+    }
+
+    #[test]
+    fn avatar_url_falls_back_to_the_default_avatar() {
+        assert_eq!(
+            user("0", None, None).avatar_url(128),
+            "https://cdn.discordapp.com/embed/avatars/5.png"
+        );
+        assert_eq!(
+            user("1337", None, None).avatar_url(128),
+            "https://cdn.discordapp.com/embed/avatars/2.png"
+        );
+    }
+
+    #[test]
+    fn avatar_url_sizes_are_powers_of_two_in_range() {
+        let with_avatar = user("0", None, Some("abc123"));
+        let size = |requested| {
+            let url = with_avatar.avatar_url(requested);
+            url.rsplit_once("size=").unwrap().1.parse::<u16>().unwrap()
+        };
+        assert_eq!(size(0), 16);
+        assert_eq!(size(16), 16);
+        assert_eq!(size(100), 128);
+        assert_eq!(size(4096), 4096);
+        assert_eq!(size(u16::MAX), 4096);
+    }
+
+    #[test]
+    fn global_name_is_shown_without_a_discriminator() {
+        assert_eq!(user("1337", Some("Nelly"), None).display_name(), "Nelly");
+        assert_eq!(user("0", Some("Nelly"), None).display_name(), "Nelly");
+    }
+
+    fn guilds(ids: std::ops::Range<u64>) -> Vec<PartialGuild> {
+        ids.map(|id| PartialGuild {
+            id: id.to_string(),
+            name: format!("Guild {}", id),
+            icon: None,
+            owner: false,
+            permissions: "0".to_string(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn locale_is_returned_with_the_user() {
+        let mut with_locale = serde_json::to_value(user("0", None, None)).unwrap();
+        with_locale["locale"] = json!("pt-BR");
+        let user: DiscordUser = serde_json::from_value(with_locale).unwrap();
+        assert_eq!(user.locale.as_deref(), Some("pt-BR"));
+
+        let body = serde_json::to_value(&user).unwrap();
+        assert_eq!(body["locale"], "pt-BR");
+    }
+
+    #[test]
+    fn locale_is_optional() {
+        // Tokens without the `identify` scope get users without one
+        assert_eq!(user("0", None, None).locale, None);
+    }
+
+    async fn user_row(
+        client: &tokio_postgres::Client,
+        select: &str,
+    ) -> worker::Result<DiscordUser> {
+        let row = client.query_one(select, &[]).await.unwrap();
+        DiscordUser::from_row(&row)
+    }
+
+    #[tokio::test]
+    async fn stored_users_fill_in_what_they_leave_out() {
+        let Some(client) = local_client().await else {
+            return;
+        };
+        let user = user_row(
+            &client,
+            "SELECT 80351110224678912::bigint AS id, 'beabadoobee' AS username, \
+             16711680 AS accent_color, NULL::text AS global_name",
+        )
+        .await
+        .unwrap();
+        assert_eq!(user.id, "80351110224678912");
+        assert_eq!(user.username, "beabadoobee");
+        assert_eq!(user.accent_color, Some(0xff0000));
+        assert_eq!(user.global_name, None);
+        assert!(user.is_migrated());
+        assert!(!user.verified);
+    }
+
+    #[tokio::test]
+    async fn row_errors_name_the_column_and_type() {
+        let Some(client) = local_client().await else {
+            return;
+        };
+        let err = user_row(&client, "SELECT 'x' AS id, 'beabadoobee' AS username")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Can't read column id as i64"), "{}", err);
+
+        let err = user_row(&client, "SELECT 1::bigint AS id")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("column username"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn every_page_is_collected() {
+        let full = u64::from(GuildsPage::MAX_LIMIT);
+        let mut asked = Vec::new();
+        let all = collect_guild_pages(|after| {
+            asked.push(after);
+            let page = match after {
+                None => guilds(1..full + 1),
+                Some(_) => guilds(full + 1..full + 4),
+            };
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(all.len(), GuildsPage::MAX_LIMIT as usize + 3);
+        // The second page starts after the last guild of the first
+        assert_eq!(asked, vec![None, Some(Snowflake::new(full))]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_page_fails_the_listing() {
+        let full = u64::from(GuildsPage::MAX_LIMIT);
+        let result = collect_guild_pages(|after| async move {
+            match after {
+                None => Ok(guilds(1..full + 1)),
+                Some(_) => Err(UserApiError::Unauthorized),
+            }
+        })
+        .await;
+        assert!(matches!(result, Err(UserApiError::Unauthorized)));
+    }
+}
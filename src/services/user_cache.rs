@@ -0,0 +1,89 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::services::user::DiscordUser;
+
+const USER_CACHE_CAPACITY: usize = 64;
+
+/// Small LRU of Discord users that lives for a single request.
+///
+/// It is inserted into the request extensions by [`crate::middleware::user_cache`] so handlers
+/// resolving the same id several times (e.g. a member list) only hit Discord once. Users aren't
+/// shared between requests.
+#[derive(Debug, Clone, Default)]
+pub struct UserLookupCache {
+    entries: Arc<Mutex<VecDeque<DiscordUser>>>,
+}
+
+impl UserLookupCache {
+    pub fn get(&self, id: &str) -> Option<DiscordUser> {
+        let mut entries = self.entries.lock().ok()?;
+        let index = entries.iter().position(|user| user.id == id)?;
+        let user = entries.remove(index)?;
+        entries.push_back(user.clone());
+        Some(user)
+    }
+
+    pub fn insert(&self, user: DiscordUser) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.retain(|cached| cached.id != user.id);
+        if entries.len() >= USER_CACHE_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn user(id: u64, username: &str) -> DiscordUser {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "username": username,
+            "discriminator": "0",
+            "global_name": null,
+            "avatar": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn least_recently_used_user_is_evicted() {
+        let cache = UserLookupCache::default();
+        for id in 0..USER_CACHE_CAPACITY as u64 {
+            cache.insert(user(id, "member"));
+        }
+        // Reading the oldest entry keeps it, the next oldest goes instead
+        assert!(cache.get("0").is_some());
+        cache.insert(user(USER_CACHE_CAPACITY as u64, "newcomer"));
+
+        assert!(cache.get("0").is_some());
+        assert!(cache.get("1").is_none());
+        assert!(cache.get(&USER_CACHE_CAPACITY.to_string()).is_some());
+    }
+
+    #[test]
+    fn reinserting_a_user_replaces_it() {
+        let cache = UserLookupCache::default();
+        cache.insert(user(1, "before"));
+        cache.insert(user(1, "after"));
+        assert_eq!(cache.get("1").unwrap().username, "after");
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn clones_share_the_request_cache() {
+        let cache = UserLookupCache::default();
+        cache.clone().insert(user(1, "shared"));
+        assert!(cache.get("1").is_some());
+        assert!(UserLookupCache::default().get("1").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// A 422 listing every offending field so the dashboard can highlight them inline.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationError {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationError {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Returns `Ok(())` when no errors were collected.
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl IntoResponse for ValidationError {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn collects_nothing_into_ok() {
+        assert!(ValidationError::new().into_result().is_ok());
+    }
+
+    #[tokio::test]
+    async fn responds_422_with_every_field_error() {
+        let mut errors = ValidationError::new();
+        errors.push("prefix", "Must be at most 5 characters");
+        errors.push("welcome.channel", "Must be a channel id");
+
+        let response = errors.into_result().unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "errors": [
+                    { "field": "prefix", "message": "Must be at most 5 characters" },
+                    { "field": "welcome.channel", "message": "Must be a channel id" },
+                ]
+            })
+        );
+    }
+}
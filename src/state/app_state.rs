@@ -1,9 +1,10 @@
 use std::sync::Arc;
 
-use crate::services::database::Database;
+use crate::{services::database::Database, state::server_config::DiscordCredentials};
 
 pub struct AppState {
     pub(crate) database: Database,
+    pub(crate) discord: DiscordCredentials,
 }
 
 pub type AppStateArc = Arc<AppState>;
@@ -1,4 +1,5 @@
 pub mod access_state;
 pub mod app_state;
+pub mod server_config;
 pub mod server_info;
 pub mod user;
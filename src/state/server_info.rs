@@ -1,22 +1,245 @@
 use std::sync::Arc;
 
-use reqwest::StatusCode;
-use tracing::error;
-use worker::{console_error, Env, Result};
+use cookie::SameSite;
+use tracing::{error, warn};
+use worker::{Env, Error, Result, Url};
+
+use crate::{
+    services::{
+        auth::{DiscordCookie, DiscordOAuth2Scope},
+        snowflake::Snowflake,
+    },
+    state::{server_config::ServerConfig, user::AuthMethod},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Development,
+    Staging,
+    Production,
+}
+
+impl Environment {
+    fn from_env(env: &Env) -> Self {
+        match env.var("ENVIRONMENT").map(|s| s.to_string()).as_deref() {
+            Ok("development") => Environment::Development,
+            Ok("staging") => Environment::Staging,
+            // Anything unknown gets the strictest behaviour
+            _ => Environment::Production,
+        }
+    }
+}
+
+/// Name prefix for the auth cookies. Browsers only accept prefixed cookies that carry the
+/// matching attributes, which keeps sibling subdomains from planting their own session cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CookiePrefix {
+    #[default]
+    None,
+    /// Requires `Secure`.
+    Secure,
+    /// Requires `Secure`, `Path=/` and no `Domain`.
+    Host,
+}
+
+impl CookiePrefix {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CookiePrefix::None => "",
+            CookiePrefix::Secure => "__Secure-",
+            CookiePrefix::Host => "__Host-",
+        }
+    }
+}
+
+impl std::str::FromStr for CookiePrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "none" => Ok(CookiePrefix::None),
+            "secure" => Ok(CookiePrefix::Secure),
+            "host" => Ok(CookiePrefix::Host),
+            other => Err(format!("Unknown cookie prefix: {}", other)),
+        }
+    }
+}
+
+/// How the auth cookies are named and scoped.
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    prefix: CookiePrefix,
+    domain: Option<String>,
+    /// `false` only for a dashboard on `http://localhost`, browsers drop `Secure` cookies set
+    /// over plain http.
+    secure: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            prefix: CookiePrefix::None,
+            domain: None,
+            secure: true,
+        }
+    }
+}
+
+impl CookieConfig {
+    /// `__Host-` cookies can't carry a `Domain`, so sharing them across subdomains is rejected.
+    pub fn new(prefix: CookiePrefix, domain: Option<String>) -> Result<Self> {
+        if prefix == CookiePrefix::Host && domain.is_some() {
+            return Err(Error::RustError(
+                "COOKIE_PREFIX=host can't be combined with COOKIE_DOMAIN".into(),
+            ));
+        }
+        Ok(Self {
+            prefix,
+            domain,
+            secure: true,
+        })
+    }
+
+    /// Drops `Secure` for local development. Prefixed cookies need `Secure`, so a prefix is
+    /// rejected then.
+    pub fn insecure(self) -> Result<Self> {
+        if self.prefix != CookiePrefix::None {
+            return Err(Error::RustError(
+                "COOKIE_PREFIX needs an https DASHBOARD_URL".into(),
+            ));
+        }
+        Ok(Self {
+            secure: false,
+            ..self
+        })
+    }
+
+    /// The mode follows the scheme of `DASHBOARD_URL`, which is only allowed to be http on
+    /// localhost.
+    fn from_env(env: &Env, dashboard_url: &str) -> Result<Self> {
+        let prefix = match env.var("COOKIE_PREFIX") {
+            Ok(prefix) => prefix.to_string().parse::<CookiePrefix>().map_err(|e| {
+                error!("Invalid COOKIE_PREFIX: {}", e);
+                Error::RustError(format!("Invalid COOKIE_PREFIX: {}", e))
+            })?,
+            Err(_) => CookiePrefix::None,
+        };
+        let domain = env
+            .var("COOKIE_DOMAIN")
+            .map(|s| s.to_string())
+            .ok()
+            .filter(|s| !s.is_empty());
+        let config = Self::new(prefix, domain).inspect_err(|e| error!("{}", e))?;
+        if Url::parse(dashboard_url).is_ok_and(|url| url.scheme() == "http") {
+            config.insecure().inspect_err(|e| error!("{}", e))
+        } else {
+            Ok(config)
+        }
+    }
+
+    /// The name the cookie is actually stored under.
+    pub fn name(&self, cookie: DiscordCookie) -> String {
+        format!("{}{}", self.prefix.as_str(), cookie)
+    }
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+    /// `None` lets the dashboard's cross site requests carry the cookies, which browsers only
+    /// allow on `Secure` cookies. Locally dashboard and API share `localhost`, `Lax` is enough.
+    pub fn same_site(&self) -> SameSite {
+        if self.secure {
+            SameSite::None
+        } else {
+            SameSite::Lax
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ServerInfo {
     api_host: String,
     webpage: String,
+    environment: Environment,
+    fanclub_guild_id: Option<Snowflake>,
+    auth_methods: Vec<AuthMethod>,
+    cookie_config: CookieConfig,
+    allowed_origins: Vec<String>,
+    login_scopes: Vec<DiscordOAuth2Scope>,
+    cdn_max_age: u32,
 }
 
+/// One day, avatars are content addressed so they can be kept for a while.
+const DEFAULT_CDN_MAX_AGE: u32 = 86_400;
+
 pub type ServerInfoArc = Arc<ServerInfo>;
 
 impl ServerInfo {
-    pub fn new(env: &Env) -> Result<Arc<Self>> {
-        let api_host = env.var("API_HOST").map(|s| s.to_string())?;
-        let webpage = env.var("DASHBOARD_URL").map(|s| s.to_string())?;
-        Ok(Arc::new(Self { api_host, webpage }))
+    pub fn new(env: &Env, config: &ServerConfig) -> Result<Arc<Self>> {
+        let api_host = config.api_host.clone();
+        let webpage = config.dashboard_url.clone();
+        let environment = Environment::from_env(env);
+
+        let fanclub_guild_id =
+            parse_fanclub_guild_id(env.var("FANCLUB_GUILD_ID").ok().map(|id| id.to_string()))?;
+
+        let auth_methods = parse_auth_methods(env.var("AUTH_METHODS").ok().map(|m| m.to_string()))?;
+
+        let cookie_config = CookieConfig::from_env(env, &webpage)?;
+
+        // Comma separated Discord scope names, e.g. `identify,guilds,email`
+        let mut login_scopes = match env.var("DISCORD_SCOPES") {
+            Ok(scopes) => scopes
+                .to_string()
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.parse::<DiscordOAuth2Scope>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| {
+                    error!("Invalid DISCORD_SCOPES: {}", e);
+                    Error::RustError(format!("Invalid DISCORD_SCOPES: {}", e))
+                })?,
+            Err(_) => vec![
+                DiscordOAuth2Scope::Identify,
+                DiscordOAuth2Scope::Guilds,
+                DiscordOAuth2Scope::Email,
+            ],
+        };
+        // Role gated features read the user's member object, which needs its own scope
+        let member_scope = env
+            .var("DISCORD_MEMBER_SCOPE")
+            .is_ok_and(|v| matches!(v.to_string().trim(), "true" | "1"));
+        if member_scope && !login_scopes.contains(&DiscordOAuth2Scope::GuildsMembersRead) {
+            login_scopes.push(DiscordOAuth2Scope::GuildsMembersRead);
+        }
+
+        // Seconds browsers may keep a proxied CDN image
+        let cdn_max_age = match env.var("CDN_MAX_AGE") {
+            Ok(max_age) => max_age.to_string().trim().parse::<u32>().map_err(|e| {
+                error!("Invalid CDN_MAX_AGE: {}", e);
+                Error::RustError(format!("Invalid CDN_MAX_AGE: {}", e))
+            })?,
+            Err(_) => DEFAULT_CDN_MAX_AGE,
+        };
+
+        let allowed_origins = parse_allowed_origins(
+            &webpage,
+            env.var("ALLOWED_ORIGINS").ok().map(|o| o.to_string()),
+        )?;
+
+        Ok(Arc::new(Self {
+            api_host,
+            webpage,
+            environment,
+            fanclub_guild_id,
+            auth_methods,
+            cookie_config,
+            allowed_origins,
+            login_scopes,
+            cdn_max_age,
+        }))
     }
 
     pub fn api_host(&self) -> &str {
@@ -25,4 +248,195 @@ impl ServerInfo {
     pub fn webpage(&self) -> &str {
         &self.webpage
     }
+    /// Whether a browser `Origin` may talk to the API, this is the same list the CORS layer uses.
+    pub fn is_allowed_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.contains(&normalize_origin(origin))
+    }
+    /// Whether `location` points at the dashboard's origin, the only place auth redirects go.
+    pub fn is_dashboard_location(&self, location: &str) -> bool {
+        match (Url::parse(location), Url::parse(&self.webpage)) {
+            (Ok(location), Ok(dashboard)) => location.origin() == dashboard.origin(),
+            _ => false,
+        }
+    }
+    /// The dashboard origin followed by the extra `ALLOWED_ORIGINS`.
+    pub fn allowed_origins(&self) -> &[String] {
+        &self.allowed_origins
+    }
+    pub fn environment(&self) -> Environment {
+        self.environment
+    }
+    pub fn is_development(&self) -> bool {
+        self.environment == Environment::Development
+    }
+    pub fn allows_auth_method(&self, method: AuthMethod) -> bool {
+        self.auth_methods.contains(&method)
+    }
+    /// The scopes users are asked for on login, see `DISCORD_SCOPES`.
+    pub fn login_scopes(&self) -> &[DiscordOAuth2Scope] {
+        &self.login_scopes
+    }
+    /// `max-age` for images served under `/cdn`, see `CDN_MAX_AGE`.
+    pub fn cdn_max_age(&self) -> u32 {
+        self.cdn_max_age
+    }
+    pub fn cookie_config(&self) -> &CookieConfig {
+        &self.cookie_config
+    }
+    /// The fanclub guild id, for features that can't work without it.
+    pub fn fanclub_guild_id(&self) -> Result<Snowflake> {
+        self.fanclub_guild_id
+            .ok_or_else(|| Error::RustError("FANCLUB_GUILD_ID is not set".into()))
+    }
+}
+
+#[cfg(test)]
+impl ServerInfo {
+    /// A production configuration for the dashboard at `webpage`, built without an `Env`.
+    pub(crate) fn for_tests(webpage: &str, extra_origins: &[&str]) -> Self {
+        let mut allowed_origins = vec![normalize_origin(webpage)];
+        allowed_origins.extend(extra_origins.iter().map(|origin| normalize_origin(origin)));
+        Self {
+            api_host: "https://api.example.com".to_string(),
+            webpage: webpage.to_string(),
+            environment: Environment::Production,
+            fanclub_guild_id: None,
+            auth_methods: vec![AuthMethod::Cookie],
+            cookie_config: CookieConfig::new(CookiePrefix::None, None)
+                .expect("an unprefixed config is always valid"),
+            allowed_origins,
+            login_scopes: vec![DiscordOAuth2Scope::Identify],
+            cdn_max_age: DEFAULT_CDN_MAX_AGE,
+        }
+    }
+
+    /// The same configuration deployed to `environment` instead.
+    pub(crate) fn with_environment(self, environment: Environment) -> Self {
+        Self {
+            environment,
+            ..self
+        }
+    }
+}
+
+/// Optional, but a malformed value is a misconfiguration we want to hear about right away.
+fn parse_fanclub_guild_id(value: Option<String>) -> Result<Option<Snowflake>> {
+    let Some(id) = value else {
+        return Ok(None);
+    };
+    id.parse::<Snowflake>().map(Some).map_err(|_| {
+        error!("FANCLUB_GUILD_ID is not a valid Discord id");
+        Error::RustError("FANCLUB_GUILD_ID is not a valid Discord id".into())
+    })
+}
+
+/// Comma separated, e.g. `cookie,bearer`. Browsers only ever need cookies.
+fn parse_auth_methods(value: Option<String>) -> Result<Vec<AuthMethod>> {
+    let Some(methods) = value else {
+        return Ok(vec![AuthMethod::Cookie]);
+    };
+    methods
+        .split(',')
+        .map(|m| m.parse::<AuthMethod>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| {
+            error!("Invalid AUTH_METHODS: {}", e);
+            Error::RustError(format!("Invalid AUTH_METHODS: {}", e))
+        })
+}
+
+/// The dashboard plus any extra origins (e.g. preview deployments), comma separated.
+fn parse_allowed_origins(webpage: &str, extra: Option<String>) -> Result<Vec<String>> {
+    let mut allowed_origins = vec![normalize_origin(webpage)];
+    let Some(extra) = extra else {
+        return Ok(allowed_origins);
+    };
+    for origin in extra.split(',').map(str::trim) {
+        if origin.is_empty() {
+            continue;
+        }
+        // Credentialed CORS can't use a wildcard, see `cors_layer`
+        if origin == "*" {
+            error!("ALLOWED_ORIGINS can't contain *");
+            return Err(Error::RustError("ALLOWED_ORIGINS can't contain *".into()));
+        }
+        if !(origin.starts_with("https://") || origin.starts_with("http://")) {
+            warn!("Skipping invalid ALLOWED_ORIGINS entry {:?}", origin);
+            continue;
+        }
+        let origin = normalize_origin(origin);
+        if !allowed_origins.contains(&origin) {
+            allowed_origins.push(origin);
+        }
+    }
+    Ok(allowed_origins)
+}
+
+/// Browsers send `Origin` without a trailing slash, configured URLs often have one.
+fn normalize_origin(origin: &str) -> String {
+    origin.trim_end_matches('/').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fanclub_guild_id_is_optional_but_validated() {
+        assert_eq!(parse_fanclub_guild_id(None).unwrap(), None);
+        assert_eq!(
+            parse_fanclub_guild_id(Some("1234".to_string())).unwrap(),
+            Some(Snowflake::new(1234))
+        );
+        assert!(parse_fanclub_guild_id(Some("fanclub".to_string())).is_err());
+    }
+
+    #[test]
+    fn features_needing_the_fanclub_guild_fail_without_it() {
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[]);
+        assert!(server_info.fanclub_guild_id().is_err());
+    }
+
+    #[test]
+    fn auth_methods_default_to_cookies() {
+        assert_eq!(parse_auth_methods(None).unwrap(), vec![AuthMethod::Cookie]);
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[]);
+        assert!(server_info.allows_auth_method(AuthMethod::Cookie));
+        assert!(!server_info.allows_auth_method(AuthMethod::Bearer));
+    }
+
+    #[test]
+    fn auth_methods_are_parsed_strictly() {
+        assert_eq!(
+            parse_auth_methods(Some("cookie, bearer".to_string())).unwrap(),
+            vec![AuthMethod::Cookie, AuthMethod::Bearer]
+        );
+        assert_eq!(
+            parse_auth_methods(Some("bearer".to_string())).unwrap(),
+            vec![AuthMethod::Bearer]
+        );
+        assert!(parse_auth_methods(Some("cookie,basic".to_string())).is_err());
+    }
+
+    #[test]
+    fn allowed_origins_refuse_a_wildcard() {
+        let dashboard = "https://fanclub.example.com/";
+        assert!(
+            parse_allowed_origins(dashboard, Some("https://preview.example.com, *".into()))
+                .is_err()
+        );
+
+        let origins = parse_allowed_origins(
+            dashboard,
+            Some(
+                "https://preview.example.com/, fanclub.example.org,,https://fanclub.example.com"
+                    .into(),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            origins,
+            ["https://fanclub.example.com", "https://preview.example.com"]
+        );
+    }
 }
@@ -1,3 +1,18 @@
+use axum::{extract::FromRequestParts, http::request::Parts};
+use tracing::{error, warn};
+use worker::{send::SendFuture, Env};
+
+use crate::{
+    api::error::ApiError,
+    services::{
+        auth::{remove_error_cookies, DiscordCookie},
+        cookie::CookieJar,
+        snowflake::Snowflake,
+        user::{DiscordUser, DiscordUserApi, UserApiError},
+    },
+    state::{app_state::AppStateArc, server_info::ServerInfoArc},
+};
+
 #[derive(Debug, Clone)]
 pub enum RequestedUser {
     User,
@@ -5,9 +20,46 @@ pub enum RequestedUser {
     UserWithToken(User),
 }
 
+/// A caller of the protected routes whose identity has been verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    /// The fanclub bot, authenticated with `BOT_API_TOKEN`.
+    Bot,
+    /// An operator, authenticated with `ADMIN_API_TOKEN` through the same header as the bot.
+    /// Only the admin routes accept it, it isn't a superset of [`Caller::Bot`].
+    Admin,
+    /// A request scoped to a guild that exists in the database, signed for with
+    /// `GUILD_API_SECRET`.
+    Guild(Snowflake),
+    /// A dashboard user, identified by their login session.
+    User(Snowflake),
+}
+
+/// Where a user's access token came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Cookie,
+    Bearer,
+}
+
+impl std::str::FromStr for AuthMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "cookie" => Ok(AuthMethod::Cookie),
+            "bearer" => Ok(AuthMethod::Bearer),
+            other => Err(format!("Unknown auth method: {}", other)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
     access_token: String,
+    auth_method: AuthMethod,
+    /// Known without asking Discord for session logins, bearer tokens aren't resolved.
+    user_id: Option<Snowflake>,
 }
 
 #[derive(Debug, Clone)]
@@ -17,15 +69,190 @@ pub struct Bot {
 
 impl User {
     pub fn new(access_token: String) -> Self {
-        Self { access_token }
+        Self::with_method(access_token, AuthMethod::Cookie)
+    }
+    pub fn with_method(access_token: String, auth_method: AuthMethod) -> Self {
+        Self {
+            access_token,
+            auth_method,
+            user_id: None,
+        }
+    }
+    pub fn with_user_id(mut self, user_id: Snowflake) -> Self {
+        self.user_id = Some(user_id);
+        self
     }
     pub fn access_token(&self) -> &str {
         &self.access_token
     }
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method
+    }
+    pub fn user_id(&self) -> Option<Snowflake> {
+        self.user_id
+    }
 }
 
 impl Bot {
     pub fn new(token: String) -> Self {
         Self { token }
     }
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+/// The Discord user behind the request's access token.
+///
+/// Resolved with `/users/@me` the first time it's extracted and kept in the request extensions,
+/// so several extractors or handlers in one request share a single Discord call. A missing or
+/// rejected token is a 401 that also clears the session cookies.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub DiscordUser);
+
+impl<S> FromRequestParts<S> for AuthenticatedUser
+where
+    S: Send + Sync,
+{
+    type Rejection = (Option<(CookieJar, CookieJar)>, ApiError);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<AuthenticatedUser>() {
+            return Ok(user.clone());
+        }
+
+        let clear_cookies = |parts: &Parts| {
+            let server_info = parts.extensions.get::<ServerInfoArc>()?;
+            let jar = CookieJar::from_headers(&parts.headers);
+            Some(remove_error_cookies(&jar, server_info.cookie_config()))
+        };
+
+        let api = match (
+            parts.extensions.get::<Env>(),
+            parts.extensions.get::<RequestedUser>(),
+        ) {
+            (Some(env), Some(requested_user)) => {
+                DiscordUserApi::from_requested_user(env, requested_user)
+            }
+            _ => None,
+        };
+        let Some(api) = api else {
+            return Err((
+                clear_cookies(parts),
+                ApiError::unauthorized("Not logged in"),
+            ));
+        };
+        let session_id = session_cookie(parts);
+        let app_state = parts.extensions.get::<AppStateArc>().cloned();
+        // reqwest futures aren't Send on wasm, the isolate is single threaded anyway
+        let user = match SendFuture::new(async move { api.get_user().await }).await {
+            Ok(user) => Self(user),
+            Err(UserApiError::Unauthorized) => {
+                // The access token is dead, drop it so the browser stops presenting it and the
+                // session row so nothing else does
+                warn!("Access token rejected by Discord");
+                if let (Some(session_id), Some(app_state)) = (session_id, app_state) {
+                    let deleted = SendFuture::new(async move {
+                        app_state.database.delete_session(&session_id).await
+                    })
+                    .await;
+                    if let Err(e) = deleted {
+                        error!("Failed to delete rejected session: {}", e);
+                    }
+                }
+                return Err((
+                    clear_cookies(parts),
+                    ApiError::unauthorized("Session expired"),
+                ));
+            }
+            Err(e) => {
+                error!("Failed to fetch user data: {}", e);
+                return Err((None, ApiError::internal("Failed to fetch user data")));
+            }
+        };
+
+        parts.extensions.insert(user.clone());
+        Ok(user)
+    }
+}
+
+/// The session cookie of a request that was authenticated by it, bearer requests never end a
+/// session they didn't use.
+fn session_cookie(parts: &Parts) -> Option<String> {
+    let Some(RequestedUser::UserWithToken(user)) = parts.extensions.get::<RequestedUser>() else {
+        return None;
+    };
+    if user.auth_method() != AuthMethod::Cookie {
+        return None;
+    }
+    let server_info = parts.extensions.get::<ServerInfoArc>()?;
+    CookieJar::from_headers(&parts.headers)
+        .get(&server_info.cookie_config().name(DiscordCookie::Session))
+        .map(|cookie| cookie.value().to_string())
+        .filter(|id| !id.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        http::{header::COOKIE, Request, StatusCode},
+        response::IntoResponse,
+    };
+
+    use super::*;
+    use crate::state::server_info::ServerInfo;
+
+    fn parts(requested_user: Option<RequestedUser>) -> Parts {
+        let (mut parts, _) = Request::builder()
+            .header(COOKIE, "discord_session=session-id")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let server_info = ServerInfo::for_tests("https://fanclub.example.com", &[]);
+        parts.extensions.insert(Arc::new(server_info));
+        if let Some(requested_user) = requested_user {
+            parts.extensions.insert(requested_user);
+        }
+        parts
+    }
+
+    #[tokio::test]
+    async fn a_session_left_without_a_token_is_refused_and_cleared() {
+        // What `cookie_check` hands on when there was no refresh token to renew the session with
+        let mut parts = parts(None);
+        let (cleared, error) = AuthenticatedUser::from_request_parts(&mut parts, &())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        let (tokens, session) = cleared.expect("the stale cookies are removed");
+        let removed: Vec<_> = [tokens.into_response(), session.into_response()]
+            .iter()
+            .flat_map(|response| response.headers().get_all("set-cookie").iter())
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect();
+        assert!(
+            removed.iter().any(|c| c.starts_with("discord_session=")),
+            "{:?}",
+            removed
+        );
+    }
+
+    #[test]
+    fn only_cookie_logins_name_a_session_to_end() {
+        let cookie = RequestedUser::UserWithToken(User::new("token".to_string()));
+        assert_eq!(
+            session_cookie(&parts(Some(cookie))).as_deref(),
+            Some("session-id")
+        );
+
+        let bearer = RequestedUser::UserWithToken(User::with_method(
+            "token".to_string(),
+            AuthMethod::Bearer,
+        ));
+        assert_eq!(session_cookie(&parts(Some(bearer))), None);
+        assert_eq!(session_cookie(&parts(None)), None);
+    }
 }